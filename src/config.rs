@@ -30,6 +30,20 @@ pub struct Config {
     pub signup_enabled: bool,
     #[envconfig(from = "JOTSY_DEPLOY_PROD", default = "true")]
     pub is_prod: bool,
+    #[envconfig(from = "JOTSY_TRUST_PROXY", default = "false")]
+    pub trust_proxy: bool,
+    #[envconfig(from = "JOTSY_SESSION_TTL_DAYS", default = "15")]
+    pub session_ttl_days: u32,
+    #[envconfig(from = "JOTSY_SESSION_SLIDING", default = "false")]
+    pub session_sliding: bool,
+    #[envconfig(from = "JOTSY_TRASH_RETENTION_DAYS", default = "30")]
+    pub trash_retention_days: u32,
+    #[envconfig(from = "JOTSY_ADMIN_USER")]
+    pub admin_user: Option<String>,
+    #[envconfig(from = "JOTSY_PUBLIC_URL", default = "http://localhost:2022")]
+    pub public_url: String,
+    #[envconfig(from = "JOTSY_HIDE_TAKEN_USERNAMES", default = "false")]
+    pub hide_taken_usernames: bool,
 }
 
 impl Config {
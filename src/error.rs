@@ -19,13 +19,18 @@ use axum::{
     body,
     http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
 pub use skytable::{error::Error as SkytableError, pool::bb8Error};
+use std::fmt;
 
 #[derive(Debug)]
 pub enum ResponseError {
     DatabaseError(SkytableError),
     PoolError(bb8Error<SkytableError>),
+    /// A record in the database couldn't be read (what it was and why are in the message)
+    CorruptRecord(String),
     /// This is a redirect, not an error. Just a hack to simplify things
     Redirect(String),
 }
@@ -41,6 +46,10 @@ impl IntoResponse for ResponseError {
                 log::error!("Failed to get connection from pool: {epool}");
                 NoticePage::e500_resp()
             }
+            Self::CorruptRecord(what) => {
+                log::error!("Couldn't read {what}");
+                NoticePage::e500_resp()
+            }
             Self::Redirect(red) => Response::builder()
                 .status(StatusCode::OK)
                 .body(body::boxed(body::Full::from(red)))
@@ -54,6 +63,19 @@ impl IntoResponse for ResponseError {
     }
 }
 
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DatabaseError(dbe) => write!(f, "database error: {dbe}"),
+            Self::PoolError(epool) => write!(f, "pool error: {epool}"),
+            Self::CorruptRecord(what) => write!(f, "couldn't read {what}"),
+            Self::Redirect(_) => write!(f, "redirect"),
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
 impl From<SkytableError> for ResponseError {
     fn from(e: SkytableError) -> Self {
         Self::DatabaseError(e)
@@ -65,3 +87,61 @@ impl From<bb8Error<SkytableError>> for ResponseError {
         Self::PoolError(e)
     }
 }
+
+#[derive(Debug)]
+/// An error returned by the JSON API. Unlike [`ResponseError`], this is always rendered
+/// as a JSON object and never as a page
+pub enum ApiError {
+    /// The request wasn't authenticated
+    Unauthorized,
+    /// The requested resource doesn't exist
+    NotFound,
+    /// The request was malformed
+    BadRequest(String),
+    /// Something went wrong on our end; the cause is logged and not shown to the client
+    Internal,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Self::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "authentication required".to_owned(),
+            ),
+            Self::NotFound => (StatusCode::NOT_FOUND, "not found".to_owned()),
+            Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            Self::Internal => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "an internal server error occurred".to_owned(),
+            ),
+        };
+        let body = json!({ "error": { "code": status.as_u16(), "message": message } });
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<ResponseError> for ApiError {
+    fn from(e: ResponseError) -> Self {
+        match e {
+            // the HTML handlers redirect to the login page when a session can't be verified
+            ResponseError::Redirect(_) => Self::Unauthorized,
+            e => {
+                log::error!("API error: {e}");
+                Self::Internal
+            }
+        }
+    }
+}
+
+impl From<SkytableError> for ApiError {
+    fn from(e: SkytableError) -> Self {
+        ResponseError::from(e).into()
+    }
+}
+
+impl From<bb8Error<SkytableError>> for ApiError {
+    fn from(e: bb8Error<SkytableError>) -> Self {
+        ResponseError::from(e).into()
+    }
+}
@@ -15,12 +15,29 @@
 */
 
 pub mod account;
+pub mod admin;
+pub mod api;
 pub mod app;
 pub mod assets;
+pub mod csrf;
+pub mod history;
+pub mod import;
+pub mod index;
+pub mod lockout;
 mod login;
 mod logout;
+pub mod notebooks;
+pub mod passkeys;
 mod root;
+pub mod search;
+pub mod sessions;
+pub mod settings;
+pub mod shares;
 pub mod signup;
+pub mod tags;
+pub mod tokens;
+pub mod totp;
+pub mod trash;
 
 pub use self::{
     login::{login, login_get},
@@ -29,12 +46,96 @@ pub use self::{
     signup::{signup, signup_get},
 };
 
-use crate::templates::NoticePage;
-use axum::response::Html;
+use crate::{templates::NoticePage, util};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequest, RequestParts, TypedHeader},
+    headers::{authorization::Bearer, Authorization},
+    http::{header, StatusCode},
+    response::Html,
+};
+use std::net::{IpAddr, SocketAddr};
 use tower_cookies::Cookies;
 const COOKIE_USERNAME: &str = "jotsy_user";
 const COOKIE_TOKEN: &str = "jotsy_token";
 
+/// The credentials sent with a request. Browsers send the session cookies while other
+/// clients can send an API token with an `Authorization: Bearer` header. We also keep some
+/// details about the client to record against new sessions
+pub struct Credentials {
+    pub cookies: Cookies,
+    bearer: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+impl Credentials {
+    /// Returns the API token, if one was sent
+    pub fn bearer(&self) -> Option<&str> {
+        self.bearer.as_deref()
+    }
+    /// Returns the hash of the session token cookie, if one was sent
+    pub fn session_hash(&self) -> Option<String> {
+        self.cookies
+            .get(COOKIE_TOKEN)
+            .map(|token| util::sha2(token.value()))
+    }
+    /// Returns the CSRF token for this session, to embed in pages with forms. This is empty
+    /// without a session cookie (like for API clients), since only sessions need one
+    pub fn csrf_token(&self) -> String {
+        self.cookies
+            .get(COOKIE_TOKEN)
+            .map(|token| csrf::token_for(token.value()))
+            .unwrap_or_default()
+    }
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Credentials {
+    type Rejection = (StatusCode, &'static str);
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let cookies = Cookies::from_request(req).await?;
+        let bearer = TypedHeader::<Authorization<Bearer>>::from_request(req)
+            .await
+            .ok()
+            .map(|TypedHeader(Authorization(bearer))| bearer.token().to_owned());
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .map(str::to_owned);
+        // only trust a forwarded address if we've been told that we're behind a proxy. Clients
+        // can send any `X-Forwarded-For` they like and proxies append to it, so the only entry
+        // we can trust is the last one, which was added by our proxy
+        let forwarded = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|xff| xff.to_str().ok())
+            .and_then(|xff| xff.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .filter(|_| util::trust_proxy());
+        let ip = forwarded.or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        });
+        Ok(Self {
+            cookies,
+            bearer,
+            user_agent,
+            ip,
+        })
+    }
+}
+
 /// This will redirect to `/` if no cookies are set, else it will return the provided page
 async fn redirect_home_if_cookie_set(cookies: Cookies, page: String) -> Html<String> {
     if cookies.get(COOKIE_TOKEN).is_some() || cookies.get(COOKIE_USERNAME).is_some() {
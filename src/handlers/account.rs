@@ -14,15 +14,25 @@
  * limitations under the License.
 */
 
+use super::{app::Note, Credentials};
 use crate::{
     error::ResponseError,
-    templates::{Account, DeleteUI, NoticePage},
+    templates::{
+        Account, ChangePassword, DeleteUI, ExportNote, ExportPage, NoticePage, EXPORT_END,
+    },
     util::{self, resp},
 };
 use axum::{
-    extract::{Extension, Form},
-    http::StatusCode,
+    body::{boxed, Body},
+    extract::{Extension, Form, Query},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
 };
+use chrono::{prelude::Local, SecondsFormat};
+use chrono_tz::Tz;
 use serde::Deserialize;
 use skytable::{
     actions::AsyncActions,
@@ -30,46 +40,92 @@ use skytable::{
     ddl::AsyncDdl,
     error::{Error, SkyhashError},
     pool::AsyncPool,
-    query, Element, RespCode,
+    query, RespCode,
 };
-use tower_cookies::Cookies;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Write},
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// The number of notes read from the database at a time while streaming an export
+const EXPORT_BATCH: usize = 100;
 
 /// `GET` for the `/account` route
 pub async fn account(
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
 ) -> crate::JotsyResponse {
     let mut con = db.get().await?;
-    let username = super::root::verify_user_or_error(&mut con, &mut cookies).await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    self::account_page(&mut con, &creds, username, None).await
+}
+
+/// Returns the account page for a verified user. If `new_token` is set, it is shown to the
+/// user since they won't be able to see it again
+pub(super) async fn account_page(
+    con: &mut Connection,
+    creds: &Credentials,
+    username: String,
+    new_token: Option<String>,
+) -> crate::JotsyResponse {
     con.switch(crate::TABLE_NOTES).await?;
     let q = query!("LGET", &username, "len");
     let count: u64 = con.run_query(&q).await?;
-    resp(StatusCode::OK, Account::render_new(count, username))
+    let tokens = super::tokens::get_tokens(con, &username).await?;
+    let current = creds.session_hash();
+    let sessions = super::sessions::list(con, &username, current.as_deref()).await?;
+    let notebooks = super::notebooks::get_counts(con, &username).await?;
+    let timezone = super::settings::get_timezone(con, &username).await?;
+    let shares = super::shares::list(con, &username).await?;
+    let admin = super::admin::is_admin(con, &username).await?;
+    let totp = super::totp::status(con, &username).await?;
+    let passkeys = super::passkeys::list(con, &username).await?;
+    resp(
+        StatusCode::OK,
+        Account::render_new(
+            count,
+            username,
+            tokens,
+            new_token,
+            sessions,
+            notebooks,
+            timezone,
+            shares,
+            admin,
+            totp,
+            passkeys,
+            creds.csrf_token(),
+        ),
+    )
 }
 
 /// Response for a delete request. Returns a [`DeleteUI`]
 async fn delete(
     what: &'static str,
     path: &'static str,
-    lose: &'static str,
-    mut cookies: Cookies,
+    warning: String,
+    mut creds: Credentials,
     db: AsyncPool,
 ) -> crate::JotsyResponse {
     let mut con = db.get().await?;
-    let un = super::root::verify_user_or_error(&mut con, &mut cookies).await?;
-    resp(StatusCode::OK, DeleteUI::render_new(what, path, un, lose))
+    let un = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    resp(
+        StatusCode::OK,
+        DeleteUI::render_new(what, path, un, warning, creds.csrf_token()),
+    )
 }
 
 /// `GET` for `/delete/account`
 pub async fn del_account_get(
-    cookies: Cookies,
+    creds: Credentials,
     Extension(db): Extension<AsyncPool>,
 ) -> crate::JotsyResponse {
     self::delete(
         "your account",
         "account",
-        "your account and all your notes",
-        cookies,
+        "You will permanently lose access to your account and all your notes.".to_owned(),
+        creds,
         db,
     )
     .await
@@ -77,33 +133,37 @@ pub async fn del_account_get(
 
 /// `GET` for `/delete/notes`
 pub async fn del_notes_get(
-    cookies: Cookies,
+    creds: Credentials,
     Extension(db): Extension<AsyncPool>,
 ) -> crate::JotsyResponse {
     self::delete(
         "all your notes",
         "notes",
-        "all your existing notes",
-        cookies,
+        format!(
+            "All your existing notes will be moved to the trash, where they will be deleted \
+            forever after {} day(s).",
+            util::trash_retention().num_days()
+        ),
+        creds,
         db,
     )
     .await
 }
 
-/// Verify a delete action. This will validate details from cookies and the form
-/// to perform a "privileged" action:
+/// Verify a privileged action (like deleting notes or changing the password). This will
+/// validate details from cookies and the form to perform a "privileged" action:
 /// - Check if auth token is good
 /// - Check if entered password is good
-async fn delete_verify(
-    cookies: &mut Cookies,
+pub(super) async fn privileged_verify(
+    creds: &mut Credentials,
     con: &mut Connection,
-    form: DeleteForm,
+    password: &str,
 ) -> crate::JotsyResponseResult<String> {
-    let username = super::root::verify_user_or_error(con, cookies).await?;
+    let username = super::root::verify_user_or_error(con, creds).await?;
     con.switch(crate::TABLE_AUTH).await?;
     let hash_from_db: Result<String, Error> = con.get(&username).await;
     match hash_from_db {
-        Ok(v) if util::bcrypt_verify(&form.password, &v) => Ok(username),
+        Ok(v) if util::bcrypt_verify(password, &v) => Ok(username),
         Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) | Ok(_) => {
             Err(ResponseError::Redirect(NoticePage::render_new(
                 "Failed to verify details for privileged action",
@@ -123,50 +183,391 @@ pub struct DeleteForm {
 /// `POST` for `/delete/account`
 /// This will:
 /// - Verify password in deletion form
-/// - Delete the username from the notes table
+/// - Delete all notes for the username and then the username from the notes table
 /// - Delete the username from the auth table
+/// - Revoke all API tokens and end all other sessions
 /// - Logout the existing session (which will ultimately delete the current session token)
 pub async fn del_account_post(
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
     Form(form): Form<DeleteForm>,
 ) -> crate::JotsyResponse {
     let mut con = db.get().await?;
-    let username = self::delete_verify(&mut cookies, &mut con, form).await?;
+    let username = self::privileged_verify(&mut creds, &mut con, &form.password).await?;
     // cool, let's first delete the notes (to avoid a new user taking over this user's notes)
+    super::app::remove_all_notes(&mut con, &username).await?;
     con.switch(crate::TABLE_NOTES).await?;
     con.del(&username).await?;
+    // and revoke any API tokens and other sessions
+    super::notebooks::remove_all(&mut con, &username).await?;
+    super::settings::remove_all(&mut con, &username).await?;
+    super::shares::remove_all(&mut con, &username).await?;
+    super::admin::remove(&mut con, &username).await?;
+    super::totp::remove_all(&mut con, &username).await?;
+    super::passkeys::remove_all(&mut con, &username).await?;
+    super::tokens::revoke_all(&mut con, &username).await?;
+    let current = creds.session_hash();
+    super::sessions::remove_all(&mut con, &username, current.as_deref()).await?;
     // now, let's delete the user token (user -> pass)
     con.switch(crate::TABLE_AUTH).await?;
     con.del(&username).await?;
     drop(con);
     // now log the user out
     log::info!("Deleted account `{username}`");
-    super::logout::logout_core(cookies, "Finished deleting account", db).await
+    super::logout::logout_core(creds.cookies, "Finished deleting account", db).await
 }
 
 /// `POST` for `/delete/notes`
 /// This will:
 /// - Verify password in deletion form
-/// - Clear all notes for the username from the notes table
+/// - Move all the user's notes to the trash
 pub async fn del_notes_post(
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
     Form(form): Form<DeleteForm>,
 ) -> crate::JotsyResponse {
     let mut con = db.get().await?;
-    let username = self::delete_verify(&mut cookies, &mut con, form).await?;
-    con.switch(crate::TABLE_NOTES).await?;
-    if let Element::RespCode(RespCode::Okay) =
-        con.run_query(&query!("LMOD", username, "clear")).await?
-    {
-        resp(
-            StatusCode::OK,
-            NoticePage::new_redirect("Deleted all notes"),
-        )
+    let username = self::privileged_verify(&mut creds, &mut con, &form.password).await?;
+    let trashed = super::app::trash_all_notes(&mut con, &username).await?;
+    resp(
+        StatusCode::OK,
+        NoticePage::new_redirect(format!("Moved {trashed} note(s) to the trash")),
+    )
+}
+
+/// `GET` for `/account/password`
+pub async fn password_get(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    resp(
+        StatusCode::OK,
+        ChangePassword::render_new(username, None, creds.csrf_token()),
+    )
+}
+
+#[derive(Deserialize)]
+/// The form to change the password
+pub struct ChangePasswordForm {
+    password: String,
+    newpassword: String,
+    vnewpassword: String,
+}
+
+/// `POST` for `/account/password`
+/// This will:
+/// - Verify the current password in the form
+/// - Check the new password
+/// - Store the hash of the new password in the auth table
+/// - End every other session for the user and revoke all their API tokens, so that anyone
+///   else who knew the old password is logged out
+pub async fn password_post(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<ChangePasswordForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = self::privileged_verify(&mut creds, &mut con, &form.password).await?;
+    // do a double check on the data; never trust the client
+    let error = if form.newpassword != form.vnewpassword {
+        Some("The passwords do not match")
+    } else if form.newpassword.len() < 8 {
+        Some("Passwords must have atleast 8 characters")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return resp(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            ChangePassword::render_new(username, Some(error), creds.csrf_token()),
+        );
+    }
+    let hash = util::bcrypt_hash(&form.newpassword);
+    con.switch(crate::TABLE_AUTH).await?;
+    con.update(&username, hash).await?;
+    let current = creds.session_hash();
+    let ended = super::sessions::remove_all(&mut con, &username, current.as_deref()).await?;
+    super::tokens::revoke_all(&mut con, &username).await?;
+    log::info!(
+        "Changed password for `{username}`, ended {ended} other session(s) and revoked all API \
+        tokens"
+    );
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to(
+            "Changed your password, logged out all other sessions and revoked your API tokens",
+            "/account",
+        ),
+    )
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+/// What to export notes as
+pub enum ExportFormat {
+    /// A zip of markdown files, one per note
+    Markdown,
+    /// The stored notes, as is
+    #[default]
+    Json,
+    /// A single page with every note rendered
+    Html,
+}
+
+#[derive(Deserialize)]
+/// The query string for an export
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// Returns the front-matter and body of a note as a markdown file. Strings are written as JSON,
+/// which is also valid YAML. `updated` is left out if the note was never edited
+fn note_to_markdown(note: &Note, notebook: &str) -> String {
+    let updated = note
+        .updated
+        .map(|date| {
+            format!(
+                "updated: {}\n",
+                date.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "---\n\
+        id: {}\n\
+        created: {}\n\
+        {}\
+        format: {}\n\
+        notebook: {}\n\
+        tags: {}\n\
+        pinned: {}\n\
+        archived: {}\n\
+        ---\n\n{}\n",
+        serde_json::to_string(&note.id).unwrap(),
+        note.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+        updated,
+        serde_json::to_string(&note.format).unwrap(),
+        serde_json::to_string(notebook).unwrap(),
+        serde_json::to_string(&note.tags).unwrap(),
+        note.pinned,
+        note.archived,
+        note.body,
+    )
+}
+
+/// Returns a zip with a markdown file for every note
+fn export_markdown(
+    notes: &[Note],
+    notebooks: &HashMap<String, String>,
+) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for note in notes {
+        let notebook = notebooks.get(&note.notebook).unwrap_or(&note.notebook);
+        zip.start_file(format!("{}.md", note.id), options)?;
+        zip.write_all(self::note_to_markdown(note, notebook).as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Returns the start, the separator and the end of a JSON export of `count` notes. Along with
+/// [`note_to_json`], this writes the same as `serde_json::to_vec_pretty` on all the notes
+fn json_parts(count: usize) -> (String, &'static str, String) {
+    if count == 0 {
+        ("[".to_owned(), "", "]".to_owned())
     } else {
-        Err(ResponseError::DatabaseError(
-            SkyhashError::UnexpectedDataType.into(),
-        ))
+        ("[\n".to_owned(), ",\n", "\n]".to_owned())
+    }
+}
+
+/// Returns a note in a JSON export (indented to be in the array)
+fn note_to_json(note: &Note) -> String {
+    // strings in JSON can't have raw newlines in them, so this only indents the structure
+    let json = serde_json::to_string_pretty(note).unwrap();
+    format!("  {}", json.replace('\n', "\n  "))
+}
+
+/// Stream the notes with the given IDs: `head`, then every note rendered by `render` (with
+/// `separator` between them), then `tail`. The notes are fetched and sent a batch at a time by
+/// a background task, so that an export never has all of a user's notes in memory. If the
+/// database fails halfway, the response is aborted so that the client doesn't take what it got
+/// for a whole export
+fn stream_notes(
+    db: AsyncPool,
+    username: String,
+    ids: Vec<String>,
+    (head, separator, tail): (String, &'static str, String),
+    render: impl Fn(&Note) -> String + Send + 'static,
+) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(head.into()).await.is_err() {
+            return;
+        }
+        let mut con = match db.get().await {
+            Ok(con) => con,
+            Err(e) => {
+                log::error!("Failed to export the notes of `{username}`: {e}");
+                return sender.abort();
+            }
+        };
+        for (idx, batch) in ids.chunks(EXPORT_BATCH).enumerate() {
+            let notes = match super::app::get_notes(&mut con, &username, batch).await {
+                Ok(notes) => notes,
+                Err(e) => {
+                    log::error!("Failed to export the notes of `{username}`: {e}");
+                    return sender.abort();
+                }
+            };
+            let mut chunk = String::new();
+            for (n, note) in notes.iter().enumerate() {
+                if idx != 0 || n != 0 {
+                    chunk.push_str(separator);
+                }
+                chunk.push_str(&render(note));
+            }
+            // the client went away
+            if sender.send_data(chunk.into()).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send_data(tail.into()).await;
+    });
+    body
+}
+
+/// `GET` for `/account/export`
+///
+/// This will:
+/// - Verify the session
+/// - Return all the user's notes (oldest first, including archived notes) as a download: a zip
+///   of markdown files with front-matter, the stored notes as JSON or a static HTML page
+///
+/// JSON and HTML exports are streamed as the notes are read. A zip can't be written without
+/// seeking back to its start, so markdown exports are built in memory before they're sent
+pub async fn export(
+    Query(query): Query<ExportQuery>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponseResult<Response> {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let ids = super::app::get_note_ids(&mut con, &username).await?;
+    let notebooks: HashMap<String, String> = super::notebooks::get_notebooks(&mut con, &username)
+        .await?
+        .into_iter()
+        .map(|notebook| (notebook.id, notebook.name))
+        .collect();
+    let tz = super::settings::get_timezone(&mut con, &username).await?;
+    let (body, ty, extension) = match query.format {
+        ExportFormat::Markdown => {
+            let notes = super::app::get_notes(&mut con, &username, &ids).await?;
+            match self::export_markdown(&notes, &notebooks) {
+                Ok(zip) => (Body::from(zip), "application/zip", "zip"),
+                Err(e) => {
+                    log::error!("Failed to create export for `{username}`: {e}");
+                    return Ok(NoticePage::e500_resp());
+                }
+            }
+        }
+        ExportFormat::Json => {
+            let parts = self::json_parts(ids.len());
+            let body =
+                self::stream_notes(db.clone(), username.clone(), ids, parts, self::note_to_json);
+            (body, mime::APPLICATION_JSON.as_ref(), "json")
+        }
+        ExportFormat::Html => {
+            let head = ExportPage::render_new(username.clone(), ids.len());
+            let parts = (head, "\n", format!("\n{EXPORT_END}"));
+            let body = self::stream_notes(db.clone(), username.clone(), ids, parts, move |note| {
+                let notebook = notebooks.get(&note.notebook).unwrap_or(&note.notebook);
+                ExportNote::render_new(note, notebook, tz)
+            });
+            (body, mime::TEXT_HTML_UTF_8.as_ref(), "html")
+        }
+    };
+    log::info!("Exported notes for `{username}`");
+    let disposition = format!(
+        "attachment; filename=\"jotsy-{}.{extension}\"",
+        Local::now().format("%Y-%m-%d")
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(ty));
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition).unwrap(),
+    );
+    Ok((StatusCode::OK, headers, boxed(body)).into_response())
+}
+
+#[derive(Deserialize)]
+/// The form to choose a timezone
+pub struct TimezoneForm {
+    /// An IANA timezone name, or empty to use the browser's timezone
+    timezone: String,
+}
+
+/// `POST` for `/account/timezone`
+///
+/// This will:
+/// - Verify the session
+/// - Store the user's timezone (or remove it, so that dates are shown in the browser's
+///   timezone)
+pub async fn timezone_post(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<TimezoneForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let timezone = form.timezone.trim();
+    if !timezone.is_empty() && timezone.parse::<Tz>().is_err() {
+        return resp(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            NoticePage::render_new("That isn't a timezone", false),
+        );
+    }
+    let timezone = (!timezone.is_empty()).then_some(timezone);
+    super::settings::set(&mut con, &username, super::settings::TIMEZONE, timezone).await?;
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to("Saved your timezone", "/account"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_parts, note_to_json};
+    use crate::handlers::app::{Note, NoteFormat};
+    use chrono::Utc;
+
+    /// Put together a JSON export the way it is streamed
+    fn streamed(notes: &[Note]) -> String {
+        let (head, separator, tail) = json_parts(notes.len());
+        let notes: Vec<String> = notes.iter().map(note_to_json).collect();
+        format!("{head}{}{tail}", notes.join(separator))
+    }
+
+    #[test]
+    fn streamed_json_is_pretty_json() {
+        let mut notes = [
+            Note::new(
+                Utc::now(),
+                "# One\n\n- a\n- b".to_owned(),
+                NoteFormat::Markdown,
+            ),
+            Note::new(Utc::now(), "two #tag".to_owned(), NoteFormat::Plaintext),
+        ];
+        notes[1].pinned = true;
+        for count in 0..=notes.len() {
+            let notes = &notes[..count];
+            assert_eq!(
+                streamed(notes),
+                serde_json::to_string_pretty(notes).unwrap()
+            );
+        }
     }
 }
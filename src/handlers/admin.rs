@@ -0,0 +1,299 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Instance administration. Admins can see every account on the instance and disable them,
+//! reset their passwords or log them out from `/admin` (except for other admins, who can only
+//! be enabled). `default:jotsyusers` maps `username -> UserStatus`; a user without one is
+//! neither an admin nor disabled. The first user to sign up is made an admin (only ever once),
+//! and the user named by `JOTSY_ADMIN_USER` always is one
+
+use super::Credentials;
+use crate::{
+    error::ResponseError,
+    templates::{AdminPage, NoticePage},
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form, Path},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+
+/// The length of the temporary password set by a password reset
+const TEMP_PASSWORD_LEN: usize = 16;
+/// The key in the meta table that holds the user who was made an admin for signing up first
+const FIRST_ADMIN: &str = "first-admin";
+
+#[derive(Serialize, Deserialize, Default)]
+/// What an admin has set for a user. This is stored as JSON in Skytable and is ser/de-d as
+/// required
+pub struct UserStatus {
+    #[serde(default)]
+    pub admin: bool,
+    /// Disabled users can't log in and their API tokens and share links stop working
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// Returns the status of a user
+pub async fn get(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<UserStatus> {
+    con.switch(crate::TABLE_USERS).await?;
+    let status: Result<String, Error> = con.get(username).await;
+    match status {
+        Ok(json) => Ok(serde_json::from_str(&json).unwrap()),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(UserStatus::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn set(
+    con: &mut Connection,
+    username: &str,
+    status: &UserStatus,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_USERS).await?;
+    let json = serde_json::to_string(status).unwrap();
+    let _: u64 = con.run_query(&query!("USET", username, json)).await?;
+    Ok(())
+}
+
+/// Remove the status of a user (with their account)
+pub async fn remove(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_USERS).await?;
+    con.del(username).await?;
+    Ok(())
+}
+
+/// Returns true if the user is an admin
+pub async fn is_admin(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<bool> {
+    if util::admin_user() == Some(username) {
+        return Ok(true);
+    }
+    Ok(self::get(con, username).await?.admin)
+}
+
+/// Returns true if the user has been disabled
+pub async fn is_disabled(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<bool> {
+    Ok(self::get(con, username).await?.disabled)
+}
+
+/// Make a user that just signed up an admin if they are the first user on this instance. The
+/// role is claimed by setting [`FIRST_ADMIN`] in the meta table, which only succeeds once, so
+/// concurrent signups (or signups after the first user deleted their account) can't both get it
+pub async fn bootstrap(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_AUTH).await?;
+    if con.dbsize().await? != 1 {
+        return Ok(());
+    }
+    con.switch(crate::TABLE_META).await?;
+    if !con.set(FIRST_ADMIN, username).await? {
+        return Ok(());
+    }
+    let status = UserStatus {
+        admin: true,
+        disabled: false,
+    };
+    self::set(con, username, &status).await?;
+    log::info!("Made `{username}` an admin since they are the first user");
+    Ok(())
+}
+
+/// Verify an admin or error. This is like [`super::root::verify_user_or_error`], but users
+/// who aren't admins get an error page instead
+async fn verify_admin_or_error(
+    con: &mut Connection,
+    creds: &mut Credentials,
+) -> crate::JotsyResponseResult<String> {
+    let username = super::root::verify_user_or_error(con, creds).await?;
+    if self::is_admin(con, &username).await? {
+        Ok(username)
+    } else {
+        Err(ResponseError::Redirect(NoticePage::render_new(
+            "Only admins can see this page",
+            false,
+        )))
+    }
+}
+
+/// An account as listed in the admin console
+pub struct AdminUser {
+    pub username: String,
+    pub status: UserStatus,
+    /// The number of notes (not counting the trash)
+    pub notes: u64,
+    /// The number of notes in the trash
+    pub trashed: u64,
+    /// The number of active sessions
+    pub sessions: usize,
+}
+
+/// Returns every account on the instance, sorted by username
+async fn list_users(con: &mut Connection) -> crate::JotsyResponseResult<Vec<AdminUser>> {
+    con.switch(crate::TABLE_NOTES).await?;
+    let mut usernames: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    usernames.sort();
+    let mut users = Vec::with_capacity(usernames.len());
+    for username in usernames {
+        let mut status = self::get(con, &username).await?;
+        status.admin |= util::admin_user() == Some(username.as_str());
+        let notes = super::app::count_notes(con, &username).await?;
+        con.switch(crate::TABLE_TRASH).await?;
+        let trashed = util::list_len(con, &username).await?;
+        let sessions = super::sessions::list(con, &username, None).await?.len();
+        users.push(AdminUser {
+            username,
+            status,
+            notes,
+            trashed,
+            sessions,
+        });
+    }
+    Ok(users)
+}
+
+/// `GET` for `/admin`
+///
+/// This will:
+/// - Verify the session and that the user is an admin
+/// - Return every account on the instance with its note counts and status
+pub async fn admin_page(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = self::verify_admin_or_error(&mut con, &mut creds).await?;
+    let users = self::list_users(&mut con).await?;
+    resp(
+        StatusCode::OK,
+        AdminPage::render_new(username, users, creds.csrf_token()),
+    )
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+/// Something an admin can do to an account
+pub enum UserAction {
+    /// Stop the user from logging in and end their sessions
+    Disable,
+    Enable,
+    /// Set a temporary password, turn off two-factor authentication, remove their passkeys,
+    /// revoke their API tokens and end their sessions
+    ResetPassword,
+    /// End all their sessions
+    Logout,
+}
+
+/// `POST` for `/admin/users/:username/:action`
+///
+/// This will:
+/// - Verify the session and that the user is an admin
+/// - Check that the account exists and isn't the admin's own account (which they can manage
+///   from their account page). Other admins can't be disabled, reset or logged out, so that
+///   one admin can't lock the others out
+/// - Disable or enable the account, reset its password (turning off two-factor authentication,
+///   removing its passkeys and revoking its API tokens) or end all its sessions
+/// - Return to the admin console (after showing the temporary password for a reset)
+pub async fn user_action(
+    Path((target, action)): Path<(String, UserAction)>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(_): Form<util::Empty>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = self::verify_admin_or_error(&mut con, &mut creds).await?;
+    if target == username {
+        return resp(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            NoticePage::redirect_to("Manage your own account from its account page", "/admin"),
+        );
+    }
+    con.switch(crate::TABLE_AUTH).await?;
+    if con.exists(&target).await? == 0 {
+        return resp(
+            StatusCode::NOT_FOUND,
+            NoticePage::redirect_to("That account doesn't exist", "/admin"),
+        );
+    }
+    if !matches!(action, UserAction::Enable) && self::is_admin(&mut con, &target).await? {
+        return resp(
+            StatusCode::FORBIDDEN,
+            NoticePage::redirect_to("You can't manage the account of another admin", "/admin"),
+        );
+    }
+    let message = match action {
+        UserAction::Disable | UserAction::Enable => {
+            let disabled = matches!(action, UserAction::Disable);
+            let mut status = self::get(&mut con, &target).await?;
+            status.disabled = disabled;
+            self::set(&mut con, &target, &status).await?;
+            if disabled {
+                let ended = super::sessions::remove_all(&mut con, &target, None).await?;
+                log::info!("`{username}` disabled `{target}` and ended {ended} session(s)");
+                format!("Disabled @{target}")
+            } else {
+                log::info!("`{username}` enabled `{target}`");
+                format!("Enabled @{target}")
+            }
+        }
+        UserAction::ResetPassword => {
+            let password = util::random_alphanumeric(TEMP_PASSWORD_LEN);
+            con.switch(crate::TABLE_AUTH).await?;
+            con.update(&target, util::bcrypt_hash(&password)).await?;
+            super::lockout::forget(&mut con, &target).await?;
+            // the user might have lost their authenticator too, which is why they need a reset
+            super::totp::remove_all(&mut con, &target).await?;
+            // and a passkey would still let whoever has it in without the new password
+            super::passkeys::remove_all(&mut con, &target).await?;
+            super::tokens::revoke_all(&mut con, &target).await?;
+            let ended = super::sessions::remove_all(&mut con, &target, None).await?;
+            log::info!(
+                "`{username}` reset the password of `{target}`, turned off their two-factor \
+                authentication, removed their passkeys, revoked their API tokens and ended \
+                {ended} session(s)"
+            );
+            return resp(
+                StatusCode::OK,
+                NoticePage::render_report(
+                    format!("Reset the password of @{target}"),
+                    vec![
+                        format!("Their temporary password is {password}"),
+                        "Two-factor authentication was turned off, their passkeys were removed \
+                        and their API tokens were revoked"
+                            .to_owned(),
+                        "It won't be shown again, so send it to them now. They should change \
+                        it once they log in"
+                            .to_owned(),
+                    ],
+                    "/admin",
+                ),
+            );
+        }
+        UserAction::Logout => {
+            let ended = super::sessions::remove_all(&mut con, &target, None).await?;
+            log::info!("`{username}` ended {ended} session(s) of `{target}`");
+            format!("Logged @{target} out of {ended} session(s)")
+        }
+    };
+    resp(StatusCode::OK, NoticePage::redirect_to(message, "/admin"))
+}
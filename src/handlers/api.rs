@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! The JSON API (mounted at `/api/v1`). These handlers mirror the HTML ones, but accept and
+//! return JSON and report errors as [`ApiError`]s instead of pages
+
+use super::{
+    app::{self, Note, NoteFormat},
+    notebooks, Credentials,
+};
+use crate::error::ApiError;
+use axum::{
+    extract::{rejection::JsonRejection, Extension, Path},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use skytable::{aio::Connection, pool::AsyncPool};
+
+#[derive(Deserialize)]
+/// The request body for creating or updating a note
+pub struct NoteBody {
+    body: String,
+    /// Defaults to markdown for new notes, and is left as is for updates
+    format: Option<NoteFormat>,
+    /// Defaults to the default notebook for new notes, and is left as is for updates
+    notebook: Option<String>,
+    /// Default to false for new notes, and are left as is for updates
+    pinned: Option<bool>,
+    archived: Option<bool>,
+}
+
+/// Set the optional fields of a request on a note
+fn apply(note: &mut Note, body: &NoteBody) {
+    if let Some(format) = body.format {
+        note.format = format;
+    }
+    if let Some(notebook) = &body.notebook {
+        note.notebook = notebook.clone();
+    }
+    if let Some(archived) = body.archived {
+        note.set_archived(archived);
+    }
+    if let Some(pinned) = body.pinned {
+        note.set_pinned(pinned);
+    }
+}
+
+/// Verify the session (or API token) and return the username
+async fn verify(con: &mut Connection, creds: &mut Credentials) -> Result<String, ApiError> {
+    Ok(super::root::verify_user_or_error(con, creds).await?)
+}
+
+/// Check that the notebook a note is being put in (if any) exists
+async fn check_notebook(
+    con: &mut Connection,
+    username: &str,
+    notebook: Option<&str>,
+) -> Result<(), ApiError> {
+    if let Some(id) = notebook {
+        if notebooks::get_notebook(con, username, id).await?.is_none() {
+            return Err(ApiError::BadRequest(format!("no notebook with ID `{id}`")));
+        }
+    }
+    Ok(())
+}
+
+/// Unwrap a JSON request body, turning a rejection into a JSON error
+fn body(body: Result<Json<NoteBody>, JsonRejection>) -> Result<NoteBody, ApiError> {
+    match body {
+        Ok(Json(body)) if body.body.is_empty() => {
+            Err(ApiError::BadRequest("note body cannot be empty".to_owned()))
+        }
+        Ok(Json(body)) => Ok(body),
+        Err(e) => Err(ApiError::BadRequest(e.to_string())),
+    }
+}
+
+/// `GET` for `/api/v1/notes`. Returns all notes, newest first
+pub async fn list_notes(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::ApiResponse<Vec<Note>> {
+    let mut con = db.get().await?;
+    let username = self::verify(&mut con, &mut creds).await?;
+    let ids = app::get_note_ids(&mut con, &username).await?;
+    let mut notes = app::get_notes(&mut con, &username, &ids).await?;
+    notes.reverse();
+    Ok((StatusCode::OK, Json(notes)))
+}
+
+/// `POST` for `/api/v1/notes`
+pub async fn create_note(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    note: Result<Json<NoteBody>, JsonRejection>,
+) -> crate::ApiResponse<Note> {
+    let note = self::body(note)?;
+    let mut con = db.get().await?;
+    let username = self::verify(&mut con, &mut creds).await?;
+    self::check_notebook(&mut con, &username, note.notebook.as_deref()).await?;
+    let mut new = Note::new_now(note.body.clone(), NoteFormat::default());
+    self::apply(&mut new, &note);
+    let note = new;
+    app::push_note(&mut con, &username, &note).await?;
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+/// `GET` for `/api/v1/notes/:id`
+pub async fn get_note(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::ApiResponse<Note> {
+    let mut con = db.get().await?;
+    let username = self::verify(&mut con, &mut creds).await?;
+    match app::get_live_note(&mut con, &username, &id).await? {
+        Some(note) => Ok((StatusCode::OK, Json(note))),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+/// `PUT` for `/api/v1/notes/:id`
+pub async fn update_note(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    update: Result<Json<NoteBody>, JsonRejection>,
+) -> crate::ApiResponse<Note> {
+    let update = self::body(update)?;
+    let mut con = db.get().await?;
+    let username = self::verify(&mut con, &mut creds).await?;
+    self::check_notebook(&mut con, &username, update.notebook.as_deref()).await?;
+    match app::get_live_note(&mut con, &username, &id).await? {
+        Some(old) => {
+            let mut note = old.clone();
+            note.edit(update.body.clone(), update.format);
+            self::apply(&mut note, &update);
+            app::update_note(&mut con, &username, &old, &note).await?;
+            super::history::record(&mut con, &username, &creds, &old, &note).await?;
+            Ok((StatusCode::OK, Json(note)))
+        }
+        None => Err(ApiError::NotFound),
+    }
+}
+
+/// `DELETE` for `/api/v1/notes/:id`. Moves the note to the trash
+pub async fn delete_note(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> Result<StatusCode, ApiError> {
+    let mut con = db.get().await?;
+    let username = self::verify(&mut con, &mut creds).await?;
+    match app::get_live_note(&mut con, &username, &id).await? {
+        Some(note) => {
+            app::trash_note(&mut con, &username, &note).await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err(ApiError::NotFound),
+    }
+}
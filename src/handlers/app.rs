@@ -14,50 +14,724 @@
  * limitations under the License.
 */
 
+use super::{
+    index,
+    notebooks::{self, Notebook},
+    Credentials,
+};
 use crate::{
-    templates::{App, NoticePage, SingleNote},
+    error::ResponseError,
+    templates::{App, NotesPage, NoticePage, SingleNote},
     util::{self, resp},
 };
 use axum::{
-    extract::{Extension, Form},
+    extract::{Extension, Form, Path, Query},
     http::StatusCode,
 };
-use chrono::prelude::Local;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use skytable::{ddl::AsyncDdl, pool::AsyncPool, query, Element, RespCode};
-use tower_cookies::Cookies;
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+use std::collections::BTreeSet;
+
+const NOTE_ID_LEN: usize = 16;
+/// The number of notes on a page
+const PAGE_SIZE: u64 = 25;
+/// The number of note IDs we read at once when looking for a cursor
+const CURSOR_SCAN: u64 = 500;
 
-#[derive(Serialize, Deserialize)]
-/// A `Note`. This is stored as JSON in Skytable and is ser/de-d as required
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+/// How the body of a note is rendered. Notes stored before this existed are markdown
+pub enum NoteFormat {
+    #[default]
+    Markdown,
+    Plaintext,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+/// A `Note`. This is stored as JSON in Skytable and is ser/de-d as required. We only ever
+/// store the raw body; it is rendered (according to its format) when displayed
 pub struct Note {
-    pub date: String,
+    pub id: String,
+    /// When the note was created. Notes from older versions call this `date`
+    #[serde(with = "rfc3339", alias = "date")]
+    pub created: DateTime<Utc>,
+    /// When the body (or format) of the note was last edited, if it has been
+    #[serde(default, with = "rfc3339::option")]
+    pub updated: Option<DateTime<Utc>>,
     pub body: String,
+    #[serde(default)]
+    pub format: NoteFormat,
+    /// The `#hashtags` in the body
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    /// The ID of the notebook this note is in
+    #[serde(default = "default_notebook")]
+    pub notebook: String,
+    /// Pinned notes are shown above the other notes in their notebook
+    #[serde(default)]
+    pub pinned: bool,
+    /// Archived notes are hidden from their notebook and shown on `/archive` instead
+    #[serde(default)]
+    pub archived: bool,
+    /// When the note was moved to the trash, if it is in the trash
+    #[serde(default, with = "rfc3339::option")]
+    pub trashed: Option<DateTime<Utc>>,
+}
+
+/// Dates are stored as RFC 3339 timestamps in UTC. Notes from older versions have display
+/// strings instead, which are still read (see [`util::parse_date`]) until they are migrated
+pub(super) mod rfc3339 {
+    use crate::util;
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(date: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&date.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+        let date = String::deserialize(d)?;
+        util::parse_date(&date).ok_or_else(|| D::Error::custom(format!("invalid date `{date}`")))
+    }
+
+    /// The same, for dates that might not be set
+    pub mod option {
+        use chrono::{DateTime, Utc};
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            date: &Option<DateTime<Utc>>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            match date {
+                Some(date) => super::serialize(date, s),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<DateTime<Utc>>, D::Error> {
+            #[derive(Deserialize)]
+            struct Date(#[serde(with = "super")] DateTime<Utc>);
+            Ok(Option::<Date>::deserialize(d)?.map(|Date(date)| date))
+        }
+    }
+}
+
+fn default_notebook() -> String {
+    notebooks::DEFAULT_NOTEBOOK.to_owned()
 }
 
 impl Note {
-    fn new_from_json<T: AsRef<str>>(json: T) -> Self {
-        let data: Note = serde_json::from_str(json.as_ref()).unwrap();
-        Self::new(data.date, util::md_to_html(&data.body))
+    pub fn new(created: DateTime<Utc>, body: String, format: NoteFormat) -> Self {
+        Self {
+            id: generate_note_id(),
+            created,
+            updated: None,
+            tags: super::tags::extract(&body),
+            body,
+            format,
+            notebook: default_notebook(),
+            pinned: false,
+            archived: false,
+            trashed: None,
+        }
+    }
+    /// Replace the body of this note (and so, its tags) and its format, if one is given. If
+    /// either changed, the note was updated right now
+    pub fn edit(&mut self, body: String, format: Option<NoteFormat>) {
+        let format = format.unwrap_or(self.format);
+        if body != self.body || format != self.format {
+            self.updated = Some(Utc::now());
+        }
+        self.tags = super::tags::extract(&body);
+        self.body = body;
+        self.format = format;
+    }
+    /// Returns the time this note was last edited (or created, if it never was)
+    pub fn last_edited(&self) -> DateTime<Utc> {
+        self.updated.unwrap_or(self.created)
     }
-    fn new(date: String, body: String) -> Self {
-        Self { date, body }
+    /// Returns a new note created right now
+    pub fn new_now(body: String, format: NoteFormat) -> Self {
+        Self::new(Utc::now(), body, format)
+    }
+    /// Pin (or unpin) this note. Archived notes can't be pinned
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned && !self.archived;
+    }
+    /// Archive (or unarchive) this note. Archiving a note unpins it
+    pub fn set_archived(&mut self, archived: bool) {
+        self.archived = archived;
+        if archived {
+            self.pinned = false;
+        }
+    }
+    fn from_json<T: AsRef<str>>(json: T) -> Self {
+        serde_json::from_str(json.as_ref()).unwrap()
+    }
+    pub fn is_plaintext(&self) -> bool {
+        self.format == NoteFormat::Plaintext
+    }
+    /// Returns when this note will be deleted forever, if it is in the trash
+    pub fn purge_date(&self) -> Option<DateTime<Utc>> {
+        self.trashed
+            .map(|trashed| trashed + util::trash_retention())
     }
 }
 
-/// Returns the main app page for an authenticated user
-pub async fn app(uname: String, db: AsyncPool) -> crate::JotsyResponse {
-    let mut con = db.get().await?;
+/// Returns a new note ID
+fn generate_note_id() -> String {
+    util::random_alphanumeric(NOTE_ID_LEN)
+}
+
+/// Returns the key for a note in the note data table
+pub fn note_key(username: &str, id: &str) -> String {
+    format!("{username}/{id}")
+}
+
+/// Returns the IDs of all the notes for the given user, oldest first
+pub async fn get_note_ids(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<String>> {
+    con.switch(crate::TABLE_NOTES).await?;
+    Ok(con.run_query(&query!("LGET", username)).await?)
+}
+
+/// A page of notes, newest first
+pub struct Page {
+    /// The pinned notes, which are only on the first page of a notebook
+    pub pinned: Vec<Note>,
+    /// The other notes
+    pub notes: Vec<Note>,
+    /// If there are older notes after this page, the ID to get the next page `before`. This
+    /// isn't always the last note on the page, since pinned notes are left out
+    pub before: Option<String>,
+}
+
+/// Returns the position of a note in a list of notes in the current table. Cursors are almost
+/// always close to the end of the list (users scroll back from their newest notes), so we
+/// search backwards from there one range at a time
+async fn find_note(
+    con: &mut Connection,
+    list: &str,
+    id: &str,
+    len: u64,
+) -> crate::JotsyResponseResult<Option<u64>> {
+    let mut stop = len;
+    while stop != 0 {
+        let start = stop.saturating_sub(CURSOR_SCAN);
+        let ids = util::list_range(con, list, start, stop).await?;
+        if let Some(pos) = ids.iter().rposition(|nid| nid == id) {
+            return Ok(Some(start + pos as u64));
+        }
+        stop = start;
+    }
+    Ok(None)
+}
+
+#[derive(Clone, Copy)]
+/// A list of notes that is read a page at a time
+pub enum NoteList<'a> {
+    /// The notes in the notebook with this ID (which doesn't include archived notes)
+    Notebook(&'a str),
+    /// The user's archived notes
+    Archive,
+    /// The notes in the user's trash
+    Trash,
+    /// The notes with this tag, in the order they were tagged
+    Tag(&'a str),
+}
+
+impl NoteList<'_> {
+    /// Returns the table and key of this list
+    fn location(&self, username: &str) -> (&'static str, String) {
+        match self {
+            Self::Notebook(notebook) => (
+                crate::TABLE_NOTEBOOK_NOTES,
+                notebooks::notes_key(username, notebook),
+            ),
+            Self::Archive => (crate::TABLE_ARCHIVE, username.to_owned()),
+            Self::Trash => (crate::TABLE_TRASH, username.to_owned()),
+            Self::Tag(tag) => index::TAGS.location(username, tag),
+        }
+    }
+
+    /// Returns true if a note in this list is shown in its pages. A notebook shows its pinned
+    /// notes above its first page instead, but every other list (like the trash, which can
+    /// have notes that were pinned when they were deleted) shows all its notes
+    fn shows_in_pages(&self, note: &Note) -> bool {
+        !(matches!(self, Self::Notebook(_)) && note.pinned)
+    }
+}
+
+/// Returns the lists (as `(table, key)`) that a note belongs in:
+/// - a note in the trash is only in the user's trash
+/// - any other note is in the user's list of all their notes, and:
+///     - an archived note is in the user's archive
+///     - any other note is in its notebook, and also in the notebook's pinned notes if it's
+///       pinned
+///
+/// The pinned notes of a notebook use the same key as the notebook, in `default:jotsypinned`
+fn lists(username: &str, note: &Note) -> Vec<(&'static str, String)> {
+    if note.trashed.is_some() {
+        return vec![NoteList::Trash.location(username)];
+    }
+    let mut lists = vec![(crate::TABLE_NOTES, username.to_owned())];
+    if note.archived {
+        lists.push(NoteList::Archive.location(username));
+        return lists;
+    }
+    lists.push(NoteList::Notebook(&note.notebook).location(username));
+    if note.pinned {
+        lists.push((
+            crate::TABLE_PINNED,
+            notebooks::notes_key(username, &note.notebook),
+        ));
+    }
+    lists
+}
+
+/// Move a note from the lists that `old` belongs in to the lists that `new` belongs in (see
+/// [`lists`]). It goes to the end (the newest end) of any list it wasn't already in
+async fn sync_lists(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+    old: Option<&Note>,
+    new: Option<&Note>,
+) -> crate::JotsyResponseResult<()> {
+    let old = old.map_or_else(Vec::new, |note| self::lists(username, note));
+    let new = new.map_or_else(Vec::new, |note| self::lists(username, note));
+    for (table, key) in old.iter().filter(|list| !new.contains(list)) {
+        con.switch(*table).await?;
+        util::list_remove(con, key, |nid| nid == id).await?;
+    }
+    for (table, key) in new.iter().filter(|list| !old.contains(list)) {
+        con.switch(*table).await?;
+        util::list_push(con, key, id).await?;
+    }
+    Ok(())
+}
+
+/// Returns the page of notes in a list right before (older than) the note with the ID
+/// `before`, or the newest notes if it isn't set. Returns `None` if there is no note with the
+/// ID `before` in the list. The first page of a notebook has its pinned notes separately
+pub async fn get_page(
+    con: &mut Connection,
+    username: &str,
+    list: NoteList<'_>,
+    before: Option<&str>,
+) -> crate::JotsyResponseResult<Option<Page>> {
+    let (table, key) = list.location(username);
+    con.switch(table).await?;
+    let len = util::list_len(con, &key).await?;
+    let stop = match before {
+        Some(id) => match self::find_note(con, &key, id, len).await? {
+            Some(pos) => pos,
+            None => return Ok(None),
+        },
+        None => len,
+    };
+    let start = stop.saturating_sub(PAGE_SIZE);
+    let ids = util::list_range(con, &key, start, stop).await?;
+    let mut notes = self::get_notes(con, username, &ids).await?;
+    notes.reverse();
+    notes.retain(|note| list.shows_in_pages(note));
+    // the pinned notes of a notebook are shown above its first page, most recently pinned first
+    let mut pinned = Vec::new();
+    if let (NoteList::Notebook(_), None) = (list, before) {
+        con.switch(crate::TABLE_PINNED).await?;
+        let ids = util::list_get(con, &key).await?;
+        pinned = self::get_notes(con, username, &ids).await?;
+        pinned.reverse();
+    }
+    Ok(Some(Page {
+        pinned,
+        notes,
+        before: (start != 0).then(|| ids[0].clone()),
+    }))
+}
+
+/// Returns the number of notes the user has
+pub async fn count_notes(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<u64> {
+    con.switch(crate::TABLE_NOTES).await?;
+    util::list_len(con, username).await
+}
+
+/// Returns the notes with the given IDs (in the same order). IDs that don't exist are skipped
+pub async fn get_notes(
+    con: &mut Connection,
+    username: &str,
+    ids: &[String],
+) -> crate::JotsyResponseResult<Vec<Note>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    con.switch(crate::TABLE_NOTE_DATA).await?;
+    let keys: Vec<String> = ids.iter().map(|id| note_key(username, id)).collect();
+    let notes = util::mget(con, keys).await?;
+    Ok(notes.iter().flatten().map(Note::from_json).collect())
+}
+
+/// Returns the note with the given ID if it exists and isn't in the trash. Notes in the trash
+/// can only be restored (or purged), so this is what anything that changes a note should use
+pub async fn get_live_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<Option<Note>> {
+    Ok(self::get_note(con, username, id)
+        .await?
+        .filter(|note| note.trashed.is_none()))
+}
+
+/// Returns the note with the given ID (even if it is in the trash), if it exists
+pub async fn get_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<Option<Note>> {
+    con.switch(crate::TABLE_NOTE_DATA).await?;
+    let note: Result<String, Error> = con.get(note_key(username, id)).await;
+    match note {
+        Ok(json) => Ok(Some(Note::from_json(json))),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Create or update the stored data for a note. This doesn't touch the user's list of notes
+/// or the search index
+pub async fn put_note(
+    con: &mut Connection,
+    username: &str,
+    note: &Note,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_NOTE_DATA).await?;
+    let json = serde_json::to_string(note).unwrap();
+    let _: u64 = con
+        .run_query(&query!("USET", note_key(username, &note.id), json))
+        .await?;
+    Ok(())
+}
+
+/// Store a new note, add it to the end of the user's list of notes (and its notebook, or the
+/// archive) and index it for search and tags
+pub async fn push_note(
+    con: &mut Connection,
+    username: &str,
+    note: &Note,
+) -> crate::JotsyResponseResult<()> {
+    self::put_note(con, username, note).await?;
+    self::sync_lists(con, username, &note.id, None, Some(note)).await?;
+    super::search::index_note(con, username, &note.id, &note.body).await?;
+    index::TAGS.add(con, username, &note.id, &note.tags).await
+}
+
+/// Store an edited note (which used to be `old`), and update the search and tag indexes. If
+/// the note was moved to another notebook, pinned, archived or unarchived, it goes to the end
+/// (the newest end) of the lists it is now in
+pub async fn update_note(
+    con: &mut Connection,
+    username: &str,
+    old: &Note,
+    note: &Note,
+) -> crate::JotsyResponseResult<()> {
+    self::put_note(con, username, note).await?;
+    self::sync_lists(con, username, &note.id, Some(old), Some(note)).await?;
+    super::search::reindex_note(con, username, &note.id, &old.body, &note.body).await?;
+    index::TAGS
+        .update(con, username, &note.id, &old.tags, &note.tags)
+        .await
+}
+
+/// Move a note (which isn't in the trash) to the trash. It leaves every other list and the
+/// search and tag indexes, but its data and history are kept until it is purged. Returns the
+/// trashed note
+pub async fn trash_note(
+    con: &mut Connection,
+    username: &str,
+    old: &Note,
+) -> crate::JotsyResponseResult<Note> {
+    let mut note = old.clone();
+    note.trashed = Some(Utc::now());
+    self::put_note(con, username, &note).await?;
+    self::sync_lists(con, username, &note.id, Some(old), Some(&note)).await?;
+    super::search::unindex_note(con, username, &note.id, &note.body).await?;
+    index::TAGS
+        .remove(con, username, &note.id, &note.tags)
+        .await?;
+    Ok(note)
+}
+
+/// Take a note out of the trash, putting it back at the end of the lists it was in (or in the
+/// default notebook, if its notebook has since been deleted). Returns the restored note
+pub async fn restore_note(
+    con: &mut Connection,
+    username: &str,
+    old: &Note,
+) -> crate::JotsyResponseResult<Note> {
+    let mut note = old.clone();
+    note.trashed = None;
+    if notebooks::get_notebook(con, username, &note.notebook)
+        .await?
+        .is_none()
+    {
+        note.notebook = default_notebook();
+    }
+    self::put_note(con, username, &note).await?;
+    self::sync_lists(con, username, &note.id, Some(old), Some(&note)).await?;
+    super::search::index_note(con, username, &note.id, &note.body).await?;
+    index::TAGS.add(con, username, &note.id, &note.tags).await?;
+    Ok(note)
+}
+
+/// Move every note of the given user that isn't already in the trash to the trash, leaving
+/// them with an empty list of notes, empty notebooks, an empty archive and empty search and
+/// tag indexes. Returns the number of notes that were trashed
+pub async fn trash_all_notes(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<usize> {
+    let ids = self::get_note_ids(con, username).await?;
+    let now = Utc::now();
+    for mut note in self::get_notes(con, username, &ids).await? {
+        note.trashed = Some(now);
+        self::put_note(con, username, &note).await?;
+    }
+    con.switch(crate::TABLE_TRASH).await?;
+    for id in &ids {
+        util::list_push(con, username, id).await?;
+    }
+    notebooks::empty_all(con, username).await?;
+    con.switch(crate::TABLE_ARCHIVE).await?;
+    con.del(username).await?;
+    index::SEARCH.clear(con, username).await?;
+    index::TAGS.clear(con, username).await?;
+    con.switch(crate::TABLE_NOTES).await?;
+    util::expect_okay(con.run_query(&query!("LMOD", username, "clear")).await?)?;
+    Ok(ids.len())
+}
+
+/// Remove a note from whatever lists it is in (see [`lists`]) and the search and tag indexes,
+/// and delete its data, history and share links for good.
+/// Returns false if no such note exists
+pub async fn remove_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<bool> {
+    match self::get_note(con, username, id).await? {
+        Some(note) => {
+            self::sync_lists(con, username, id, Some(&note), None).await?;
+            if note.trashed.is_none() {
+                super::search::unindex_note(con, username, id, &note.body).await?;
+                index::TAGS.remove(con, username, id, &note.tags).await?;
+            }
+        }
+        None => {
+            // we don't know which lists it's in, but it can only be in one of these two
+            for table in [crate::TABLE_NOTES, crate::TABLE_TRASH] {
+                con.switch(table).await?;
+                util::list_remove(con, username, |nid| nid == id).await?;
+            }
+        }
+    }
+    super::history::remove(con, username, id).await?;
+    super::shares::remove_for_note(con, username, id).await?;
+    con.switch(crate::TABLE_NOTE_DATA).await?;
+    let deleted = con.del(note_key(username, id)).await?;
+    Ok(deleted == 1)
+}
+
+/// Delete every note (and its history) for the given user for good, including the notes in
+/// their trash, leaving them with an empty list of notes, empty notebooks, an empty archive,
+/// an empty trash and empty search and tag indexes
+pub async fn remove_all_notes(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<()> {
+    let mut ids = self::get_note_ids(con, username).await?;
+    con.switch(crate::TABLE_TRASH).await?;
+    ids.extend(util::list_get(con, username).await?);
+    if !ids.is_empty() {
+        con.switch(crate::TABLE_NOTE_DATA).await?;
+        let keys: Vec<String> = ids.iter().map(|id| note_key(username, id)).collect();
+        con.del(keys).await?;
+    }
+    super::history::remove_all(con, username, &ids).await?;
+    notebooks::empty_all(con, username).await?;
+    con.switch(crate::TABLE_ARCHIVE).await?;
+    con.del(username).await?;
+    con.switch(crate::TABLE_TRASH).await?;
+    con.del(username).await?;
+    index::SEARCH.clear(con, username).await?;
+    index::TAGS.clear(con, username).await?;
     con.switch(crate::TABLE_NOTES).await?;
-    let query = query!("LGET", &uname);
-    let notes: Vec<String> = con.run_query(&query).await?;
-    let notes: Vec<Note> = notes.iter().rev().map(Note::new_from_json).collect();
-    resp(StatusCode::OK, App::render_new(uname, notes))
+    util::expect_okay(con.run_query(&query!("LMOD", username, "clear")).await?)
+}
+
+/// What the app page is showing
+pub enum View {
+    /// A notebook, which also has the composer
+    Notebook(Notebook),
+    /// The notes with a tag
+    Tag(String),
+    /// The archived notes
+    Archive,
+    /// The notes in the trash
+    Trash,
+}
+
+/// Returns the main app page for an authenticated user, with the first page of the notes in
+/// their default notebook
+pub async fn app(creds: &Credentials, uname: String, db: AsyncPool) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let notebook = notebooks::get_notebook(&mut con, &uname, notebooks::DEFAULT_NOTEBOOK)
+        .await?
+        .expect("no default notebook");
+    self::notebook_app(&mut con, creds, uname, notebook).await
+}
+
+/// Returns the app page for a notebook, with the first page of its notes
+pub async fn notebook_app(
+    con: &mut Connection,
+    creds: &Credentials,
+    uname: String,
+    notebook: Notebook,
+) -> crate::JotsyResponse {
+    let page = self::get_page(con, &uname, NoteList::Notebook(&notebook.id), None)
+        .await?
+        .expect("no cursor");
+    self::render_app(con, creds, uname, page, View::Notebook(notebook)).await
+}
+
+/// Returns the app page showing the given page of notes
+pub async fn render_app(
+    con: &mut Connection,
+    creds: &Credentials,
+    uname: String,
+    page: Page,
+    view: View,
+) -> crate::JotsyResponse {
+    let count = self::count_notes(con, &uname).await?;
+    let tags = super::tags::get_tags(con, &uname).await?;
+    let notebooks = notebooks::get_counts(con, &uname).await?;
+    let tz = super::settings::get_timezone(con, &uname).await?;
+    resp(
+        StatusCode::OK,
+        App::render_new(
+            uname,
+            count,
+            page,
+            tags,
+            notebooks,
+            view,
+            tz,
+            creds.csrf_token(),
+        ),
+    )
+}
+
+/// `GET` for `/archive`
+///
+/// This will:
+/// - Verify the session
+/// - Return the app, showing the first page of archived notes (most recently archived first)
+pub async fn archive_page(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let page = self::get_page(&mut con, &username, NoteList::Archive, None)
+        .await?
+        .expect("no cursor");
+    self::render_app(&mut con, &creds, username, page, View::Archive).await
+}
+
+#[derive(Deserialize)]
+/// The query string for a page of notes
+pub struct PageQuery {
+    before: Option<String>,
+    notebook: Option<String>,
+    /// Page through the archive instead of a notebook
+    #[serde(default)]
+    archived: bool,
+    /// Or the trash
+    #[serde(default)]
+    trashed: bool,
+    /// Or the notes with this tag
+    tag: Option<String>,
+}
+
+/// `GET` for `/notes`
+///
+/// This will:
+/// - Verify the session
+/// - Return the rendered page of notes in the notebook (or the default notebook, the archive,
+///   the trash or a tag) older than the note `before` (or the newest notes)
+pub async fn notes_page(
+    Query(query): Query<PageQuery>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let tag = query.tag.as_deref().and_then(super::tags::normalize);
+    let list = if query.trashed {
+        NoteList::Trash
+    } else if let Some(tag) = &tag {
+        NoteList::Tag(tag)
+    } else if query.archived {
+        NoteList::Archive
+    } else {
+        NoteList::Notebook(
+            query
+                .notebook
+                .as_deref()
+                .unwrap_or(notebooks::DEFAULT_NOTEBOOK),
+        )
+    };
+    let tz = super::settings::get_timezone(&mut con, &username).await?;
+    match self::get_page(&mut con, &username, list, query.before.as_deref()).await? {
+        Some(page) => resp(StatusCode::OK, NotesPage::render_new(page, tz)),
+        None => self::note_not_found(),
+    }
 }
 
 #[derive(Deserialize)]
 /// A note from the AJAX submission
 pub struct FormNote {
     note: String,
+    /// Older clients don't send a format: new notes are then markdown and edits keep the
+    /// existing format
+    format: Option<NoteFormat>,
+    /// Similarly, new notes go to the default notebook and edits don't move the note
+    notebook: Option<String>,
+}
+
+/// Check that the notebook a note is being put in (if any) exists
+async fn check_notebook(
+    con: &mut Connection,
+    username: &str,
+    notebook: Option<&str>,
+) -> crate::JotsyResponseResult<bool> {
+    match notebook {
+        Some(id) => Ok(notebooks::get_notebook(con, username, id).await?.is_some()),
+        None => Ok(true),
+    }
 }
 
 /// `POST` for `/create/note`
@@ -67,27 +741,214 @@ pub struct FormNote {
 /// - Create the note
 /// - Return a rendered note element
 pub async fn create_note(
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
     Form(note): Form<FormNote>,
 ) -> crate::JotsyResponse {
-    let time = Local::now().format("%B %d, %Y | %I:%M %p").to_string();
     let mut con = db.get().await?;
     // verify the user
-    let username = super::root::verify_user_or_error(&mut con, &mut cookies).await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    if !self::check_notebook(&mut con, &username, note.notebook.as_deref()).await? {
+        return notebooks::notebook_not_found();
+    }
     // now create the note
-    let note = Note::new(time, note.note);
-    con.switch(crate::TABLE_NOTES).await?;
-    let json = serde_json::to_string(&note).unwrap();
-    let query = query!("LMOD", &username, "PUSH", json);
-    match con.run_query(&query).await {
-        Ok(Element::RespCode(RespCode::Okay)) => {
-            resp(StatusCode::CREATED, SingleNote::render_new(note))
-        }
-        Ok(_) => NoticePage::re500(),
-        Err(e) => {
+    let mut new = Note::new_now(note.note, note.format.unwrap_or_default());
+    if let Some(notebook) = note.notebook {
+        new.notebook = notebook;
+    }
+    let note = new;
+    let tz = super::settings::get_timezone(&mut con, &username).await?;
+    match self::push_note(&mut con, &username, &note).await {
+        Ok(()) => resp(StatusCode::CREATED, SingleNote::render_new(note, tz)),
+        Err(ResponseError::DatabaseError(e)) => {
             log::error!("Error while creating note: {e}");
             NoticePage::re500()
         }
+        Err(e) => Err(e),
+    }
+}
+
+pub(super) fn note_not_found() -> crate::JotsyResponse {
+    resp(
+        StatusCode::NOT_FOUND,
+        NoticePage::render_new("That note doesn't exist", false),
+    )
+}
+
+/// `PUT` for `/notes/:id`
+///
+/// This will:
+/// - Verify the session
+/// - Replace the body (and format and notebook, if given) of the note
+/// - Record the new version in the note's history, if the body or format changed
+/// - Return the updated note element
+pub async fn edit_note(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<FormNote>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    if !self::check_notebook(&mut con, &username, form.notebook.as_deref()).await? {
+        return notebooks::notebook_not_found();
+    }
+    match self::get_live_note(&mut con, &username, &id).await? {
+        Some(old) => {
+            let mut note = old.clone();
+            note.edit(form.note, form.format);
+            if let Some(notebook) = form.notebook {
+                note.notebook = notebook;
+            }
+            self::update_note(&mut con, &username, &old, &note).await?;
+            super::history::record(&mut con, &username, &creds, &old, &note).await?;
+            let tz = super::settings::get_timezone(&mut con, &username).await?;
+            resp(StatusCode::OK, SingleNote::render_new(note, tz))
+        }
+        None => self::note_not_found(),
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+/// Something that can be done to a note from its card
+pub enum NoteAction {
+    Pin,
+    Unpin,
+    Archive,
+    Unarchive,
+    /// Take the note out of the trash
+    Restore,
+}
+
+/// `POST` for `/notes/:id/:action`
+///
+/// This will:
+/// - Verify the session
+/// - Pin, unpin, archive or unarchive the note (if it isn't in the trash), or restore it from
+///   the trash
+/// - Return the updated note element
+pub async fn note_action(
+    Path((id, action)): Path<(String, NoteAction)>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let old = match self::get_note(&mut con, &username, &id).await? {
+        Some(old) => old,
+        None => return self::note_not_found(),
+    };
+    let note = if old.trashed.is_some() {
+        match action {
+            NoteAction::Restore => self::restore_note(&mut con, &username, &old).await?,
+            // notes in the trash can only be restored
+            _ => return self::note_not_found(),
+        }
+    } else {
+        let mut note = old.clone();
+        match action {
+            NoteAction::Pin => note.set_pinned(true),
+            NoteAction::Unpin => note.set_pinned(false),
+            NoteAction::Archive => note.set_archived(true),
+            NoteAction::Unarchive => note.set_archived(false),
+            // and only notes in the trash can be restored
+            NoteAction::Restore => return self::note_not_found(),
+        }
+        self::update_note(&mut con, &username, &old, &note).await?;
+        note
+    };
+    let tz = super::settings::get_timezone(&mut con, &username).await?;
+    resp(StatusCode::OK, SingleNote::render_new(note, tz))
+}
+
+/// `DELETE` for `/notes/:id`
+///
+/// This will:
+/// - Verify the session
+/// - Move the note to the trash
+pub async fn delete_note(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    match self::get_live_note(&mut con, &username, &id).await? {
+        Some(note) => {
+            self::trash_note(&mut con, &username, &note).await?;
+            resp(StatusCode::NO_CONTENT, "")
+        }
+        None => self::note_not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lists, Note, NoteFormat, NoteList};
+    use chrono::{Local, NaiveDate, TimeZone, Utc};
+
+    fn pinned_note() -> Note {
+        let mut note = Note::new(Utc::now(), "keep me".to_owned(), NoteFormat::Markdown);
+        note.pinned = true;
+        note
+    }
+
+    #[test]
+    fn pinned_notes_are_shown_above_their_notebook() {
+        let note = pinned_note();
+        let notebook = NoteList::Notebook(&note.notebook);
+        assert!(lists("user", &note).contains(&notebook.location("user")));
+        assert!(!notebook.shows_in_pages(&note));
+    }
+
+    #[test]
+    fn trashed_pinned_notes_are_shown_in_the_trash() {
+        // what `trash_note` does to a note
+        let mut note = pinned_note();
+        note.trashed = Some(Utc::now());
+        assert_eq!(lists("user", &note), [NoteList::Trash.location("user")]);
+        assert!(NoteList::Trash.shows_in_pages(&note));
+    }
+
+    #[test]
+    fn archived_pinned_notes_are_shown_in_the_archive() {
+        let mut note = pinned_note();
+        note.archived = true;
+        assert!(lists("user", &note).contains(&NoteList::Archive.location("user")));
+        assert!(NoteList::Archive.shows_in_pages(&note));
+    }
+
+    #[test]
+    fn dates_are_stored_as_rfc3339() {
+        let created = Utc.with_ymd_and_hms(2022, 11, 2, 14, 5, 0).unwrap();
+        let mut note = Note::new(created, "hi".to_owned(), NoteFormat::Markdown);
+        let json = serde_json::to_value(&note).unwrap();
+        assert_eq!(json["created"], "2022-11-02T14:05:00Z");
+        assert!(json["updated"].is_null());
+        note.updated = Some(created);
+        let note: Note = serde_json::from_str(&serde_json::to_string(&note).unwrap()).unwrap();
+        assert_eq!(note.created, created);
+        assert_eq!(note.updated, Some(created));
+    }
+
+    #[test]
+    fn legacy_dates_are_read() {
+        let json = r#"{
+            "id": "old",
+            "date": "November 02, 2022 | 02:05 PM",
+            "body": "from before",
+            "updated": null
+        }"#;
+        let note: Note = serde_json::from_str(json).unwrap();
+        let local = NaiveDate::from_ymd_opt(2022, 11, 2)
+            .and_then(|date| date.and_hms_opt(14, 5, 0))
+            .and_then(|date| Local.from_local_datetime(&date).earliest())
+            .unwrap();
+        assert_eq!(note.created, local.with_timezone(&Utc));
+        assert_eq!(note.updated, None);
+        assert_eq!(note.format, NoteFormat::Markdown);
+        let bad = r#"{"id": "x", "created": "soon", "body": ""}"#;
+        assert!(serde_json::from_str::<Note>(bad).is_err());
     }
 }
@@ -23,6 +23,8 @@ const CSS_INDEX_LOGIN: &str = include_str!("../../static/css/login.css");
 const CSS_INDEX_APP: &str = include_str!("../../static/css/app.css");
 const JS_INDEX_LOGIN: &str = include_str!("../../static/js/login.js");
 const JS_INDEX_APP: &str = include_str!("../../static/js/app.js");
+const JS_PASSKEYS: &str = include_str!("../../static/js/passkeys.js");
+const JS_ACCOUNT: &str = include_str!("../../static/js/account.js");
 const IMG_FAVICON: &[u8] = include_bytes!("../../static/favicon.ico");
 
 async fn asset(source: &'static [u8], ty: &'static str) -> impl IntoResponse {
@@ -51,6 +53,14 @@ pub async fn index_app_js() -> impl IntoResponse {
     js(JS_INDEX_APP).await
 }
 
+pub async fn index_passkeys_js() -> impl IntoResponse {
+    js(JS_PASSKEYS).await
+}
+
+pub async fn index_account_js() -> impl IntoResponse {
+    js(JS_ACCOUNT).await
+}
+
 pub async fn favicon() -> impl IntoResponse {
     asset(IMG_FAVICON, "image/x-icon").await
 }
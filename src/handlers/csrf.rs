@@ -0,0 +1,325 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! CSRF protection. Every session has a CSRF token (derived from its session token, so we
+//! don't have to store it) which pages embed in their forms. Any request that changes
+//! something and comes with a session cookie has to send it back, either:
+//! - As the `csrf_token` field of a form
+//! - In the `X-CSRF-Token` header (for scripts, and multipart forms like imports which are sent
+//!   by a script)
+//!
+//! [`verify`] checks it before the handlers run. Another site can make a browser send the
+//! session cookie, but it can't read the token
+
+use super::COOKIE_TOKEN;
+use crate::{templates::NoticePage, util};
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+/// The header scripts send the token in
+const HEADER: &str = "x-csrf-token";
+/// The largest form we read to find the token (forms with notes in them can be quite large)
+const FORM_MAX_SIZE: usize = 2 * 1024 * 1024;
+/// Routes used to log in or sign up, which happen before there is a session to protect
+const EXEMPT: [&str; 5] = [
+    "/login",
+    "/login/2fa",
+    "/login/passkey",
+    "/login/passkey/options",
+    "/signup",
+];
+
+/// Returns the CSRF token for a session token
+pub fn token_for(session_token: &str) -> String {
+    util::sha2(format!("csrf:{session_token}"))
+}
+
+#[derive(Deserialize)]
+/// The token in a form, which can have any other fields
+struct TokenField {
+    csrf_token: Option<String>,
+}
+
+/// Returns the token sent with a request, if any. The request is returned too, since we might
+/// have had to read its body. Fails with the response to send if the form is too large to read
+async fn sent_token(req: Request<Body>) -> Result<(Request<Body>, Option<String>), Response> {
+    if let Some(token) = req
+        .headers()
+        .get(HEADER)
+        .and_then(|token| token.to_str().ok())
+    {
+        let token = token.to_owned();
+        return Ok((req, Some(token)));
+    }
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|ty| ty.to_str().ok())
+        .is_some_and(|ty| ty.starts_with(mime::APPLICATION_WWW_FORM_URLENCODED.as_ref()));
+    if !is_form {
+        return Ok((req, None));
+    }
+    let too_large = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|len| len > FORM_MAX_SIZE);
+    if too_large {
+        return Err(self::form_too_large());
+    }
+    // read the form (a chunk at a time, since the length might not be given) and put it back
+    // for the handler
+    let (parts, mut body) = req.into_parts();
+    let mut form = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => return Ok((Request::from_parts(parts, Body::empty()), None)),
+        };
+        if form.len() + chunk.len() > FORM_MAX_SIZE {
+            return Err(self::form_too_large());
+        }
+        form.extend_from_slice(&chunk);
+    }
+    let token = serde_urlencoded::from_bytes::<TokenField>(&form)
+        .ok()
+        .and_then(|form| form.csrf_token);
+    Ok((Request::from_parts(parts, Body::from(form)), token))
+}
+
+/// Returns the page for a form larger than [`FORM_MAX_SIZE`]
+fn form_too_large() -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Html(NoticePage::render_new(
+            "This form is too large to be sent",
+            false,
+        )),
+    )
+        .into_response()
+}
+
+/// Compare tokens in constant time, so that a token can't be guessed a character at a time
+fn tokens_match(sent: &str, expected: &str) -> bool {
+    sent.len() == expected.len()
+        && sent
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Middleware that rejects requests that change something (anything but `GET`, `HEAD` and
+/// `OPTIONS`) and have a session cookie, but not the session's CSRF token
+pub async fn verify(req: Request<Body>, next: Next<Body>) -> Response {
+    let is_safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if is_safe || EXEMPT.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    let expected = req
+        .extensions()
+        .get::<Cookies>()
+        .and_then(|cookies| cookies.get(COOKIE_TOKEN))
+        .map(|token| self::token_for(token.value()));
+    let expected = match expected {
+        Some(expected) => expected,
+        // no session, so there's nothing to forge a request for (the handlers will refuse it)
+        None => return next.run(req).await,
+    };
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let (req, sent) = match self::sent_token(req).await {
+        Ok(sent) => sent,
+        Err(resp) => return resp,
+    };
+    match sent {
+        Some(sent) if self::tokens_match(&sent, &expected) => next.run(req).await,
+        _ => {
+            log::warn!("Rejected a {method} to {path} without a valid CSRF token");
+            (
+                StatusCode::FORBIDDEN,
+                Html(NoticePage::render_new(
+                    "This request didn't come with a valid security token (it might be from \
+                    another site or an old page). Reload the page and try again",
+                    false,
+                )),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{token_for, tokens_match, verify, FORM_MAX_SIZE, HEADER};
+    use crate::handlers::COOKIE_TOKEN;
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header, Method, Request, StatusCode},
+        middleware,
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+
+    const SESSION: &str = "session-token";
+
+    /// Returns an app that echoes the forms it is sent, behind the middleware
+    fn app() -> Router {
+        async fn echo(body: String) -> String {
+            body
+        }
+        Router::new()
+            .route("/notes", post(echo).get(echo))
+            .route("/login", post(echo))
+            .layer(middleware::from_fn(verify))
+            .layer(CookieManagerLayer::new())
+    }
+
+    fn request(method: Method, path: &str, session: bool) -> axum::http::request::Builder {
+        let req = Request::builder().method(method).uri(path);
+        if session {
+            req.header(header::COOKIE, format!("{COOKIE_TOKEN}={SESSION}"))
+        } else {
+            req
+        }
+    }
+
+    fn form(body: impl Into<Body>) -> Request<Body> {
+        request(Method::POST, "/notes", true)
+            .header(
+                header::CONTENT_TYPE,
+                mime::APPLICATION_WWW_FORM_URLENCODED.as_ref(),
+            )
+            .body(body.into())
+            .unwrap()
+    }
+
+    /// Send a request through the app and return the status and body of the response
+    async fn send(req: Request<Body>) -> (StatusCode, String) {
+        let resp = app().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let mut body = resp.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (status, String::from_utf8(bytes).unwrap())
+    }
+
+    #[test]
+    fn tokens_are_compared_in_full() {
+        let token = token_for(SESSION);
+        assert!(tokens_match(&token, &token));
+        assert!(!tokens_match(&token[1..], &token));
+        assert!(!tokens_match(&format!("{token}0"), &token));
+        assert!(!tokens_match("", &token));
+        let mut wrong = token.clone().into_bytes();
+        wrong[10] ^= 1;
+        assert!(!tokens_match(std::str::from_utf8(&wrong).unwrap(), &token));
+    }
+
+    #[tokio::test]
+    async fn header_tokens_are_accepted() {
+        let req = request(Method::POST, "/notes", true)
+            .header(HEADER, token_for(SESSION))
+            .body(Body::from("{\"note\": \"hi\"}"))
+            .unwrap();
+        assert_eq!(
+            send(req).await,
+            (StatusCode::OK, "{\"note\": \"hi\"}".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn form_tokens_are_accepted_and_the_form_is_kept() {
+        let body = format!("name=work&csrf_token={}", token_for(SESSION));
+        assert_eq!(send(form(body.clone())).await, (StatusCode::OK, body));
+    }
+
+    #[tokio::test]
+    async fn wrong_tokens_are_rejected() {
+        let token = token_for(SESSION);
+        for sent in [
+            String::new(),
+            token_for("another-session"),
+            format!("{token}0"),
+            token[..token.len() - 1].to_owned(),
+        ] {
+            let req = request(Method::POST, "/notes", true)
+                .header(HEADER, &sent)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(send(req).await.0, StatusCode::FORBIDDEN, "{sent:?}");
+            let body = format!("csrf_token={sent}");
+            assert_eq!(send(form(body)).await.0, StatusCode::FORBIDDEN, "{sent:?}");
+        }
+        // no token at all
+        assert_eq!(send(form("name=work")).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn oversized_forms_are_rejected() {
+        let token = token_for(SESSION);
+        let body = format!("csrf_token={token}&body={}", "a".repeat(FORM_MAX_SIZE));
+        // whether or not the length is given up front
+        let mut req = form(body.clone());
+        req.headers_mut()
+            .insert(header::CONTENT_LENGTH, body.len().into());
+        assert_eq!(send(req).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+        let (mut sender, streamed) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in [body, "more".to_owned()] {
+                if sender.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert_eq!(send(form(streamed)).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn exempt_and_safe_requests_need_no_token() {
+        let req = request(Method::POST, "/login", true)
+            .body(Body::from("username=me"))
+            .unwrap();
+        assert_eq!(send(req).await, (StatusCode::OK, "username=me".to_owned()));
+        let req = request(Method::GET, "/notes", true)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(req).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_session_are_let_through() {
+        // there is nothing to forge, and the handlers refuse them anyway
+        let req = request(Method::POST, "/notes", false)
+            .header(
+                header::CONTENT_TYPE,
+                mime::APPLICATION_WWW_FORM_URLENCODED.as_ref(),
+            )
+            .body(Body::from("name=work"))
+            .unwrap();
+        assert_eq!(send(req).await, (StatusCode::OK, "name=work".to_owned()));
+    }
+}
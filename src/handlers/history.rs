@@ -0,0 +1,460 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Revision history. Every edit to the body (or format) of a note appends a [`Revision`] to an
+//! append-only list in `default:jotsyrevisions`, under the same `username/id` key as the note.
+//! Notes that were never edited have no revisions, so the first edit also records the
+//! original version
+
+use super::{
+    app::{self, rfc3339, Note, NoteFormat},
+    Credentials,
+};
+use crate::{
+    templates::{HistoryPage, NoticePage},
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form, Path},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use skytable::{actions::AsyncActions, aio::Connection, ddl::AsyncDdl, pool::AsyncPool};
+
+/// The number of unchanged lines shown around each change in a diff
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// What made a revision
+pub enum Editor {
+    /// The session with this hash (of its token)
+    Session(String),
+    /// An API token
+    Api,
+    /// Nothing: this is how the note was before its first recorded edit
+    Original,
+}
+
+#[derive(Serialize, Deserialize)]
+/// A version of a note. This is stored as JSON in Skytable and is ser/de-d as required
+pub struct Revision {
+    /// When the note was edited to this version
+    #[serde(with = "rfc3339")]
+    pub date: DateTime<Utc>,
+    pub body: String,
+    pub format: NoteFormat,
+    pub editor: Editor,
+    /// The user agent of the client that made the edit, if it sent one
+    pub user_agent: Option<String>,
+}
+
+impl Revision {
+    /// Returns the current version of a note, as made by the client with these credentials
+    fn new(note: &Note, creds: &Credentials) -> Self {
+        let editor = match creds.session_hash() {
+            Some(hash) if creds.bearer().is_none() => Editor::Session(hash),
+            _ => Editor::Api,
+        };
+        Self {
+            date: note.last_edited(),
+            body: note.body.clone(),
+            format: note.format,
+            editor,
+            user_agent: creds.user_agent().map(str::to_owned),
+        }
+    }
+    /// Returns the version of a note from before its history was recorded
+    fn original(note: &Note) -> Self {
+        Self {
+            date: note.last_edited(),
+            body: note.body.clone(),
+            format: note.format,
+            editor: Editor::Original,
+            user_agent: None,
+        }
+    }
+    pub fn is_plaintext(&self) -> bool {
+        self.format == NoteFormat::Plaintext
+    }
+}
+
+/// Record an edit to a note (which used to be `old`) made by the client with these
+/// credentials. Nothing is recorded if neither the body nor the format changed
+pub async fn record(
+    con: &mut Connection,
+    username: &str,
+    creds: &Credentials,
+    old: &Note,
+    note: &Note,
+) -> crate::JotsyResponseResult<()> {
+    if old.body == note.body && old.format == note.format {
+        return Ok(());
+    }
+    let key = app::note_key(username, &note.id);
+    con.switch(crate::TABLE_REVISIONS).await?;
+    if util::list_len(con, &key).await? == 0 {
+        let original = serde_json::to_string(&Revision::original(old)).unwrap();
+        util::list_push(con, &key, original).await?;
+    }
+    let revision = serde_json::to_string(&Revision::new(note, creds)).unwrap();
+    util::list_push(con, &key, revision).await
+}
+
+/// Returns the revisions of a note, oldest first
+pub async fn get(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<Vec<Revision>> {
+    con.switch(crate::TABLE_REVISIONS).await?;
+    let revisions = util::list_get(con, &app::note_key(username, id)).await?;
+    Ok(revisions
+        .iter()
+        .map(|json| serde_json::from_str(json).unwrap())
+        .collect())
+}
+
+/// Delete the history of a note
+pub async fn remove(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_REVISIONS).await?;
+    con.del(app::note_key(username, id)).await?;
+    Ok(())
+}
+
+/// Delete the history of all the notes with the given IDs
+pub async fn remove_all(
+    con: &mut Connection,
+    username: &str,
+    ids: &[String],
+) -> crate::JotsyResponseResult<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    con.switch(crate::TABLE_REVISIONS).await?;
+    let keys: Vec<String> = ids.iter().map(|id| app::note_key(username, id)).collect();
+    con.del(keys).await?;
+    Ok(())
+}
+
+/// A line in the diff between two versions of a note
+pub struct DiffLine {
+    /// `+` for an added line, `-` for a removed line, a space for an unchanged line and `…`
+    /// for unchanged lines that were left out
+    pub sign: char,
+    pub text: String,
+}
+
+impl DiffLine {
+    /// Returns the CSS class for this line
+    pub fn class(&self) -> &'static str {
+        match self.sign {
+            '+' => "diff-added",
+            '-' => "diff-removed",
+            '…' => "diff-gap",
+            _ => "diff-same",
+        }
+    }
+}
+
+/// Returns the changed lines between two bodies, with a few unchanged lines around each
+/// change
+fn diff(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let diff = TextDiff::from_slices(&old, &new);
+    let mut lines = Vec::new();
+    for (idx, group) in diff.grouped_ops(DIFF_CONTEXT).iter().enumerate() {
+        if idx != 0 {
+            lines.push(DiffLine {
+                sign: '…',
+                text: String::new(),
+            });
+        }
+        for change in group.iter().flat_map(|op| diff.iter_changes(op)) {
+            let sign = match change.tag() {
+                ChangeTag::Insert => '+',
+                ChangeTag::Delete => '-',
+                ChangeTag::Equal => ' ',
+            };
+            lines.push(DiffLine {
+                sign,
+                text: change.value().to_owned(),
+            });
+        }
+    }
+    lines
+}
+
+/// A revision as shown on the history page
+pub struct Version {
+    /// The position of this version in the history, starting at zero for the original
+    pub number: usize,
+    pub revision: Revision,
+    /// The changes from the version before this one. The original is all additions
+    pub diff: Vec<DiffLine>,
+    /// Did this version change the format of the note?
+    pub format_changed: bool,
+    /// Is this how the note is right now?
+    pub current: bool,
+    /// Was this version made by the session that is looking at the history?
+    pub this_session: bool,
+}
+
+impl Version {
+    /// Returns what made this version, if anything did
+    pub fn made_by(&self) -> Option<String> {
+        match &self.revision.editor {
+            Editor::Session(_) if self.this_session => Some("this session".to_owned()),
+            Editor::Session(_) => Some(match &self.revision.user_agent {
+                Some(ua) => format!("another session ({ua})"),
+                None => "another session".to_owned(),
+            }),
+            Editor::Api => Some("an API token".to_owned()),
+            Editor::Original => None,
+        }
+    }
+}
+
+/// Returns the versions of a note for the history page, newest first
+fn versions(note: &Note, revisions: Vec<Revision>, session: Option<&str>) -> Vec<Version> {
+    let mut versions: Vec<Version> = Vec::with_capacity(revisions.len());
+    let (mut body, mut format) = (String::new(), None);
+    for (number, revision) in revisions.into_iter().enumerate() {
+        versions.push(Version {
+            number,
+            diff: self::diff(&body, &revision.body),
+            format_changed: format.is_some_and(|format| format != revision.format),
+            current: false,
+            this_session: matches!(
+                (&revision.editor, session),
+                (Editor::Session(hash), Some(session)) if hash == session
+            ),
+            revision,
+        });
+        let revision = &versions[number].revision;
+        body.clone_from(&revision.body);
+        format = Some(revision.format);
+    }
+    if let Some(last) = versions.last_mut() {
+        last.current = last.revision.body == note.body && last.revision.format == note.format;
+    }
+    versions.reverse();
+    versions
+}
+
+/// Returns the note as it is after restoring a version of it, or `None` if that's already how
+/// it is
+fn restored(note: &Note, revision: Revision) -> Option<Note> {
+    if revision.body == note.body && revision.format == note.format {
+        return None;
+    }
+    let mut note = note.clone();
+    note.edit(revision.body, Some(revision.format));
+    Some(note)
+}
+
+/// `GET` for `/notes/:id/history`
+///
+/// This will:
+/// - Verify the session
+/// - Return every version of the note (newest first) with the changes each one made
+pub async fn history_page(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let note = match app::get_note(&mut con, &username, &id).await? {
+        Some(note) => note,
+        None => return app::note_not_found(),
+    };
+    let revisions = self::get(&mut con, &username, &id).await?;
+    let versions = self::versions(&note, revisions, creds.session_hash().as_deref());
+    let tz = super::settings::get_timezone(&mut con, &username).await?;
+    resp(
+        StatusCode::OK,
+        HistoryPage::render_new(username, note, versions, tz, creds.csrf_token()),
+    )
+}
+
+/// `POST` for `/notes/:id/history/:number/restore`
+///
+/// This will:
+/// - Verify the session
+/// - Set the body and format of the note to those of the given version, which is recorded as
+///   a new version (so the history is never rewritten)
+/// - Redirect to the history of the note
+pub async fn restore(
+    Path((id, number)): Path<(String, usize)>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(_): Form<util::Empty>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let old = match app::get_live_note(&mut con, &username, &id).await? {
+        Some(note) => note,
+        None => return app::note_not_found(),
+    };
+    let revision = match self::get(&mut con, &username, &id)
+        .await?
+        .into_iter()
+        .nth(number)
+    {
+        Some(revision) => revision,
+        None => {
+            return resp(
+                StatusCode::NOT_FOUND,
+                NoticePage::render_new("That version doesn't exist", false),
+            )
+        }
+    };
+    let target = format!("/notes/{id}/history");
+    let note = match self::restored(&old, revision) {
+        Some(note) => note,
+        None => {
+            return resp(
+                StatusCode::OK,
+                NoticePage::redirect_to("That's already the current version", target),
+            )
+        }
+    };
+    app::update_note(&mut con, &username, &old, &note).await?;
+    self::record(&mut con, &username, &creds, &old, &note).await?;
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to("Restored the note", target),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, restored, versions, Editor, Revision};
+    use crate::handlers::app::{Note, NoteFormat};
+    use chrono::{Duration, Utc};
+
+    fn signs(body_a: &str, body_b: &str) -> String {
+        diff(body_a, body_b).iter().map(|line| line.sign).collect()
+    }
+
+    fn revision(body: &str, format: NoteFormat, editor: Editor) -> Revision {
+        Revision {
+            date: Utc::now(),
+            body: body.to_owned(),
+            format,
+            editor,
+            user_agent: None,
+        }
+    }
+
+    /// A note that was written as "one", then edited to "two" by a session and to "three" by
+    /// an API token (which also made it plaintext)
+    fn history() -> (Note, Vec<Revision>) {
+        let mut note = Note::new(
+            Utc::now() - Duration::days(1),
+            "one".to_owned(),
+            NoteFormat::Markdown,
+        );
+        note.edit("three".to_owned(), Some(NoteFormat::Plaintext));
+        let revisions = vec![
+            revision("one", NoteFormat::Markdown, Editor::Original),
+            revision(
+                "two",
+                NoteFormat::Markdown,
+                Editor::Session("abc".to_owned()),
+            ),
+            revision("three", NoteFormat::Plaintext, Editor::Api),
+        ];
+        (note, revisions)
+    }
+
+    #[test]
+    fn diffs_show_changed_lines() {
+        let lines = diff("a\nb\nc", "a\nB\nc\nd");
+        let rendered: Vec<String> = lines
+            .iter()
+            .map(|line| format!("{}{}", line.sign, line.text))
+            .collect();
+        assert_eq!(rendered, [" a", "-b", "+B", " c", "+d"]);
+        assert_eq!(lines[1].class(), "diff-removed");
+        assert_eq!(lines[2].class(), "diff-added");
+        assert_eq!(lines[0].class(), "diff-same");
+        // the original version is all additions, and nothing changed is nothing to show
+        assert_eq!(signs("", "a\nb"), "++");
+        assert!(diff("same", "same").is_empty());
+    }
+
+    #[test]
+    fn diffs_leave_out_distant_unchanged_lines() {
+        let old: Vec<String> = (0..20).map(|n| n.to_string()).collect();
+        let mut new = old.clone();
+        new[1] = "one".to_owned();
+        new[18] = "eighteen".to_owned();
+        let lines = diff(&old.join("\n"), &new.join("\n"));
+        // three lines of context around each change, with a gap between them
+        assert_eq!(signs(&old.join("\n"), &new.join("\n")), " -+   …   -+ ");
+        let gap = lines.iter().find(|line| line.sign == '…').unwrap();
+        assert_eq!(gap.class(), "diff-gap");
+        assert!(gap.text.is_empty());
+    }
+
+    #[test]
+    fn versions_are_newest_first() {
+        let (note, revisions) = history();
+        let versions = versions(&note, revisions, Some("abc"));
+        let numbers: Vec<usize> = versions.iter().map(|v| v.number).collect();
+        assert_eq!(numbers, [2, 1, 0]);
+        assert!(versions[0].current && !versions[1].current);
+        assert!(versions[0].format_changed && !versions[1].format_changed);
+        assert_eq!(versions[0].made_by().as_deref(), Some("an API token"));
+        assert_eq!(versions[1].made_by().as_deref(), Some("this session"));
+        assert_eq!(versions[2].made_by(), None);
+        let diff: String = versions[1].diff.iter().map(|line| line.sign).collect();
+        assert_eq!(diff, "-+");
+        // the note was edited since its last recorded version
+        let (mut note, revisions) = history();
+        note.edit("four".to_owned(), None);
+        let versions = super::versions(&note, revisions, None);
+        assert!(versions.iter().all(|version| !version.current));
+        assert_eq!(versions[1].made_by().as_deref(), Some("another session"));
+    }
+
+    #[test]
+    fn restoring_a_version() {
+        let (note, mut revisions) = history();
+        let restored = restored(&note, revisions.remove(1)).unwrap();
+        assert_eq!(restored.id, note.id);
+        assert_eq!(restored.body, "two");
+        assert_eq!(restored.format, NoteFormat::Markdown);
+        assert_eq!(restored.created, note.created);
+        assert!(restored.updated >= note.updated);
+        // restoring the current version changes nothing
+        let (note, mut revisions) = history();
+        assert!(super::restored(&note, revisions.remove(2)).is_none());
+        // but restoring the same body in another format does
+        let same_body = revision("three", NoteFormat::Markdown, Editor::Api);
+        let restored = super::restored(&note, same_body).unwrap();
+        assert_eq!(restored.format, NoteFormat::Markdown);
+    }
+}
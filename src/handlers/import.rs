@@ -0,0 +1,775 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Importing notes. An upload is a file or a zip of files, and each file is read as:
+//! - markdown (`.md`, `.markdown` or `.txt`), with the front-matter from our markdown exports
+//!   if it has any
+//! - JSON (`.json`): one of our JSON exports, a Simplenote export or a Google Keep note (a
+//!   Google Takeout has one of these for every note)
+//!
+//! Imported notes get new IDs and are added to the end of the user's notes, in the order they
+//! are in the upload
+
+use super::{
+    app::{self, Note, NoteFormat},
+    notebooks, Credentials,
+};
+use crate::{
+    templates::NoticePage,
+    util::{self, resp},
+};
+use axum::{
+    extract::{ContentLengthLimit, Extension, Multipart},
+    http::StatusCode,
+};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use skytable::pool::AsyncPool;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+/// The largest upload we accept (32 MiB)
+const UPLOAD_MAX_SIZE: u64 = 32 * 1024 * 1024;
+/// The largest file we read out of a zip (16 MiB)
+const FILE_MAX_SIZE: u64 = 16 * 1024 * 1024;
+/// The most we read out of an upload, across all of its files (64 MiB)
+const IMPORT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// The most notes that can be imported at once
+const IMPORT_MAX_NOTES: usize = 10_000;
+/// The number of notes from each file shown in a dry run
+const PREVIEW_NOTES: usize = 3;
+/// The number of characters of a note shown in a dry run
+const PREVIEW_LEN: usize = 40;
+
+/// The kinds of files we can import
+enum FileKind {
+    Markdown,
+    Json,
+}
+
+impl FileKind {
+    fn from_name(name: &str) -> Option<Self> {
+        let extension = name.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "md" | "markdown" | "txt" => Some(Self::Markdown),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the notes in a markdown file. `date` is used if the file has no front-matter
+fn read_markdown(text: &str, date: Option<DateTime<Utc>>) -> Result<Vec<Note>, String> {
+    let text = text
+        .strip_prefix('\u{feff}')
+        .unwrap_or(text)
+        .replace("\r\n", "\n");
+    let (front, body) = match text.strip_prefix("---\n") {
+        Some(rest) if rest.starts_with("---\n") => ("", &rest[4..]),
+        Some(rest) => rest.split_once("\n---\n").unwrap_or(("", text.as_str())),
+        None => ("", text.as_str()),
+    };
+    let body = body.trim_matches('\n');
+    if body.trim().is_empty() {
+        return Err("the note is empty".to_owned());
+    }
+    let mut note = Note::new(
+        date.unwrap_or_else(Utc::now),
+        body.to_owned(),
+        NoteFormat::Markdown,
+    );
+    for line in front.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        // our exports write values as JSON, but accept bare strings from elsewhere too
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+        match (key, value) {
+            // exports from older versions call `created` `date`
+            ("created" | "date", Value::String(date)) => {
+                note.created =
+                    util::parse_date(&date).ok_or_else(|| format!("invalid date `{date}`"))?
+            }
+            ("updated", Value::String(date)) => {
+                note.updated =
+                    Some(util::parse_date(&date).ok_or_else(|| format!("invalid date `{date}`"))?)
+            }
+            ("format", format) => {
+                note.format = serde_json::from_value(format)
+                    .map_err(|_| "the format must be markdown or plaintext".to_owned())?
+            }
+            ("notebook", Value::String(notebook)) => note.notebook = notebook,
+            ("pinned", Value::Bool(pinned)) => note.set_pinned(pinned),
+            ("archived", Value::Bool(archived)) => note.set_archived(archived),
+            _ => {}
+        }
+    }
+    Ok(vec![note])
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+/// A Simplenote export
+struct SimplenoteExport {
+    active_notes: Vec<SimplenoteNote>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SimplenoteNote {
+    content: String,
+    creation_date: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    markdown: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+/// A note from Google Keep (in a Google Takeout)
+struct KeepNote {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    text_content: String,
+    list_content: Option<Vec<KeepListItem>>,
+    #[serde(default)]
+    labels: Vec<KeepLabel>,
+    #[serde(default)]
+    is_pinned: bool,
+    #[serde(default)]
+    is_archived: bool,
+    #[serde(default)]
+    is_trashed: bool,
+    created_timestamp_usec: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeepListItem {
+    text: String,
+    #[serde(default)]
+    is_checked: bool,
+}
+
+#[derive(Deserialize)]
+struct KeepLabel {
+    name: String,
+}
+
+/// Add tags to the end of a body (since a note's tags are the `#hashtags` in its body).
+/// Labels that can't be tags are left out
+fn append_tags(body: &mut String, labels: &[String]) {
+    let tags: Vec<String> = labels
+        .iter()
+        .filter_map(|label| {
+            super::tags::normalize(&label.split_whitespace().collect::<Vec<_>>().join("-"))
+        })
+        .map(|tag| format!("#{tag}"))
+        .collect();
+    if !tags.is_empty() {
+        body.push_str("\n\n");
+        body.push_str(&tags.join(" "));
+    }
+}
+
+impl SimplenoteNote {
+    fn into_note(self) -> Note {
+        let mut body = self.content;
+        self::append_tags(&mut body, &self.tags);
+        let format = if self.markdown {
+            NoteFormat::Markdown
+        } else {
+            NoteFormat::Plaintext
+        };
+        let mut note = Note::new(
+            self.creation_date
+                .as_deref()
+                .and_then(util::parse_date)
+                .unwrap_or_else(Utc::now),
+            body,
+            format,
+        );
+        note.set_pinned(self.pinned);
+        note
+    }
+}
+
+impl KeepNote {
+    fn into_note(self) -> Option<Note> {
+        if self.is_trashed {
+            return None;
+        }
+        let (mut body, format) = match self.list_content {
+            // checklists become markdown task lists
+            Some(items) => {
+                let mut body = String::new();
+                if !self.title.is_empty() {
+                    body.push_str(&format!("# {}\n\n", self.title));
+                }
+                for item in items {
+                    let mark = if item.is_checked { 'x' } else { ' ' };
+                    body.push_str(&format!("- [{mark}] {}\n", item.text));
+                }
+                (body.trim_end().to_owned(), NoteFormat::Markdown)
+            }
+            None if self.title.is_empty() => (self.text_content, NoteFormat::Plaintext),
+            None => (
+                format!("{}\n\n{}", self.title, self.text_content),
+                NoteFormat::Plaintext,
+            ),
+        };
+        if body.trim().is_empty() {
+            return None;
+        }
+        let labels: Vec<String> = self.labels.into_iter().map(|label| label.name).collect();
+        self::append_tags(&mut body, &labels);
+        let date = self
+            .created_timestamp_usec
+            .and_then(DateTime::from_timestamp_micros)
+            .unwrap_or_else(Utc::now);
+        let mut note = Note::new(date, body, format);
+        note.set_archived(self.is_archived);
+        note.set_pinned(self.is_pinned);
+        Some(note)
+    }
+}
+
+/// Returns the notes in a JSON file: one of our exports, a Simplenote export or a Google Keep
+/// note
+fn read_json(text: &str) -> Result<Vec<Note>, String> {
+    let value: Value = serde_json::from_str(text).map_err(|e| format!("invalid JSON: {e}"))?;
+    let invalid = |e: serde_json::Error| format!("invalid export: {e}");
+    if value.is_array() {
+        // our own export. The notes get new IDs, but keep everything else
+        let notes: Vec<Note> = serde_json::from_value(value).map_err(invalid)?;
+        Ok(notes
+            .into_iter()
+            .map(|old| {
+                let mut note = Note::new(old.created, old.body, old.format);
+                note.updated = old.updated;
+                note.notebook = old.notebook;
+                note.set_archived(old.archived);
+                note.set_pinned(old.pinned);
+                note
+            })
+            .collect())
+    } else if value.get("activeNotes").is_some() {
+        let export: SimplenoteExport = serde_json::from_value(value).map_err(invalid)?;
+        Ok(export
+            .active_notes
+            .into_iter()
+            .map(SimplenoteNote::into_note)
+            .collect())
+    } else if value.get("textContent").is_some() || value.get("listContent").is_some() {
+        let note: KeepNote = serde_json::from_value(value).map_err(invalid)?;
+        Ok(note.into_note().into_iter().collect())
+    } else {
+        Err("not a Jotsy, Simplenote or Google Keep export".to_owned())
+    }
+}
+
+/// Returns the notes in a file
+fn read_file(
+    kind: FileKind,
+    data: &[u8],
+    date: Option<DateTime<Utc>>,
+) -> Result<Vec<Note>, String> {
+    let text = std::str::from_utf8(data).map_err(|_| "the file isn't UTF-8 text".to_owned())?;
+    match kind {
+        FileKind::Markdown => self::read_markdown(text, date),
+        FileKind::Json => self::read_json(text),
+    }
+}
+
+/// The notes in a file, or why they couldn't be read
+struct FileReport {
+    name: String,
+    notes: Result<Vec<Note>, String>,
+}
+
+/// What is left of the limits on an import. Zips can hold far more than they weigh, so we
+/// count as we go and give up on the whole upload as soon as either runs out
+struct Budget {
+    bytes: u64,
+    notes: usize,
+}
+
+impl Budget {
+    fn new() -> Self {
+        Self {
+            bytes: IMPORT_MAX_SIZE,
+            notes: IMPORT_MAX_NOTES,
+        }
+    }
+    fn spend_bytes(&mut self, bytes: u64) -> Result<(), String> {
+        self.bytes = self.bytes.checked_sub(bytes).ok_or_else(|| {
+            format!(
+                "You can import at most {} MiB at once",
+                IMPORT_MAX_SIZE / 1024 / 1024
+            )
+        })?;
+        Ok(())
+    }
+    fn spend_notes(&mut self, notes: &Result<Vec<Note>, String>) -> Result<(), String> {
+        let count = notes.as_ref().map_or(0, Vec::len);
+        self.notes = self
+            .notes
+            .checked_sub(count)
+            .ok_or_else(|| format!("You can import at most {IMPORT_MAX_NOTES} notes at once"))?;
+        Ok(())
+    }
+}
+
+/// Read every markdown and JSON file in a zip. Anything else (like the HTML files and
+/// attachments in a Google Takeout) is skipped. Fails if the zip goes over the budget
+fn read_zip(name: &str, data: Vec<u8>, budget: &mut Budget) -> Result<Vec<FileReport>, String> {
+    let mut zip = match ZipArchive::new(Cursor::new(data)) {
+        Ok(zip) => zip,
+        Err(e) => {
+            return Ok(vec![FileReport {
+                name: name.to_owned(),
+                notes: Err(format!("invalid zip: {e}")),
+            }])
+        }
+    };
+    let mut reports = Vec::new();
+    for i in 0..zip.len() {
+        let mut file = match zip.by_index(i) {
+            Ok(file) => file,
+            Err(e) => {
+                reports.push(FileReport {
+                    name: name.to_owned(),
+                    notes: Err(format!("invalid zip: {e}")),
+                });
+                break;
+            }
+        };
+        let path = file.name().to_owned();
+        let kind = match FileKind::from_name(&path) {
+            Some(kind) if file.is_file() && !path.starts_with("__MACOSX/") => kind,
+            _ => continue,
+        };
+        let name = format!("{name}/{path}");
+        // don't trust the size in the zip; stop reading once the file (or the upload) is too
+        // large
+        let limit = FILE_MAX_SIZE.min(budget.bytes);
+        let mut data = Vec::new();
+        let read = (&mut file).take(limit + 1).read_to_end(&mut data);
+        budget.spend_bytes(data.len() as u64)?;
+        let notes = match read {
+            Ok(_) if data.len() as u64 > FILE_MAX_SIZE => Err("the file is too large".to_owned()),
+            Ok(_) => {
+                // zips without dates say 1980, which is as early as they go. Zips store the
+                // local time of whoever made them, and we can only assume that is ours
+                let modified = file.last_modified();
+                let date = NaiveDate::from_ymd_opt(
+                    modified.year().into(),
+                    modified.month().into(),
+                    modified.day().into(),
+                )
+                .and_then(|date| {
+                    date.and_hms_opt(
+                        modified.hour().into(),
+                        modified.minute().into(),
+                        modified.second().into(),
+                    )
+                })
+                .filter(|_| modified.year() > 1980)
+                .and_then(|date| Local.from_local_datetime(&date).earliest())
+                .map(|date| date.with_timezone(&Utc));
+                self::read_file(kind, &data, date)
+            }
+            Err(e) => Err(format!("couldn't read the file: {e}")),
+        };
+        budget.spend_notes(&notes)?;
+        reports.push(FileReport { name, notes });
+    }
+    Ok(reports)
+}
+
+/// Read an uploaded file. Fails if it goes over the budget
+fn read_upload(
+    name: String,
+    data: Vec<u8>,
+    budget: &mut Budget,
+) -> Result<Vec<FileReport>, String> {
+    if data.starts_with(b"PK\x03\x04") {
+        return self::read_zip(&name, data, budget);
+    }
+    budget.spend_bytes(data.len() as u64)?;
+    let notes = match FileKind::from_name(&name) {
+        Some(kind) => self::read_file(kind, &data, None),
+        None => Err("only zip, markdown and JSON files can be imported".to_owned()),
+    };
+    budget.spend_notes(&notes)?;
+    Ok(vec![FileReport { name, notes }])
+}
+
+/// Read all the uploaded files. Fails if they go over the budget
+fn read_uploads(uploads: Vec<(String, Vec<u8>)>) -> Result<Vec<FileReport>, String> {
+    let mut budget = Budget::new();
+    let mut reports = Vec::new();
+    for (name, data) in uploads {
+        reports.extend(self::read_upload(name, data, &mut budget)?);
+    }
+    Ok(reports)
+}
+
+/// Returns the start of a note's body, for previews
+fn preview(note: &Note) -> String {
+    let line = note.body.lines().find(|line| !line.trim().is_empty());
+    let line = line.unwrap_or_default().trim();
+    if line.chars().count() > PREVIEW_LEN {
+        let cut: String = line.chars().take(PREVIEW_LEN).collect();
+        format!("\"{cut}…\"")
+    } else {
+        format!("\"{line}\"")
+    }
+}
+
+fn bad_upload(message: impl ToString) -> crate::JotsyResponse {
+    resp(
+        StatusCode::BAD_REQUEST,
+        NoticePage::render_report(message, Vec::new(), "/account"),
+    )
+}
+
+/// `POST` for `/account/import`. This is a multipart form with the files to import (`file`) and
+/// optionally `dryrun`
+///
+/// This will:
+/// - Verify the session
+/// - Read the notes in every uploaded file (and every file in uploaded zips)
+/// - Unless this is a dry run, add the notes to the end of the user's notes. Notes in a
+///   notebook that doesn't exist (matched by ID or name) go to the default notebook
+/// - Return a report of what was (or, for a dry run, would be) imported from every file
+pub async fn import(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    ContentLengthLimit(mut form): ContentLengthLimit<Multipart, UPLOAD_MAX_SIZE>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let mut uploads = Vec::new();
+    let mut dry_run = false;
+    loop {
+        let field = match form.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return self::bad_upload(format!("Failed to read the upload: {e}")),
+        };
+        match field.name() {
+            Some("dryrun") => dry_run = true,
+            Some("file") => {
+                let name = field.file_name().unwrap_or("upload").to_owned();
+                match field.bytes().await {
+                    Ok(data) if data.is_empty() => {}
+                    Ok(data) => uploads.push((name, data.to_vec())),
+                    Err(e) => return self::bad_upload(format!("Failed to read the upload: {e}")),
+                }
+            }
+            _ => {}
+        }
+    }
+    if uploads.is_empty() {
+        return self::bad_upload("Choose a file to import");
+    }
+    // unzipping and parsing can take a while, so keep it off the async workers
+    let reports = match tokio::task::spawn_blocking(move || self::read_uploads(uploads)).await {
+        Ok(Ok(reports)) => reports,
+        Ok(Err(e)) => return self::bad_upload(e),
+        Err(e) => {
+            log::error!("Failed to read an import: {e}");
+            return NoticePage::re500();
+        }
+    };
+    let total: usize = reports
+        .iter()
+        .filter_map(|report| report.notes.as_ref().ok())
+        .map(Vec::len)
+        .sum();
+    let details = reports
+        .iter()
+        .map(|report| match &report.notes {
+            Ok(notes) if notes.is_empty() => format!("{}: no notes", report.name),
+            Ok(notes) if dry_run => {
+                let previews: Vec<String> = notes
+                    .iter()
+                    .take(PREVIEW_NOTES)
+                    .map(self::preview)
+                    .collect();
+                let more = if notes.len() > PREVIEW_NOTES {
+                    ", …"
+                } else {
+                    ""
+                };
+                format!(
+                    "{}: {} note(s): {}{more}",
+                    report.name,
+                    notes.len(),
+                    previews.join(", ")
+                )
+            }
+            Ok(notes) => format!("{}: {} note(s)", report.name, notes.len()),
+            Err(e) => format!("{}: failed: {e}", report.name),
+        })
+        .collect();
+    let failed = reports
+        .iter()
+        .filter(|report| report.notes.is_err())
+        .count();
+    let status = if total == 0 && failed != 0 {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::OK
+    };
+    if dry_run {
+        return resp(
+            status,
+            NoticePage::render_report(
+                format!(
+                    "Found {total} note(s) to import (this was a dry run, so nothing was imported)"
+                ),
+                details,
+                "/account",
+            ),
+        );
+    }
+    let notebooks = notebooks::get_notebooks(&mut con, &username).await?;
+    for report in reports {
+        for mut note in report.notes.unwrap_or_default() {
+            let notebook = notebooks
+                .iter()
+                .find(|nb| nb.id == note.notebook || nb.name == note.notebook);
+            note.notebook = match notebook {
+                Some(notebook) => notebook.id.clone(),
+                None => notebooks::DEFAULT_NOTEBOOK.to_owned(),
+            };
+            app::push_note(&mut con, &username, &note).await?;
+        }
+    }
+    log::info!("Imported {total} note(s) for `{username}`");
+    resp(
+        status,
+        NoticePage::render_report(format!("Imported {total} note(s)"), details, "/account"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_json, read_markdown, read_uploads, read_zip, Budget, FileReport};
+    use crate::handlers::app::{Note, NoteFormat};
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    const MARKDOWN_EXPORT: &str = "\u{feff}---\r\n\
+        id: \"abc\"\r\n\
+        created: 2022-11-02T14:05:00Z\r\n\
+        updated: 2022-11-03T09:00:00Z\r\n\
+        format: \"plaintext\"\r\n\
+        notebook: \"Work\"\r\n\
+        tags: [\"todo\"]\r\n\
+        pinned: true\r\n\
+        archived: false\r\n\
+        ---\r\n\r\nShip the #todo list\r\n";
+
+    const JOTSY_EXPORT: &str = r#"[
+        {
+            "id": "old",
+            "created": "2022-11-02T14:05:00Z",
+            "body": "Archived",
+            "format": "markdown",
+            "notebook": "nb1",
+            "archived": true
+        },
+        {
+            "id": "older",
+            "date": "November 01, 2022 | 08:30 AM",
+            "body": "From before notebooks",
+            "format": "plaintext"
+        }
+    ]"#;
+
+    const SIMPLENOTE_EXPORT: &str = r#"{
+        "activeNotes": [
+            {
+                "id": "1",
+                "content": "Groceries",
+                "creationDate": "2022-10-01T12:00:00.000Z",
+                "tags": ["home", "shopping list", "!!"],
+                "pinned": true,
+                "markdown": true
+            },
+            { "id": "2", "content": "Plain" }
+        ],
+        "trashedNotes": [{ "id": "3", "content": "Gone" }]
+    }"#;
+
+    const KEEP_CHECKLIST: &str = r#"{
+        "color": "DEFAULT",
+        "isTrashed": false,
+        "isPinned": true,
+        "isArchived": true,
+        "title": "Packing",
+        "listContent": [
+            { "text": "Passport", "isChecked": true },
+            { "text": "Charger", "isChecked": false }
+        ],
+        "labels": [{ "name": "Travel" }],
+        "createdTimestampUsec": 1667397900000000
+    }"#;
+
+    fn date(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, body) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(body.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn notes(reports: &[FileReport]) -> Vec<&Note> {
+        reports
+            .iter()
+            .flat_map(|report| report.notes.as_ref().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn markdown_export() {
+        let notes = read_markdown(MARKDOWN_EXPORT, None).unwrap();
+        assert_eq!(notes.len(), 1);
+        let note = &notes[0];
+        assert_ne!(note.id, "abc");
+        assert_eq!(note.body, "Ship the #todo list");
+        assert_eq!(note.created, date(2022, 11, 2, 14, 5));
+        assert_eq!(note.updated, Some(date(2022, 11, 3, 9, 0)));
+        assert_eq!(note.format, NoteFormat::Plaintext);
+        assert_eq!(note.notebook, "Work");
+        assert!(note.pinned && !note.archived);
+        assert!(note.tags.contains("todo"));
+    }
+
+    #[test]
+    fn markdown_without_front_matter() {
+        let modified = date(2021, 5, 6, 7, 8);
+        let note = &read_markdown("# Title\n\ntext\n", Some(modified)).unwrap()[0];
+        assert_eq!(note.body, "# Title\n\ntext");
+        assert_eq!(note.created, modified);
+        assert_eq!(note.format, NoteFormat::Markdown);
+        // a horizontal rule isn't front-matter unless the file starts with it
+        let note = &read_markdown("above\n---\nbelow", None).unwrap()[0];
+        assert_eq!(note.body, "above\n---\nbelow");
+        assert!(read_markdown("---\nformat: \"markdown\"\n---\n\n", None).is_err());
+        assert!(read_markdown("---\ncreated: yesterday\n---\nbody", None).is_err());
+    }
+
+    #[test]
+    fn jotsy_export() {
+        let notes = read_json(JOTSY_EXPORT).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_ne!(notes[0].id, "old");
+        assert_eq!(notes[0].created, date(2022, 11, 2, 14, 5));
+        assert_eq!(notes[0].notebook, "nb1");
+        assert!(notes[0].archived);
+        assert_eq!(notes[1].body, "From before notebooks");
+        assert_eq!(notes[1].format, NoteFormat::Plaintext);
+        assert!(notes[1].updated.is_none());
+    }
+
+    #[test]
+    fn simplenote_export() {
+        let notes = read_json(SIMPLENOTE_EXPORT).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].body, "Groceries\n\n#home #shopping-list");
+        assert_eq!(notes[0].created, date(2022, 10, 1, 12, 0));
+        assert_eq!(notes[0].format, NoteFormat::Markdown);
+        assert!(notes[0].pinned);
+        assert!(notes[0].tags.contains("shopping-list"));
+        assert_eq!(notes[1].body, "Plain");
+        assert_eq!(notes[1].format, NoteFormat::Plaintext);
+        assert!(!notes[1].pinned);
+    }
+
+    #[test]
+    fn keep_notes() {
+        let note = &read_json(KEEP_CHECKLIST).unwrap()[0];
+        assert_eq!(
+            note.body,
+            "# Packing\n\n- [x] Passport\n- [ ] Charger\n\n#travel"
+        );
+        assert_eq!(note.format, NoteFormat::Markdown);
+        assert_eq!(note.created, date(2022, 11, 2, 14, 5));
+        // archived notes can't be pinned
+        assert!(note.archived && !note.pinned);
+        let note = &read_json(r#"{"title": "Idea", "textContent": "Write it down"}"#).unwrap()[0];
+        assert_eq!(note.body, "Idea\n\nWrite it down");
+        assert_eq!(note.format, NoteFormat::Plaintext);
+        let trashed = r#"{"textContent": "Gone", "isTrashed": true}"#;
+        assert!(read_json(trashed).unwrap().is_empty());
+        assert!(read_json(r#"{"something": "else"}"#).is_err());
+    }
+
+    #[test]
+    fn zips_skip_other_files() {
+        let data = zip(&[
+            ("Takeout/Keep/Idea.json", r#"{"textContent": "Idea"}"#),
+            ("Takeout/Keep/Idea.html", "<p>Idea</p>"),
+            ("__MACOSX/._note.md", "junk"),
+            ("note.md", "Hello"),
+        ]);
+        let reports = read_zip("takeout.zip", data, &mut Budget::new()).unwrap();
+        let names: Vec<&str> = reports.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["takeout.zip/Takeout/Keep/Idea.json", "takeout.zip/note.md"]
+        );
+        let bodies: Vec<&str> = notes(&reports).iter().map(|n| n.body.as_str()).collect();
+        assert_eq!(bodies, ["Idea", "Hello"]);
+    }
+
+    #[test]
+    fn zips_stop_at_the_budget() {
+        let data = zip(&[("a.md", "one"), ("b.md", "two"), ("c.md", "three")]);
+        let mut budget = Budget {
+            bytes: 1024,
+            notes: 2,
+        };
+        assert!(read_zip("notes.zip", data.clone(), &mut budget).is_err());
+        let mut budget = Budget {
+            bytes: 6,
+            notes: 10,
+        };
+        assert!(read_zip("notes.zip", data, &mut budget).is_err());
+        // the budget is shared by every file in the upload
+        let uploads = vec![
+            ("a.md".to_owned(), b"one".to_vec()),
+            ("b.md".to_owned(), b"two".to_vec()),
+        ];
+        assert_eq!(notes(&read_uploads(uploads).unwrap()).len(), 2);
+    }
+}
@@ -0,0 +1,165 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Per-user inverted indexes of notes, used for search and tags. An index is a pair of tables:
+//! - the postings table maps `username/key -> [note ID]`
+//! - the keys table maps `username -> [key]`, so that we can list (or drop) all of a user's keys
+
+use crate::util;
+use skytable::{actions::AsyncActions, aio::Connection, ddl::AsyncDdl};
+use std::collections::{BTreeSet, HashSet};
+
+/// An index from some keys (like search terms or tags) to the notes that have them
+pub struct Index {
+    postings: &'static str,
+    keys: &'static str,
+}
+
+/// The full-text search index
+pub const SEARCH: Index = Index {
+    postings: crate::TABLE_SEARCH,
+    keys: crate::TABLE_SEARCH_TERMS,
+};
+
+/// The tag index
+pub const TAGS: Index = Index {
+    postings: crate::TABLE_TAGS,
+    keys: crate::TABLE_USER_TAGS,
+};
+
+/// Returns the key for a postings list
+fn postings_key(username: &str, key: &str) -> String {
+    format!("{username}/{key}")
+}
+
+impl Index {
+    /// Add a note under the given keys
+    pub async fn add(
+        &self,
+        con: &mut Connection,
+        username: &str,
+        id: &str,
+        keys: &BTreeSet<String>,
+    ) -> crate::JotsyResponseResult<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        con.switch(self.keys).await?;
+        let known: HashSet<String> = util::list_get(con, username).await?.into_iter().collect();
+        for key in keys {
+            if !known.contains(key) {
+                util::list_push(con, username, key).await?;
+            }
+        }
+        con.switch(self.postings).await?;
+        for key in keys {
+            util::list_push(con, &postings_key(username, key), id).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove a note from under the given keys. Keys that no longer have any notes are dropped
+    pub async fn remove(
+        &self,
+        con: &mut Connection,
+        username: &str,
+        id: &str,
+        keys: &BTreeSet<String>,
+    ) -> crate::JotsyResponseResult<()> {
+        for key in keys {
+            let pkey = postings_key(username, key);
+            con.switch(self.postings).await?;
+            util::list_remove(con, &pkey, |nid| nid == id).await?;
+            if util::list_get(con, &pkey).await?.is_empty() {
+                con.del(&pkey).await?;
+                con.switch(self.keys).await?;
+                util::list_remove(con, username, |k| k == key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move a note from the `old` keys to the `new` keys
+    pub async fn update(
+        &self,
+        con: &mut Connection,
+        username: &str,
+        id: &str,
+        old: &BTreeSet<String>,
+        new: &BTreeSet<String>,
+    ) -> crate::JotsyResponseResult<()> {
+        let removed = old.difference(new).cloned().collect();
+        let added = new.difference(old).cloned().collect();
+        self.remove(con, username, id, &removed).await?;
+        self.add(con, username, id, &added).await
+    }
+
+    /// Returns all of a user's keys
+    pub async fn keys(
+        &self,
+        con: &mut Connection,
+        username: &str,
+    ) -> crate::JotsyResponseResult<Vec<String>> {
+        con.switch(self.keys).await?;
+        util::list_get(con, username).await
+    }
+
+    /// Returns the table and key of the list of notes under a key, which is in the order the
+    /// notes were added to it
+    pub fn location(&self, username: &str, key: &str) -> (&'static str, String) {
+        (self.postings, postings_key(username, key))
+    }
+
+    /// Returns the IDs of the notes under each of the given keys, fetched all at once
+    pub async fn get_many(
+        &self,
+        con: &mut Connection,
+        username: &str,
+        keys: &[&String],
+    ) -> crate::JotsyResponseResult<Vec<Vec<String>>> {
+        con.switch(self.postings).await?;
+        let pkeys: Vec<String> = keys.iter().map(|key| postings_key(username, key)).collect();
+        util::list_mget(con, &pkeys).await
+    }
+
+    /// Returns the number of notes under a key
+    pub async fn count(
+        &self,
+        con: &mut Connection,
+        username: &str,
+        key: &str,
+    ) -> crate::JotsyResponseResult<u64> {
+        con.switch(self.postings).await?;
+        util::list_len(con, &postings_key(username, key)).await
+    }
+
+    /// Drop all of a user's keys
+    pub async fn clear(
+        &self,
+        con: &mut Connection,
+        username: &str,
+    ) -> crate::JotsyResponseResult<()> {
+        let keys = self.keys(con, username).await?;
+        if !keys.is_empty() {
+            con.switch(self.postings).await?;
+            let pkeys: Vec<String> = keys.iter().map(|key| postings_key(username, key)).collect();
+            con.del(pkeys).await?;
+        }
+        con.switch(self.keys).await?;
+        con.del(username).await?;
+        Ok(())
+    }
+}
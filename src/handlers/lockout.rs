@@ -0,0 +1,403 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Brute-force protection for logins. We count failed logins (wrong passwords and wrong
+//! two-factor codes) per account and per IP address in `default:jotsyloginattempts`, which
+//! maps `user:<username>` or `ip:<address>` to `Attempts`. After a few free attempts, every
+//! failure makes the client wait twice as long before trying again, and too many failures
+//! lock the account (or address) out for a while. Passkey logins that are started are counted
+//! the same way under `passkey:<address>`, since each one is stored until it expires
+
+use crate::util;
+use serde::{Deserialize, Serialize};
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+use std::{net::IpAddr, time::Duration};
+
+/// Failures are forgotten once there hasn't been one for this long (in seconds)
+const FAILURE_WINDOW: i64 = 60 * 60;
+/// The longest a client has to wait between attempts before being locked out (in seconds)
+const MAX_BACKOFF: i64 = 5 * 60;
+/// How long a lockout lasts (in seconds)
+const LOCKOUT: i64 = 15 * 60;
+/// How often the reaper looks for failures that can be forgotten
+const REAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// When to slow down and lock out a client
+struct Policy {
+    /// The number of failures allowed before every failure needs a wait
+    free: u32,
+    /// The number of failures after which the client is locked out
+    lockout_after: u32,
+}
+
+/// Guessing one account's password
+const ACCOUNT_POLICY: Policy = Policy {
+    free: 3,
+    lockout_after: 10,
+};
+/// Guessing from one address. This is more lenient since many users can share an address
+const IP_POLICY: Policy = Policy {
+    free: 10,
+    lockout_after: 50,
+};
+/// Starting passkey logins from one address. These don't fail, so they are only counted to
+/// stop one client from filling the database with them
+const PASSKEY_LOGIN_POLICY: Policy = Policy {
+    free: 20,
+    lockout_after: 100,
+};
+
+#[derive(Serialize, Deserialize, Default)]
+/// The failed logins for an account or address. This is stored as JSON in Skytable and is
+/// ser/de-d as required
+struct Attempts {
+    /// The number of failures in a row
+    failures: u32,
+    /// When the last failure was (a UNIX timestamp)
+    last_failure: i64,
+    /// No login is tried before this (a UNIX timestamp)
+    blocked_until: i64,
+    /// Is this a lockout, rather than a backoff?
+    locked: bool,
+}
+
+impl Attempts {
+    /// Returns true if these failures no longer block anything and can be forgotten. A
+    /// lockout that has ended forgets its failures so that the client starts over
+    fn is_stale(&self, now: i64) -> bool {
+        self.blocked_until <= now && (self.locked || now - self.last_failure > FAILURE_WINDOW)
+    }
+    /// Record a failure at `now`, making the client wait (or locking it out) if it has failed
+    /// too often. Returns true if this failure locked the client out
+    fn fail(&mut self, policy: &Policy, now: i64) -> bool {
+        if self.is_stale(now) {
+            *self = Self::default();
+        }
+        self.failures += 1;
+        self.last_failure = now;
+        if self.failures >= policy.lockout_after {
+            self.blocked_until = now + LOCKOUT;
+            self.locked = true;
+            return true;
+        }
+        if self.failures > policy.free {
+            let exp = self.failures - policy.free - 1;
+            let backoff = 2i64
+                .checked_pow(exp)
+                .map_or(MAX_BACKOFF, |b| b.min(MAX_BACKOFF));
+            self.blocked_until = now + backoff;
+        }
+        false
+    }
+}
+
+/// Who failed to log in
+enum Target<'a> {
+    Account(&'a str),
+    Ip(IpAddr),
+    /// Not a failure, but a passkey login started from this address
+    PasskeyLogin(IpAddr),
+}
+
+impl Target<'_> {
+    fn key(&self) -> String {
+        match self {
+            Self::Account(username) => format!("user:{username}"),
+            Self::Ip(ip) => format!("ip:{ip}"),
+            Self::PasskeyLogin(ip) => format!("passkey:{ip}"),
+        }
+    }
+    fn policy(&self) -> &'static Policy {
+        match self {
+            Self::Account(_) => &ACCOUNT_POLICY,
+            Self::Ip(_) => &IP_POLICY,
+            Self::PasskeyLogin(_) => &PASSKEY_LOGIN_POLICY,
+        }
+    }
+    fn describe(&self) -> String {
+        match self {
+            Self::Account(username) => format!("account `{username}`"),
+            Self::Ip(ip) => format!("IP {ip}"),
+            Self::PasskeyLogin(ip) => format!("passkey logins from IP {ip}"),
+        }
+    }
+}
+
+fn targets(username: &str, ip: Option<IpAddr>) -> impl Iterator<Item = Target<'_>> {
+    std::iter::once(Target::Account(username)).chain(ip.map(Target::Ip))
+}
+
+async fn get(con: &mut Connection, key: &str) -> crate::JotsyResponseResult<Option<Attempts>> {
+    con.switch(crate::TABLE_LOGIN_ATTEMPTS).await?;
+    let attempts: Result<String, Error> = con.get(key).await;
+    match attempts {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(attempts) => Ok(Some(attempts)),
+            Err(e) => {
+                // the next failure overwrites it
+                log::warn!("Ignored the unreadable failed logins `{key}`: {e}");
+                Ok(None)
+            }
+        },
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn set(
+    con: &mut Connection,
+    key: &str,
+    attempts: &Attempts,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_LOGIN_ATTEMPTS).await?;
+    let json = serde_json::to_string(attempts).unwrap();
+    let _: u64 = con.run_query(&query!("USET", key, json)).await?;
+    Ok(())
+}
+
+/// Returns how many seconds the client has to wait before trying to log in to this account,
+/// if it has to wait at all
+pub async fn check(
+    con: &mut Connection,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> crate::JotsyResponseResult<Option<i64>> {
+    let now = util::now();
+    let mut wait = None;
+    for target in self::targets(username, ip) {
+        let key = target.key();
+        let attempts = match self::get(con, &key).await? {
+            Some(attempts) => attempts,
+            None => continue,
+        };
+        if attempts.blocked_until > now {
+            wait = wait.max(Some(attempts.blocked_until - now));
+        } else if attempts.is_stale(now) {
+            if attempts.locked {
+                log::info!("Unlocked {} after its lockout ended", target.describe());
+            }
+            con.del(&key).await?;
+        }
+    }
+    Ok(wait)
+}
+
+/// Record a failed login to this account, making the client wait (or locking it out) if it
+/// has failed too often
+pub async fn record_failure(
+    con: &mut Connection,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> crate::JotsyResponseResult<()> {
+    let now = util::now();
+    for target in self::targets(username, ip) {
+        let key = target.key();
+        let mut attempts = self::get(con, &key).await?.unwrap_or_default();
+        if attempts.fail(target.policy(), now) {
+            log::warn!(
+                "Locked out {} for {} minutes after {} failed logins",
+                target.describe(),
+                LOCKOUT / 60,
+                attempts.failures
+            );
+        }
+        self::set(con, &key, &attempts).await?;
+    }
+    Ok(())
+}
+
+/// Count a passkey login started from this address. Returns how many seconds the client has to
+/// wait before starting another, if it has to wait at all (in which case this one isn't
+/// started or counted)
+pub async fn start_passkey_login(
+    con: &mut Connection,
+    ip: Option<IpAddr>,
+) -> crate::JotsyResponseResult<Option<i64>> {
+    let target = match ip {
+        Some(ip) => Target::PasskeyLogin(ip),
+        None => return Ok(None),
+    };
+    let key = target.key();
+    let now = util::now();
+    let mut attempts = self::get(con, &key).await?.unwrap_or_default();
+    if attempts.blocked_until > now {
+        return Ok(Some(attempts.blocked_until - now));
+    }
+    if attempts.fail(target.policy(), now) {
+        log::warn!(
+            "Locked out {} for {} minutes after {} were started",
+            target.describe(),
+            LOCKOUT / 60,
+            attempts.failures
+        );
+    }
+    self::set(con, &key, &attempts).await?;
+    Ok(None)
+}
+
+/// Forget the failed logins to this account, after the user logged in or an admin reset their
+/// password. Failures from their address are kept, so that logging in to one account doesn't
+/// help guess another
+pub async fn forget(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_LOGIN_ATTEMPTS).await?;
+    con.del(Target::Account(username).key()).await?;
+    Ok(())
+}
+
+/// Returns a wait for humans, like "5 minutes"
+pub fn describe_wait(seconds: i64) -> String {
+    if seconds < 60 {
+        format!("{seconds} second(s)")
+    } else {
+        // round up, so that we never ask for too short a wait
+        format!("{} minute(s)", (seconds + 59) / 60)
+    }
+}
+
+/// Forget all failures that no longer block anything. Returns the number of accounts and
+/// addresses forgotten
+async fn reap(pool: &AsyncPool) -> crate::JotsyResponseResult<usize> {
+    let mut con = pool.get().await?;
+    con.switch(crate::TABLE_LOGIN_ATTEMPTS).await?;
+    let keys: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    let attempts = util::mget(&mut con, keys.clone()).await?;
+    let now = util::now();
+    let mut stale = Vec::new();
+    for (key, attempts) in keys.into_iter().zip(attempts) {
+        let attempts: Attempts = match attempts.map(|json| serde_json::from_str(&json)) {
+            Some(Ok(attempts)) => attempts,
+            Some(Err(e)) => {
+                log::warn!("Skipped the unreadable failed logins `{key}`: {e}");
+                continue;
+            }
+            None => continue,
+        };
+        if attempts.is_stale(now) {
+            if attempts.locked {
+                log::info!("Unlocked `{key}` after its lockout ended");
+            }
+            stale.push(key);
+        }
+    }
+    if !stale.is_empty() {
+        con.del(&stale).await?;
+    }
+    Ok(stale.len())
+}
+
+/// Periodically forget failed logins that no longer block anything. This is spawned as a
+/// background task and never returns
+pub async fn reaper(pool: AsyncPool) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match self::reap(&pool).await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Forgot the failed logins of {purged} account(s)/address(es)"),
+            Err(e) => log::error!("Failed to purge old failed logins: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Attempts, Policy, ACCOUNT_POLICY, FAILURE_WINDOW, LOCKOUT, MAX_BACKOFF,
+        PASSKEY_LOGIN_POLICY,
+    };
+
+    const NOW: i64 = 1_700_000_000;
+
+    /// Returns the failures after failing `times` times in a row at `NOW`
+    fn failed(policy: &Policy, times: u32) -> Attempts {
+        let mut attempts = Attempts::default();
+        for _ in 0..times {
+            attempts.fail(policy, NOW);
+        }
+        attempts
+    }
+
+    #[test]
+    fn the_first_failures_are_free() {
+        let attempts = failed(&ACCOUNT_POLICY, 3);
+        assert_eq!(attempts.failures, 3);
+        assert!(attempts.blocked_until <= NOW && !attempts.locked);
+        // the fourth one isn't
+        let attempts = failed(&ACCOUNT_POLICY, 4);
+        assert_eq!(attempts.blocked_until, NOW + 1);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let waits: Vec<i64> = (4..10)
+            .map(|times| failed(&ACCOUNT_POLICY, times).blocked_until - NOW)
+            .collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32]);
+        assert!(!failed(&ACCOUNT_POLICY, 9).locked);
+    }
+
+    #[test]
+    fn too_many_failures_lock_out() {
+        let mut attempts = failed(&ACCOUNT_POLICY, 9);
+        assert!(attempts.fail(&ACCOUNT_POLICY, NOW));
+        assert!(attempts.locked);
+        assert_eq!(attempts.failures, 10);
+        assert_eq!(attempts.blocked_until, NOW + LOCKOUT);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = Policy {
+            free: 0,
+            lockout_after: 1000,
+        };
+        assert_eq!(failed(&policy, 9).blocked_until, NOW + 256);
+        assert_eq!(failed(&policy, 10).blocked_until, NOW + MAX_BACKOFF);
+        // far past what fits in an i64
+        assert_eq!(failed(&policy, 100).blocked_until, NOW + MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failures_go_stale() {
+        let attempts = failed(&ACCOUNT_POLICY, 5);
+        assert!(!attempts.is_stale(NOW));
+        assert!(!attempts.is_stale(NOW + FAILURE_WINDOW));
+        assert!(attempts.is_stale(NOW + FAILURE_WINDOW + 1));
+        // a lockout is forgotten as soon as it ends
+        let mut attempts = failed(&ACCOUNT_POLICY, 10);
+        assert!(!attempts.is_stale(NOW + LOCKOUT - 1));
+        assert!(attempts.is_stale(NOW + LOCKOUT));
+        // and the client starts over
+        assert!(!attempts.fail(&ACCOUNT_POLICY, NOW + LOCKOUT));
+        assert_eq!(attempts.failures, 1);
+        assert!(!attempts.locked);
+        assert!(attempts.blocked_until <= NOW + LOCKOUT);
+    }
+
+    #[test]
+    fn passkey_logins_are_limited() {
+        assert!(failed(&PASSKEY_LOGIN_POLICY, 20).blocked_until <= NOW);
+        assert_eq!(failed(&PASSKEY_LOGIN_POLICY, 21).blocked_until, NOW + 1);
+        assert!(failed(&PASSKEY_LOGIN_POLICY, 100).locked);
+    }
+}
@@ -14,15 +14,15 @@
  * limitations under the License.
 */
 
-use super::{COOKIE_TOKEN, COOKIE_USERNAME};
+use super::{sessions::Session, Credentials, COOKIE_TOKEN, COOKIE_USERNAME};
 use crate::{
     templates::{LoginPage, NoticePage},
     util::{self, create_cookie, resp},
 };
 use axum::{
     extract::{Extension, Form},
-    http::StatusCode,
-    response::Html,
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
 };
 use rand::Rng;
 use serde::Deserialize;
@@ -53,25 +53,37 @@ pub async fn login_get(cookies: Cookies) -> Html<String> {
 /// Authenticate an user. **You must ensure that the user is verified before authenticating
 /// them!**
 /// This will:
+/// - Refuse to log in users that have been disabled by an admin
 /// - Generate a session token
-/// - Store the hash of the session token in the auth table
-/// - Set cookies `username` and `token` with a validity of 15 days
+/// - Store the session under the hash of the session token in the sessions table
+/// - Forget the failed logins to the account (see `lockout`)
+/// - Set cookies `username` and `token` with the same validity as the session
 /// - Redirect the user to root `/`
 pub(super) async fn authenticate(
     uname: String,
-    cookies: &mut Cookies,
+    creds: &mut Credentials,
     con: &mut Connection,
 ) -> crate::JotsyResponse {
+    if super::admin::is_disabled(con, &uname).await? {
+        log::info!("Refused to log in disabled user `{uname}`");
+        return resp(
+            StatusCode::FORBIDDEN,
+            NoticePage::render_new("This account has been disabled by an admin", false),
+        );
+    }
     // sweet, we're verified
     // generate a token
     let token = generate_token();
     // hash the token
     let token_hash = util::sha2(&token);
-    // store the hash in the DB
-    con.set(token_hash, &uname).await?;
+    // store the session in the DB
+    let session = Session::new(uname.clone(), creds);
+    super::sessions::create(con, &token_hash, &session).await?;
+    // only now that the user is fully logged in (including any second factor)
+    super::lockout::forget(con, &uname).await?;
     // now set cookies
-    cookies.add(create_cookie(COOKIE_USERNAME, &uname));
-    cookies.add(create_cookie(COOKIE_TOKEN, token));
+    creds.cookies.add(create_cookie(COOKIE_USERNAME, &uname));
+    creds.cookies.add(create_cookie(COOKIE_TOKEN, token));
     resp(
         StatusCode::OK,
         NoticePage::new_redirect("Logged in successfully."),
@@ -80,42 +92,76 @@ pub(super) async fn authenticate(
 
 /// `POST` for `/login`
 /// This will:
+/// - Refuse to try the credentials if there were too many failed logins to the account or
+///   from the client's address recently
 /// - Attempt to verify the provided credentials
-/// - If they are valid, it will call `authenticate`
-/// - If not, it will return the login page with an error
+/// - If they are valid, it will call `authenticate` (or ask for a code first if the user has
+///   two-factor authentication on)
+/// - If not, it will record the failure and return the login page with an error, which is the
+///   same whether the password was wrong or the user doesn't exist
 pub async fn login(
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
     Form(lgn): Form<Login>,
-) -> crate::JotsyResponse {
+) -> crate::JotsyResponseResult<Response> {
     /*
     Login flow:
-    1. Get the hashed password from the DB
-    2. bcrypt::verify(hash_from_db, pass_from_form)
-    3. If verified, generate a token
+    1. If the account or address has failed too often, ask the client to wait (see `lockout`)
+    2. Get the hashed password from the DB
+    3. bcrypt::verify(hash_from_db, pass_from_form)
+    4. If verified and the user has two-factor authentication on, ask for a code (see
+       `totp::login_2fa`, which finishes the login)
+    5. If verified, generate a token
         a. Store hash(token) into DB
         b. Send token to browser
-    4. If not verified, return to `/`
+    6. If not verified (or the user doesn't exist, after a dummy verify), record the failure
+       and return to `/`
     */
     let mut con = db.get().await?;
+    if let Some(wait) = super::lockout::check(&mut con, &lgn.username, creds.ip()).await? {
+        return Ok(self::rate_limited(wait));
+    }
     con.switch(crate::TABLE_AUTH).await?;
     let hash_from_db: Result<String, Error> = con.get(&lgn.username).await;
-    match hash_from_db {
+    let ret = match hash_from_db {
         Ok(v) if util::bcrypt_verify(&lgn.password, &v) => {
-            authenticate(lgn.username, &mut cookies, &mut con).await
+            if super::totp::is_enabled(&mut con, &lgn.username).await? {
+                // the password is right, but they still need to enter a code
+                super::totp::start_login(&mut con, lgn.username).await
+            } else {
+                authenticate(lgn.username, &mut creds, &mut con).await
+            }
         }
         Ok(_) => {
             // nope, unverified
+            super::lockout::record_failure(&mut con, &lgn.username, creds.ip()).await?;
             resp(StatusCode::UNAUTHORIZED, LoginPage::render_new(true))
         }
         Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => {
-            resp(StatusCode::NOT_FOUND, LoginPage::render_new(true))
+            // no such user, but make this look exactly like a wrong password (including how
+            // long it takes) so that usernames can't be enumerated
+            util::bcrypt_dummy_verify(&lgn.password);
+            super::lockout::record_failure(&mut con, &lgn.username, creds.ip()).await?;
+            resp(StatusCode::UNAUTHORIZED, LoginPage::render_new(true))
         }
         Err(e) => {
             log::error!("Failed to log user in: {}", e);
             NoticePage::re500()
         }
-    }
+    };
+    Ok(ret?.into_response())
+}
+
+/// Returns the login page asking the client to wait `wait` seconds before trying again
+pub(super) fn rate_limited(wait: i64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, wait.to_string())],
+        Html(LoginPage::render_rate_limited(
+            super::lockout::describe_wait(wait),
+        )),
+    )
+        .into_response()
 }
 
 const CHARSET: &[u8] =
@@ -22,10 +22,10 @@ use axum::{
     extract::{Extension, Form},
     http::StatusCode,
 };
-use skytable::{actions::AsyncActions, ddl::AsyncDdl, pool::AsyncPool};
+use skytable::pool::AsyncPool;
 use tower_cookies::Cookies;
 
-use super::{COOKIE_USERNAME, COOKIE_TOKEN};
+use super::{COOKIE_TOKEN, COOKIE_USERNAME};
 
 /// `POST` for `/logout`
 pub async fn logout(
@@ -38,11 +38,11 @@ pub async fn logout(
 
 /// The main logic for a logout procedure. This will:
 /// - Get the cookies
-/// - Will attempt to remove hash(token) from the DB
+/// - Will attempt to end the session for hash(token)
 ///     - If this succeeds, it will remove the cookies
 /// - If there are either of `username` or `token` cookies set, then remove them
 /// - If no cookies are set, it will simply return a NOT_ACCEPTABLE error because
-/// you aren't expected to `POST` to `/logout` without either
+///   you aren't expected to `POST` to `/logout` without either
 /// - Redirects to `/`
 pub async fn logout_core(
     cookies: Cookies,
@@ -52,14 +52,13 @@ pub async fn logout_core(
     let mut con = db.get().await?;
     let c_user = cookies.get(super::COOKIE_USERNAME);
     let c_token = cookies.get(super::COOKIE_TOKEN);
-    con.switch(crate::TABLE_AUTH).await?;
     match (c_user, c_token) {
         (Some(_), Some(token_c)) => {
             let token = token_c.value().to_owned();
             // let's attempt to remove this
-            let del = con.del(util::sha2(token)).await?;
+            let del = super::sessions::remove(&mut con, &util::sha2(token)).await?;
             // now remove these cookies
-            if del == 1 {
+            if del {
                 cookies.remove(util::null_cookie(COOKIE_USERNAME));
                 cookies.remove(util::null_cookie(COOKIE_TOKEN));
             }
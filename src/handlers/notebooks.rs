@@ -0,0 +1,317 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Notebooks. Every note is in exactly one notebook, and every user has a default notebook
+//! (which is where notes from before notebooks existed live). We store:
+//! - `default:jotsynotebooks`, which maps `username -> [Notebook]`
+//! - `default:jotsynotebooknotes`, which maps `username/notebook ID -> [note ID]`, oldest
+//!   first, just like the user's list of all their notes in `default:jotsynotes`. Archived
+//!   notes aren't in here
+//! - `default:jotsypinned`, which maps `username/notebook ID -> [note ID]` for the pinned
+//!   notes in each notebook, in the order they were pinned
+
+use super::{app, Credentials};
+use crate::{
+    templates::NoticePage,
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form, Path},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use skytable::{actions::AsyncActions, aio::Connection, ddl::AsyncDdl, pool::AsyncPool, query};
+
+/// The ID of every user's default notebook
+pub const DEFAULT_NOTEBOOK: &str = "default";
+/// The name of the default notebook until it is renamed
+const DEFAULT_NOTEBOOK_NAME: &str = "Notes";
+const NOTEBOOK_ID_LEN: usize = 8;
+const NOTEBOOK_NAME_MAX_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone)]
+/// A `Notebook`. This is stored as JSON in the user's list of notebooks
+pub struct Notebook {
+    pub id: String,
+    pub name: String,
+}
+
+impl Notebook {
+    fn default_notebook() -> Self {
+        Self {
+            id: DEFAULT_NOTEBOOK.to_owned(),
+            name: DEFAULT_NOTEBOOK_NAME.to_owned(),
+        }
+    }
+    pub fn is_default(&self) -> bool {
+        self.id == DEFAULT_NOTEBOOK
+    }
+    /// Returns the path to the app page for this notebook
+    pub fn path(&self) -> String {
+        if self.is_default() {
+            "/".to_owned()
+        } else {
+            format!("/notebooks/{}", self.id)
+        }
+    }
+}
+
+/// A notebook, with the number of notes in it
+pub struct NotebookCount {
+    pub notebook: Notebook,
+    pub count: u64,
+}
+
+/// Returns the key for the list of notes in a notebook
+pub fn notes_key(username: &str, notebook: &str) -> String {
+    format!("{username}/{notebook}")
+}
+
+/// Returns all of a user's notebooks, the default notebook first
+pub async fn get_notebooks(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<Notebook>> {
+    con.switch(crate::TABLE_NOTEBOOKS).await?;
+    let mut notebooks: Vec<Notebook> = util::list_get(con, username)
+        .await?
+        .iter()
+        .map(|notebook| serde_json::from_str(notebook).unwrap())
+        .collect();
+    // the default notebook is only stored once it has been renamed
+    if !notebooks.iter().any(Notebook::is_default) {
+        notebooks.insert(0, Notebook::default_notebook());
+    }
+    Ok(notebooks)
+}
+
+/// Returns the notebook with the given ID, if it exists
+pub async fn get_notebook(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<Option<Notebook>> {
+    let notebooks = self::get_notebooks(con, username).await?;
+    Ok(notebooks.into_iter().find(|notebook| notebook.id == id))
+}
+
+/// Returns all of a user's notebooks with the number of notes in each
+pub async fn get_counts(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<NotebookCount>> {
+    let notebooks = self::get_notebooks(con, username).await?;
+    con.switch(crate::TABLE_NOTEBOOK_NOTES).await?;
+    let mut counts = Vec::with_capacity(notebooks.len());
+    for notebook in notebooks {
+        let count = util::list_len(con, &self::notes_key(username, &notebook.id)).await?;
+        counts.push(NotebookCount { notebook, count });
+    }
+    Ok(counts)
+}
+
+/// Empty all of a user's notebooks (and their pinned notes). The notebooks themselves are kept
+pub async fn empty_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    let keys: Vec<String> = self::get_notebooks(con, username)
+        .await?
+        .iter()
+        .map(|notebook| self::notes_key(username, &notebook.id))
+        .collect();
+    con.switch(crate::TABLE_NOTEBOOK_NOTES).await?;
+    con.del(keys.clone()).await?;
+    con.switch(crate::TABLE_PINNED).await?;
+    con.del(keys).await?;
+    Ok(())
+}
+
+/// Delete all of a user's notebooks
+pub async fn remove_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    self::empty_all(con, username).await?;
+    con.switch(crate::TABLE_NOTEBOOKS).await?;
+    con.del(username).await?;
+    Ok(())
+}
+
+/// Replace the stored list of a user's notebooks
+async fn put_notebooks(
+    con: &mut Connection,
+    username: &str,
+    notebooks: &[Notebook],
+) -> crate::JotsyResponseResult<()> {
+    let notebooks: Vec<String> = notebooks
+        .iter()
+        .map(|notebook| serde_json::to_string(notebook).unwrap())
+        .collect();
+    con.switch(crate::TABLE_NOTEBOOKS).await?;
+    con.del(username).await?;
+    util::expect_okay(con.run_query(&query!("LSET", username, notebooks)).await?)
+}
+
+/// Returns the trimmed name from a form, or an error page if it isn't a valid notebook name
+fn validate_name(name: &str) -> Result<&str, crate::JotsyResponse> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > NOTEBOOK_NAME_MAX_LEN {
+        Err(resp(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            NoticePage::render_new(
+                "Notebook names must have between 1 and 64 characters",
+                false,
+            ),
+        ))
+    } else {
+        Ok(name)
+    }
+}
+
+pub(super) fn notebook_not_found() -> crate::JotsyResponse {
+    resp(
+        StatusCode::NOT_FOUND,
+        NoticePage::render_new("That notebook doesn't exist", false),
+    )
+}
+
+/// `GET` for `/notebooks/:id`. Returns the app page for the notebook
+pub async fn notebook_page(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    match self::get_notebook(&mut con, &username, &id).await? {
+        Some(notebook) => app::notebook_app(&mut con, &creds, username, notebook).await,
+        None => self::notebook_not_found(),
+    }
+}
+
+#[derive(Deserialize)]
+/// The form to create or rename a notebook
+pub struct NotebookForm {
+    name: String,
+}
+
+/// `POST` for `/notebooks`
+///
+/// This will:
+/// - Verify the session
+/// - Create a new, empty notebook
+/// - Redirect to the new notebook
+pub async fn create_notebook(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<NotebookForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let name = match self::validate_name(&form.name) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    let notebook = Notebook {
+        id: util::random_alphanumeric(NOTEBOOK_ID_LEN),
+        name: name.to_owned(),
+    };
+    let mut notebooks = self::get_notebooks(&mut con, &username).await?;
+    notebooks.push(notebook.clone());
+    self::put_notebooks(&mut con, &username, &notebooks).await?;
+    resp(
+        StatusCode::CREATED,
+        NoticePage::redirect_to("Created notebook", notebook.path()),
+    )
+}
+
+/// `POST` for `/notebooks/:id/rename`
+pub async fn rename_notebook(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<NotebookForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let name = match self::validate_name(&form.name) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    let mut notebooks = self::get_notebooks(&mut con, &username).await?;
+    match notebooks.iter_mut().find(|notebook| notebook.id == id) {
+        Some(notebook) => {
+            notebook.name = name.to_owned();
+            let path = notebook.path();
+            self::put_notebooks(&mut con, &username, &notebooks).await?;
+            resp(
+                StatusCode::OK,
+                NoticePage::redirect_to("Renamed notebook", path),
+            )
+        }
+        None => self::notebook_not_found(),
+    }
+}
+
+/// `POST` for `/notebooks/:id/delete`
+///
+/// This will:
+/// - Verify the session
+/// - Move all the notes in the notebook (including its archived notes) to the default notebook
+/// - Delete the notebook
+pub async fn delete_notebook(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(_): Form<util::Empty>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    if id == DEFAULT_NOTEBOOK {
+        return resp(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            NoticePage::render_new("The default notebook can't be deleted", false),
+        );
+    }
+    let mut notebooks = self::get_notebooks(&mut con, &username).await?;
+    let before = notebooks.len();
+    notebooks.retain(|notebook| notebook.id != id);
+    if notebooks.len() == before {
+        return self::notebook_not_found();
+    }
+    con.switch(crate::TABLE_NOTEBOOK_NOTES).await?;
+    let ids = util::list_get(&mut con, &self::notes_key(&username, &id)).await?;
+    let mut notes = app::get_notes(&mut con, &username, &ids).await?;
+    // archived notes aren't in the notebook's list, but they still belong to it
+    con.switch(crate::TABLE_ARCHIVE).await?;
+    let archived = util::list_get(&mut con, &username).await?;
+    let archived = app::get_notes(&mut con, &username, &archived).await?;
+    notes.extend(archived.into_iter().filter(|note| note.notebook == id));
+    let moved = notes.len();
+    for note in notes {
+        let mut moved = note.clone();
+        moved.notebook = DEFAULT_NOTEBOOK.to_owned();
+        app::update_note(&mut con, &username, &note, &moved).await?;
+    }
+    con.switch(crate::TABLE_NOTEBOOK_NOTES).await?;
+    con.del(self::notes_key(&username, &id)).await?;
+    con.switch(crate::TABLE_PINNED).await?;
+    con.del(self::notes_key(&username, &id)).await?;
+    self::put_notebooks(&mut con, &username, &notebooks).await?;
+    log::info!("Deleted notebook for `{username}` and moved {moved} note(s)");
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to(
+            format!("Deleted notebook and moved {moved} note(s) to your default notebook"),
+            "/",
+        ),
+    )
+}
@@ -0,0 +1,525 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Passkeys (WebAuthn). Users register passkeys from their account page and can then log in
+//! with one instead of their password (or a code: a passkey already checks that the user is
+//! there). Passkeys are discoverable, so logging in doesn't need a username. We keep:
+//! - `default:jotsypasskeys` mapping `username -> UserPasskeys`
+//! - `default:jotsypasskeyhandles` mapping `handle -> username`. A handle is a random UUID
+//!   that a passkey stores instead of the username, so that we can tell whose passkey it is
+//! - `default:jotsypasskeyceremonies` mapping `id -> PendingCeremony` for registrations and
+//!   logins that were started but not finished yet
+
+use super::{app::rfc3339, Credentials};
+use crate::{
+    error::ResponseError,
+    templates::NoticePage,
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+use std::{sync::OnceLock, time::Duration};
+use webauthn_rs::prelude::{
+    DiscoverableAuthentication, DiscoverableKey, Passkey, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, Url, Uuid, Webauthn, WebauthnBuilder,
+};
+
+/// How long a user has to finish registering a passkey or logging in with one (in seconds)
+const CEREMONY_TTL: i64 = 5 * 60;
+/// The length of the ID of a ceremony
+const CEREMONY_ID_LEN: usize = 32;
+/// The length of the ID of a passkey, used to remove it
+const PASSKEY_ID_LEN: usize = 16;
+/// The longest name a passkey can have
+const MAX_NAME_LEN: usize = 64;
+/// How often the reaper looks for ceremonies that have expired
+const REAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static WEBAUTHN: OnceLock<Webauthn> = OnceLock::new();
+
+/// Set up WebAuthn for the URL Jotsy is served on. Passkeys only work on that URL (and only
+/// over HTTPS, unless it's `localhost`)
+pub fn init(public_url: &str) -> crate::DynResult<()> {
+    let origin = Url::parse(public_url)?;
+    let rp_id = origin
+        .host_str()
+        .ok_or("JOTSY_PUBLIC_URL must have a host")?;
+    let webauthn = WebauthnBuilder::new(rp_id, &origin)?
+        .rp_name("Jotsy")
+        .build()?;
+    let _ = WEBAUTHN.set(webauthn);
+    Ok(())
+}
+
+fn webauthn() -> &'static Webauthn {
+    WEBAUTHN.get().expect("passkeys weren't initialized")
+}
+
+#[derive(Serialize, Deserialize)]
+/// A passkey as we store it
+pub struct StoredPasskey {
+    pub id: String,
+    /// A name the user gave the passkey, like the device it's on
+    pub name: String,
+    #[serde(with = "rfc3339")]
+    pub created: DateTime<Utc>,
+    #[serde(default, with = "rfc3339::option")]
+    pub last_used: Option<DateTime<Utc>>,
+    passkey: Passkey,
+}
+
+#[derive(Serialize, Deserialize)]
+/// All of a user's passkeys. This is stored as JSON in Skytable and is ser/de-d as required
+struct UserPasskeys {
+    handle: Uuid,
+    passkeys: Vec<StoredPasskey>,
+}
+
+async fn get(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Option<UserPasskeys>> {
+    con.switch(crate::TABLE_PASSKEYS).await?;
+    let passkeys: Result<String, Error> = con.get(username).await;
+    match passkeys {
+        // never treat this as missing, which would replace the passkeys on the next change
+        Ok(json) => serde_json::from_str(&json).map(Some).map_err(|e| {
+            ResponseError::CorruptRecord(format!("the passkeys of `{username}`: {e}"))
+        }),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn set(
+    con: &mut Connection,
+    username: &str,
+    passkeys: &UserPasskeys,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_PASSKEYS).await?;
+    let json = serde_json::to_string(passkeys).unwrap();
+    let _: u64 = con.run_query(&query!("USET", username, json)).await?;
+    Ok(())
+}
+
+/// Returns all of a user's passkeys, oldest first
+pub async fn list(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<StoredPasskey>> {
+    Ok(self::get(con, username)
+        .await?
+        .map(|passkeys| passkeys.passkeys)
+        .unwrap_or_default())
+}
+
+/// Remove all of a user's passkeys
+pub async fn remove_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    if let Some(passkeys) = self::get(con, username).await? {
+        con.switch(crate::TABLE_PASSKEY_HANDLES).await?;
+        con.del(passkeys.handle.to_string()).await?;
+        con.switch(crate::TABLE_PASSKEYS).await?;
+        con.del(username).await?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+/// A registration or login that was started
+enum Ceremony {
+    Register {
+        username: String,
+        /// The handle of the user, which is new if this is their first passkey
+        handle: Uuid,
+        state: PasskeyRegistration,
+    },
+    Login {
+        state: DiscoverableAuthentication,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+/// A ceremony that hasn't been finished yet. This is stored as JSON in Skytable and is
+/// ser/de-d as required
+struct PendingCeremony {
+    ceremony: Ceremony,
+    /// When the ceremony has to be started over (a UNIX timestamp)
+    expires: i64,
+}
+
+impl PendingCeremony {
+    fn is_expired(&self) -> bool {
+        util::now() >= self.expires
+    }
+}
+
+/// Store a ceremony until it is finished. Returns its ID, which the client sends back with the
+/// result
+async fn start_ceremony(
+    con: &mut Connection,
+    ceremony: Ceremony,
+) -> crate::JotsyResponseResult<String> {
+    let id = util::random_alphanumeric(CEREMONY_ID_LEN);
+    let pending = PendingCeremony {
+        ceremony,
+        expires: util::now() + CEREMONY_TTL,
+    };
+    con.switch(crate::TABLE_PASSKEY_CEREMONIES).await?;
+    con.set(&id, serde_json::to_string(&pending).unwrap())
+        .await?;
+    Ok(id)
+}
+
+/// Take a ceremony to finish it, so that it can't be used again. Returns `None` if it doesn't
+/// exist or has expired
+async fn take_ceremony(
+    con: &mut Connection,
+    id: &str,
+) -> crate::JotsyResponseResult<Option<Ceremony>> {
+    con.switch(crate::TABLE_PASSKEY_CEREMONIES).await?;
+    let pending: Result<String, Error> = con.get(id).await;
+    let pending = match pending {
+        Ok(json) => serde_json::from_str::<PendingCeremony>(&json),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    con.del(id).await?;
+    match pending {
+        Ok(pending) => Ok((!pending.is_expired()).then_some(pending.ceremony)),
+        Err(e) => {
+            // the client just has to start over, like for an expired ceremony
+            log::warn!("Ended the unreadable passkey ceremony `{id}`: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// Purge all expired ceremonies. Returns the number of ceremonies purged
+async fn reap(pool: &AsyncPool) -> crate::JotsyResponseResult<usize> {
+    let mut con = pool.get().await?;
+    con.switch(crate::TABLE_PASSKEY_CEREMONIES).await?;
+    let ids: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    let pending = util::mget(&mut con, ids.clone()).await?;
+    let expired: Vec<String> = ids
+        .into_iter()
+        .zip(pending)
+        .filter(|(id, pending)| {
+            pending.as_ref().is_some_and(|json| {
+                match serde_json::from_str::<PendingCeremony>(json) {
+                    Ok(pending) => pending.is_expired(),
+                    Err(e) => {
+                        log::warn!("Skipped the unreadable passkey ceremony `{id}`: {e}");
+                        false
+                    }
+                }
+            })
+        })
+        .map(|(id, _)| id)
+        .collect();
+    if !expired.is_empty() {
+        con.del(&expired).await?;
+    }
+    Ok(expired.len())
+}
+
+/// Periodically purge passkey registrations and logins that were never finished. This is
+/// spawned as a background task and never returns
+pub async fn reaper(pool: AsyncPool) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match self::reap(&pool).await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {purged} expired passkey ceremonies"),
+            Err(e) => log::error!("Failed to purge expired passkey ceremonies: {e}"),
+        }
+    }
+}
+
+/// `POST` for `/account/passkeys/options`
+///
+/// This will:
+/// - Verify the session
+/// - Start registering a passkey (that isn't one of the user's passkeys already)
+/// - Return the ID of the registration and the options for `navigator.credentials.create()`
+pub async fn register_options(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponseResult<Response> {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let (handle, exclude) = match self::get(&mut con, &username).await? {
+        Some(passkeys) => (
+            passkeys.handle,
+            passkeys
+                .passkeys
+                .iter()
+                .map(|stored| stored.passkey.cred_id().clone())
+                .collect(),
+        ),
+        None => (Uuid::new_v4(), Vec::new()),
+    };
+    let (mut options, state) =
+        match webauthn().start_passkey_registration(handle, &username, &username, Some(exclude)) {
+            Ok(started) => started,
+            Err(e) => {
+                log::error!("Failed to start registering a passkey for `{username}`: {e}");
+                return Ok(NoticePage::e500_resp());
+            }
+        };
+    // ask for a discoverable passkey, since logging in doesn't ask for a username. Without
+    // `residentKey`, browsers go by `requireResidentKey`
+    if let Some(selection) = options.public_key.authenticator_selection.as_mut() {
+        selection.resident_key = None;
+        selection.require_resident_key = true;
+    }
+    let ceremony = Ceremony::Register {
+        username,
+        handle,
+        state,
+    };
+    let id = self::start_ceremony(&mut con, ceremony).await?;
+    Ok(Json(json!({ "id": id, "options": options })).into_response())
+}
+
+#[derive(Deserialize)]
+/// The result of registering a passkey
+pub struct RegisterForm {
+    /// The ID of the registration
+    id: String,
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+/// `POST` for `/account/passkeys`
+///
+/// This will:
+/// - Verify the session
+/// - Finish the registration (which must have been started by the same user) and store the
+///   passkey
+pub async fn register(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Json(form): Json<RegisterForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let (handle, state) = match self::take_ceremony(&mut con, &form.id).await? {
+        Some(Ceremony::Register {
+            username: started_by,
+            handle,
+            state,
+        }) if started_by == username => (handle, state),
+        _ => {
+            return resp(
+                StatusCode::BAD_REQUEST,
+                "This registration has expired. Please try again",
+            )
+        }
+    };
+    let passkey = match webauthn().finish_passkey_registration(&form.credential, &state) {
+        Ok(passkey) => passkey,
+        Err(e) => {
+            log::info!("Failed to register a passkey for `{username}`: {e}");
+            return resp(
+                StatusCode::BAD_REQUEST,
+                "That passkey couldn't be registered",
+            );
+        }
+    };
+    let name = match form.name.trim() {
+        "" => "Passkey".to_owned(),
+        name => name.chars().take(MAX_NAME_LEN).collect(),
+    };
+    let mut passkeys = self::get(&mut con, &username)
+        .await?
+        .unwrap_or(UserPasskeys {
+            handle,
+            passkeys: Vec::new(),
+        });
+    passkeys.passkeys.push(StoredPasskey {
+        id: util::random_alphanumeric(PASSKEY_ID_LEN),
+        name,
+        created: Utc::now(),
+        last_used: None,
+        passkey,
+    });
+    self::set(&mut con, &username, &passkeys).await?;
+    con.switch(crate::TABLE_PASSKEY_HANDLES).await?;
+    let _: u64 = con
+        .run_query(&query!("USET", passkeys.handle.to_string(), &username))
+        .await?;
+    log::info!("Registered a passkey for `{username}`");
+    resp(StatusCode::CREATED, "Registered your passkey")
+}
+
+#[derive(Deserialize)]
+/// The form to remove a passkey
+pub struct RemovePasskeyForm {
+    id: String,
+}
+
+/// `POST` for `/account/passkeys/remove`
+pub async fn remove_passkey(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<RemovePasskeyForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let mut passkeys = match self::get(&mut con, &username).await? {
+        Some(passkeys) if passkeys.passkeys.iter().any(|stored| stored.id == form.id) => passkeys,
+        _ => {
+            return resp(
+                StatusCode::NOT_FOUND,
+                NoticePage::render_new("That passkey doesn't exist", false),
+            )
+        }
+    };
+    passkeys.passkeys.retain(|stored| stored.id != form.id);
+    if passkeys.passkeys.is_empty() {
+        self::remove_all(&mut con, &username).await?;
+    } else {
+        self::set(&mut con, &username, &passkeys).await?;
+    }
+    log::info!("Removed a passkey of `{username}`");
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to("Removed passkey", "/account"),
+    )
+}
+
+/// `POST` for `/login/passkey/options`
+///
+/// This will:
+/// - Check that the client's address hasn't started too many logins (since anyone can start
+///   one, and each is stored until it expires)
+/// - Start a login with a (discoverable) passkey
+/// - Return the ID of the login and the options for `navigator.credentials.get()`
+pub async fn login_options(
+    creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponseResult<Response> {
+    let mut con = db.get().await?;
+    if let Some(wait) = super::lockout::start_passkey_login(&mut con, creds.ip()).await? {
+        return Ok(super::login::rate_limited(wait));
+    }
+    let (mut options, state) = match webauthn().start_discoverable_authentication() {
+        Ok(started) => started,
+        Err(e) => {
+            log::error!("Failed to start a passkey login: {e}");
+            return Ok(NoticePage::e500_resp());
+        }
+    };
+    // this is for autofill by default, but we log in from a button instead
+    options.mediation = None;
+    let id = self::start_ceremony(&mut con, Ceremony::Login { state }).await?;
+    Ok(Json(json!({ "id": id, "options": options })).into_response())
+}
+
+#[derive(Deserialize)]
+/// The result of logging in with a passkey
+pub struct LoginForm {
+    /// The ID of the login
+    id: String,
+    credential: PublicKeyCredential,
+}
+
+/// Returns the page for a passkey login that failed
+fn login_failed() -> crate::JotsyResponse {
+    resp(
+        StatusCode::UNAUTHORIZED,
+        NoticePage::new_redirect("Couldn't log you in with that passkey"),
+    )
+}
+
+/// `POST` for `/login/passkey`
+///
+/// This will:
+/// - Find the user whose passkey this is
+/// - Finish the login, checking the passkey's signature
+/// - Record that the passkey was used and call `authenticate`
+pub async fn login(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Json(form): Json<LoginForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let state = match self::take_ceremony(&mut con, &form.id).await? {
+        Some(Ceremony::Login { state }) => state,
+        _ => {
+            return resp(
+                StatusCode::BAD_REQUEST,
+                NoticePage::new_redirect("This login has expired. Please try again"),
+            )
+        }
+    };
+    let handle = match webauthn().identify_discoverable_authentication(&form.credential) {
+        Ok((handle, _)) => handle,
+        Err(_) => return self::login_failed(),
+    };
+    con.switch(crate::TABLE_PASSKEY_HANDLES).await?;
+    let username: Result<String, Error> = con.get(handle.to_string()).await;
+    let username = match username {
+        Ok(username) => username,
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => {
+            return self::login_failed()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut passkeys = match self::get(&mut con, &username).await? {
+        Some(passkeys) => passkeys,
+        None => return self::login_failed(),
+    };
+    let keys: Vec<DiscoverableKey> = passkeys
+        .passkeys
+        .iter()
+        .map(|stored| DiscoverableKey::from(&stored.passkey))
+        .collect();
+    let result = match webauthn().finish_discoverable_authentication(&form.credential, state, &keys)
+    {
+        Ok(result) => result,
+        Err(e) => {
+            log::info!("Failed passkey login for `{username}`: {e}");
+            return self::login_failed();
+        }
+    };
+    if let Some(stored) = passkeys
+        .passkeys
+        .iter_mut()
+        .find(|stored| stored.passkey.cred_id() == result.cred_id())
+    {
+        stored.passkey.update_credential(&result);
+        stored.last_used = Some(Utc::now());
+    }
+    self::set(&mut con, &username, &passkeys).await?;
+    super::login::authenticate(username, &mut creds, &mut con).await
+}
@@ -15,18 +15,10 @@
 */
 
 use {
-    super::{COOKIE_TOKEN, COOKIE_USERNAME},
+    super::{sessions, Credentials, COOKIE_TOKEN, COOKIE_USERNAME},
     crate::{error::ResponseError, templates::LoginPage, util},
     axum::extract::Extension,
-    skytable::{
-        actions::AsyncActions,
-        aio::Connection,
-        ddl::AsyncDdl,
-        error::{Error, SkyhashError},
-        pool::AsyncPool,
-        RespCode,
-    },
-    tower_cookies::Cookies,
+    skytable::{aio::Connection, pool::AsyncPool},
 };
 
 /// `GET` for `/`
@@ -34,33 +26,48 @@ use {
 /// - If cookies are set, verify and return the app
 /// - If no cookies are set, return login
 pub async fn root(
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
 ) -> crate::JotsyResponse {
     // our database has hash(tokens) -> username
     // so we need to send the hash of the token and see if the returne value
     let mut con = db.get().await?;
-    let uname = verify_user_or_error(&mut con, &mut cookies).await?;
+    let uname = verify_user_or_error(&mut con, &mut creds).await?;
     drop(con);
-    super::app::app(uname, db).await
+    super::app::app(&creds, uname, db).await
 }
 
 /// Verify an user or error
 /// This will:
+/// - Verify the API token if one was sent, returning the username if it's valid (and its
+///   user hasn't been disabled) and the login page if it isn't
 /// - Return the login page if no cookie is set
 /// - Verify the session if cookies are set:
 ///     - If verified, it will return the username
 ///     - If not, it will return the login page
 pub(super) async fn verify_user_or_error(
     con: &mut Connection,
-    cookies: &mut Cookies,
+    creds: &mut Credentials,
 ) -> crate::JotsyResponseResult<String> {
+    if let Some(token) = creds.bearer() {
+        return match super::tokens::verify_token(con, token).await? {
+            Some(username) if !super::admin::is_disabled(con, &username).await? => Ok(username),
+            _ => Err(ResponseError::Redirect(LoginPage::render_new(false))),
+        };
+    }
+    let cookies = &mut creds.cookies;
     let username = cookies.get(COOKIE_USERNAME);
     let token = cookies.get(COOKIE_TOKEN);
     if let (Some(username), Some(token)) = (username, token) {
-        let verified = verify_user(con, username.value(), token.value()).await?;
-        if verified {
-            return Ok(username.value().to_string());
+        match verify_user(con, username.value(), token.value()).await? {
+            Verified::Renewed => {
+                // the session was extended, so extend the cookies too
+                cookies.add(util::create_cookie(COOKIE_USERNAME, username.value()));
+                cookies.add(util::create_cookie(COOKIE_TOKEN, token.value()));
+                return Ok(username.value().to_string());
+            }
+            Verified::Yes => return Ok(username.value().to_string()),
+            Verified::No => {}
         }
     }
     cookies.remove(util::null_cookie(COOKIE_USERNAME));
@@ -68,32 +75,47 @@ pub(super) async fn verify_user_or_error(
     Err(ResponseError::Redirect(LoginPage::render_new(false)))
 }
 
+/// The result of verifying a session
+enum Verified {
+    Yes,
+    /// The session is valid and was renewed, so the cookies should be renewed too
+    Renewed,
+    No,
+}
+
 /// Verify the provided token for the username
 /// This will:
 /// - Hash the token
-/// - Get the value for the hash
-///     - If found, check if uname == uname from DB
-///         - If yes, return true
-///         - If no, return false. Clearly, someone is trying to forge something
-///         (**the caller should unset the cookies**)
-///     - If not found, simply return false (**the caller should unset the cookies**)
+/// - Get the session for the hash
+///     - If found, check if uname == uname from the session
+///         - If yes and the session hasn't expired, record the activity and return
+///           `Yes` (or `Renewed` if sliding renewal extended the session)
+///         - If yes but the session has expired, purge it and return `No`
+///         - If no, return `No`. Clearly, someone is trying to forge something
+///           (**the caller should unset the cookies**)
+///     - If not found, simply return `No` (**the caller should unset the cookies**)
 async fn verify_user<'a>(
     con: &mut Connection,
     uname: &'a str,
     token: &'a str,
-) -> crate::JotsyResponseResult<bool> {
-    con.switch(crate::TABLE_AUTH).await?;
+) -> crate::JotsyResponseResult<Verified> {
     let hash: String = util::sha2(token);
-    let x: Result<String, Error> = con.get(&hash).await;
-    match x {
-        Ok(ret) if ret.eq(uname) => Ok(true),
-        Ok(_) => {
+    match sessions::get(con, &hash).await? {
+        Some(session) if session.username.eq(uname) && !session.is_expired() => {
+            let touched = sessions::touch(con, &hash, session).await?;
+            if touched && util::session_sliding() {
+                Ok(Verified::Renewed)
+            } else {
+                Ok(Verified::Yes)
+            }
+        }
+        Some(_) => {
             // so we got the uname but it's not equal to this? well, possibly the
-            // session was removed, so purge it (penalty for forge attempts :D)
-            con.del(hash).await?;
-            Ok(false)
+            // session was removed, so purge it (penalty for forge attempts :D). and
+            // of course, expired sessions go too
+            sessions::remove(con, &hash).await?;
+            Ok(Verified::No)
         }
-        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(false),
-        Err(e) => Err(e.into()),
+        None => Ok(Verified::No),
     }
 }
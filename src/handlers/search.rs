@@ -0,0 +1,439 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Full-text search over a user's notes. We keep an inverted [index](super::index) of the
+//! terms in each note (in `default:jotsysearch`) along with each user's vocabulary (in
+//! `default:jotsysearchterms`), which is scanned for prefix matches
+//!
+//! A query is a list of words and `"quoted phrases"`. Words match any term they are a prefix
+//! of, phrases must appear exactly (ignoring case and punctuation), and a note must match every
+//! part of the query
+
+use super::{
+    app::{self, Note},
+    index, Credentials,
+};
+use crate::{templates::SearchPage, util::resp};
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use skytable::{aio::Connection, pool::AsyncPool};
+use std::{
+    collections::{BTreeSet, HashSet},
+    ops::Range,
+};
+
+/// Longer "words" (like hashes or base64 blobs) aren't worth indexing
+const TERM_MAX_LEN: usize = 64;
+/// The number of characters to show before the first match in a snippet
+const SNIPPET_CONTEXT: usize = 60;
+/// The total number of characters in a snippet
+const SNIPPET_LEN: usize = 200;
+
+/// Split text into lowercased terms, along with where each of them is in the text
+fn tokenize(text: &str) -> Vec<(Range<usize>, String)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (idx, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(idx),
+            (false, Some(s)) => {
+                tokens.push((s..idx, text[s..idx].to_lowercase()));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// Returns the unique terms in some text that go into the index
+fn terms(text: &str) -> BTreeSet<String> {
+    self::tokenize(text)
+        .into_iter()
+        .map(|(_, term)| term)
+        .filter(|term| term.chars().count() <= TERM_MAX_LEN)
+        .collect()
+}
+
+/// Add a note to the index
+pub async fn index_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+    body: &str,
+) -> crate::JotsyResponseResult<()> {
+    index::SEARCH
+        .add(con, username, id, &self::terms(body))
+        .await
+}
+
+/// Remove a note from the index
+pub async fn unindex_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+    body: &str,
+) -> crate::JotsyResponseResult<()> {
+    index::SEARCH
+        .remove(con, username, id, &self::terms(body))
+        .await
+}
+
+/// Update the index after the body of a note has changed from `old` to `new`
+pub async fn reindex_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+    old: &str,
+    new: &str,
+) -> crate::JotsyResponseResult<()> {
+    index::SEARCH
+        .update(con, username, id, &self::terms(old), &self::terms(new))
+        .await
+}
+
+/// Returns true if the user has anything in the index
+pub async fn has_index(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<bool> {
+    Ok(!index::SEARCH.keys(con, username).await?.is_empty())
+}
+
+/// A part of a search query
+enum Part {
+    /// A word, which matches any term that starts with it
+    Word(String),
+    /// A quoted phrase, which must appear as is
+    Phrase(Vec<String>),
+}
+
+impl Part {
+    /// Returns where this part matches in the given tokens
+    fn matches(&self, tokens: &[(Range<usize>, String)]) -> Vec<Range<usize>> {
+        match self {
+            Self::Word(word) => tokens
+                .iter()
+                .filter(|(_, term)| term.starts_with(word.as_str()))
+                .map(|(range, _)| range.clone())
+                .collect(),
+            Self::Phrase(phrase) => tokens
+                .windows(phrase.len())
+                .filter(|window| window.iter().map(|(_, t)| t).eq(phrase.iter()))
+                .map(|window| window[0].0.start..window[window.len() - 1].0.end)
+                .collect(),
+        }
+    }
+}
+
+/// Parse a search query. Text in double quotes is a phrase; everything else is split into words
+fn parse(query: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    for (idx, segment) in query.split('"').enumerate() {
+        let terms: Vec<String> = self::tokenize(segment)
+            .into_iter()
+            .map(|(_, term)| term)
+            .collect();
+        if idx % 2 == 1 && !terms.is_empty() {
+            parts.push(Part::Phrase(terms));
+        } else {
+            parts.extend(terms.into_iter().map(Part::Word));
+        }
+    }
+    parts
+}
+
+/// Returns the IDs of the notes that could match all the parts of a query, using the index
+async fn candidates(
+    con: &mut Connection,
+    username: &str,
+    parts: &[Part],
+) -> crate::JotsyResponseResult<HashSet<String>> {
+    let vocabulary = index::SEARCH.keys(con, username).await?;
+    let mut candidates: Option<HashSet<String>> = None;
+    for part in parts {
+        // every term of a phrase must be in the note, while a word can match any term it
+        // is a prefix of
+        let matching: Vec<HashSet<String>> = match part {
+            Part::Phrase(phrase) => {
+                let terms: Vec<&String> = phrase.iter().collect();
+                index::SEARCH
+                    .get_many(con, username, &terms)
+                    .await?
+                    .into_iter()
+                    .map(|ids| ids.into_iter().collect())
+                    .collect()
+            }
+            Part::Word(word) => {
+                let terms: Vec<&String> = vocabulary
+                    .iter()
+                    .filter(|t| t.starts_with(word.as_str()))
+                    .collect();
+                let postings = index::SEARCH.get_many(con, username, &terms).await?;
+                vec![postings.into_iter().flatten().collect()]
+            }
+        };
+        for ids in matching {
+            candidates = Some(match candidates {
+                Some(c) => c.intersection(&ids).cloned().collect(),
+                None => ids,
+            });
+        }
+        if candidates.as_ref().is_some_and(HashSet::is_empty) {
+            break;
+        }
+    }
+    Ok(candidates.unwrap_or_default())
+}
+
+/// Escape text for HTML
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the byte offset that is `chars` characters before (or after, if `forward`) `from`
+fn char_offset(text: &str, from: usize, chars: usize, forward: bool) -> usize {
+    if forward {
+        text[from..]
+            .char_indices()
+            .nth(chars)
+            .map_or(text.len(), |(idx, _)| from + idx)
+    } else {
+        text[..from]
+            .char_indices()
+            .rev()
+            .nth(chars.saturating_sub(1))
+            .map_or(0, |(idx, _)| idx)
+    }
+}
+
+/// Returns an escaped snippet of a note's body around the first match, with every match in
+/// it wrapped in `<mark>`
+fn snippet(body: &str, mut matches: Vec<Range<usize>>) -> String {
+    matches.sort_by_key(|range| range.start);
+    let first = matches.first().map_or(0, |range| range.start);
+    let start = self::char_offset(body, first, SNIPPET_CONTEXT, false);
+    let end = self::char_offset(body, start, SNIPPET_LEN, true);
+    let mut snippet = String::new();
+    if start != 0 {
+        snippet.push('…');
+    }
+    let mut at = start;
+    for range in matches {
+        // skip matches outside the snippet or overlapping one we've already marked
+        if range.start < at || range.end > end {
+            continue;
+        }
+        snippet.push_str(&self::escape(&body[at..range.start]));
+        snippet.push_str("<mark>");
+        snippet.push_str(&self::escape(&body[range.clone()]));
+        snippet.push_str("</mark>");
+        at = range.end;
+    }
+    snippet.push_str(&self::escape(&body[at..end]));
+    if end != body.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// A note that matched a search
+pub struct SearchResult {
+    pub note: Note,
+    /// An escaped snippet of the note, with the matches highlighted
+    pub snippet: String,
+}
+
+/// Search a user's notes. Results are newest first
+pub async fn search(
+    con: &mut Connection,
+    username: &str,
+    query: &str,
+) -> crate::JotsyResponseResult<Vec<SearchResult>> {
+    let parts = self::parse(query);
+    if parts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let candidates = self::candidates(con, username, &parts).await?;
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut ids = app::get_note_ids(con, username).await?;
+    ids.retain(|id| candidates.contains(id));
+    ids.reverse();
+    let notes = app::get_notes(con, username, &ids).await?;
+    let mut results = Vec::new();
+    for note in notes {
+        let tokens = self::tokenize(&note.body);
+        let mut matches = Vec::new();
+        // the index can't tell if the terms of a phrase are next to each other, so check
+        // every part against the note itself
+        let all = parts.iter().all(|part| {
+            let found = part.matches(&tokens);
+            let any = !found.is_empty();
+            matches.extend(found);
+            any
+        });
+        if all {
+            let snippet = self::snippet(&note.body, matches);
+            results.push(SearchResult { note, snippet });
+        }
+    }
+    Ok(results)
+}
+
+#[derive(Deserialize)]
+/// The query string for a search
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+/// `GET` for `/search`
+///
+/// This will:
+/// - Verify the session
+/// - Search the user's notes for `q`
+/// - Return the results with highlighted snippets
+pub async fn search_page(
+    Query(query): Query<SearchQuery>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let results = self::search(&mut con, &username, &query.q).await?;
+    let tz = super::settings::get_timezone(&mut con, &username).await?;
+    resp(
+        StatusCode::OK,
+        SearchPage::render_new(username, query.q, results, tz, creds.csrf_token()),
+    )
+}
+
+#[cfg(test)]
+// matches are lists of ranges, which often have just one
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::{parse, snippet, terms, tokenize, Part, SNIPPET_CONTEXT, TERM_MAX_LEN};
+
+    /// Returns the parts of a query as `(is_phrase, terms)`
+    fn parts(query: &str) -> Vec<(bool, Vec<String>)> {
+        parse(query)
+            .into_iter()
+            .map(|part| match part {
+                Part::Word(word) => (false, vec![word]),
+                Part::Phrase(phrase) => (true, phrase),
+            })
+            .collect()
+    }
+
+    fn owned(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|&term| term.to_owned()).collect()
+    }
+
+    #[test]
+    fn tokenize_lowercases_and_keeps_positions() {
+        let text = "Hello, wörld! It's 2022";
+        let tokens = tokenize(text);
+        let terms: Vec<&str> = tokens.iter().map(|(_, term)| term.as_str()).collect();
+        assert_eq!(terms, ["hello", "wörld", "it", "s", "2022"]);
+        for (range, term) in &tokens {
+            assert_eq!(text[range.clone()].to_lowercase(), *term);
+        }
+        assert!(tokenize("  ...  ").is_empty());
+    }
+
+    #[test]
+    fn long_terms_are_not_indexed() {
+        let long = "a".repeat(TERM_MAX_LEN + 1);
+        let terms = terms(&format!("short {long} short"));
+        assert_eq!(terms.into_iter().collect::<Vec<_>>(), ["short"]);
+    }
+
+    #[test]
+    fn parse_words_and_phrases() {
+        assert_eq!(
+            parts(r#"rust "Hello, World" async"#),
+            [
+                (false, owned(&["rust"])),
+                (true, owned(&["hello", "world"])),
+                (false, owned(&["async"])),
+            ]
+        );
+        // an unclosed quote is still a phrase, and empty quotes are ignored
+        assert_eq!(
+            parts(r#""" todo "buy milk"#),
+            [(false, owned(&["todo"])), (true, owned(&["buy", "milk"]))]
+        );
+        assert!(parts("  !?  ").is_empty());
+    }
+
+    #[test]
+    fn words_match_prefixes_and_phrases_match_in_order() {
+        let tokens = tokenize("The quick brown fox. Quickly, the brown dog");
+        let word = &parse("quick")[0];
+        assert_eq!(word.matches(&tokens).len(), 2);
+        let phrase = &parse(r#""the brown""#)[0];
+        assert_eq!(phrase.matches(&tokens), [30..39]);
+        let reversed = &parse(r#""brown the""#)[0];
+        assert!(reversed.matches(&tokens).is_empty());
+    }
+
+    #[test]
+    fn snippet_marks_matches_and_escapes() {
+        let body = "a <b> & fish";
+        assert_eq!(
+            snippet(body, vec![8..12, 2..5]),
+            "a <mark>&lt;b&gt;</mark> &amp; <mark>fish</mark>"
+        );
+    }
+
+    #[test]
+    fn snippet_is_cut_around_the_first_match() {
+        let body = format!("{}needle{}", "x".repeat(100), "y".repeat(300));
+        let snippet = snippet(&body, vec![100..106]);
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        let before = snippet
+            .trim_start_matches('…')
+            .split("<mark>")
+            .next()
+            .unwrap();
+        assert_eq!(before.chars().count(), SNIPPET_CONTEXT);
+        assert!(snippet.contains("<mark>needle</mark>"));
+    }
+
+    #[test]
+    fn snippet_handles_multibyte_text() {
+        let body = format!("{}ünïcödé", "é".repeat(100));
+        let start = body.find('ü').unwrap();
+        let snippet = snippet(&body, vec![start..body.len()]);
+        assert!(snippet.ends_with("<mark>ünïcödé</mark>"));
+        // overlapping matches are only marked once
+        assert_eq!(super::snippet("abc", vec![0..3, 1..2]), "<mark>abc</mark>");
+    }
+}
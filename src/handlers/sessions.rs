@@ -0,0 +1,310 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Login sessions. Like API tokens, we only store the hash of a session token:
+//! - `default:jotsysessions` maps `sha2(token) -> Session`
+//! - `default:jotsyusersessions` maps `username -> [sha2(token)]` so that we can list and end
+//!   all of a user's sessions
+
+use super::Credentials;
+use crate::{
+    templates::NoticePage,
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+use std::time::Duration;
+
+/// Don't bother updating `last_seen` more often than this (in seconds)
+const LAST_SEEN_RESOLUTION: i64 = 60;
+/// How often the reaper looks for expired sessions
+const REAP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize)]
+/// A `Session`. This is stored as JSON in Skytable and is ser/de-d as required
+pub struct Session {
+    pub username: String,
+    /// UNIX timestamp of when the session was created
+    pub created: i64,
+    /// UNIX timestamp of when the session was last used
+    pub last_seen: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Session {
+    pub fn new(username: String, creds: &Credentials) -> Self {
+        let now = util::now();
+        Self {
+            username,
+            created: now,
+            last_seen: now,
+            user_agent: creds.user_agent().map(str::to_owned),
+            ip: creds.ip().map(|ip| ip.to_string()),
+        }
+    }
+    /// Returns the UNIX timestamp after which this session is no longer valid
+    pub fn expires(&self) -> i64 {
+        let since = if util::session_sliding() {
+            self.last_seen
+        } else {
+            self.created
+        };
+        since + util::session_ttl()
+    }
+    pub fn is_expired(&self) -> bool {
+        util::now() >= self.expires()
+    }
+    pub fn created_at(&self) -> String {
+        util::format_timestamp(self.created)
+    }
+    pub fn last_seen_at(&self) -> String {
+        util::format_timestamp(self.last_seen)
+    }
+}
+
+/// A session as listed on the account page
+pub struct ActiveSession {
+    /// The hash of the session token
+    pub id: String,
+    pub session: Session,
+    /// Is this the session that is looking at the list?
+    pub current: bool,
+}
+
+/// Store a new session for the given hash of a session token
+pub async fn create(
+    con: &mut Connection,
+    hash: &str,
+    session: &Session,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_SESSIONS).await?;
+    let json = serde_json::to_string(session).unwrap();
+    con.set(hash, json).await?;
+    con.switch(crate::TABLE_USER_SESSIONS).await?;
+    util::list_push(con, &session.username, hash).await
+}
+
+/// Returns the session for the given hash of a session token, if it exists
+pub async fn get(con: &mut Connection, hash: &str) -> crate::JotsyResponseResult<Option<Session>> {
+    con.switch(crate::TABLE_SESSIONS).await?;
+    let session: Result<String, Error> = con.get(hash).await;
+    match session {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(session) => Ok(Some(session)),
+            Err(e) => {
+                // the user just has to log in again
+                log::warn!("Ignored the unreadable session `{hash}`: {e}");
+                Ok(None)
+            }
+        },
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record activity on a session. Returns true if `last_seen` was updated (and so, with sliding
+/// renewal, the session was extended)
+pub async fn touch(
+    con: &mut Connection,
+    hash: &str,
+    mut session: Session,
+) -> crate::JotsyResponseResult<bool> {
+    let now = util::now();
+    if now - session.last_seen < LAST_SEEN_RESOLUTION {
+        return Ok(false);
+    }
+    session.last_seen = now;
+    con.switch(crate::TABLE_SESSIONS).await?;
+    let json = serde_json::to_string(&session).unwrap();
+    let _: u64 = con.run_query(&query!("USET", hash, json)).await?;
+    Ok(true)
+}
+
+/// End a session. Returns false if it didn't exist
+pub async fn remove(con: &mut Connection, hash: &str) -> crate::JotsyResponseResult<bool> {
+    match self::get(con, hash).await? {
+        Some(session) => {
+            con.switch(crate::TABLE_SESSIONS).await?;
+            let del = con.del(hash).await?;
+            con.switch(crate::TABLE_USER_SESSIONS).await?;
+            util::list_remove(con, &session.username, |h| h == hash).await?;
+            Ok(del == 1)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Returns all the active sessions for a user, most recently used first. `current` is the
+/// hash of the session making the request (if any)
+pub async fn list(
+    con: &mut Connection,
+    username: &str,
+    current: Option<&str>,
+) -> crate::JotsyResponseResult<Vec<ActiveSession>> {
+    con.switch(crate::TABLE_USER_SESSIONS).await?;
+    let hashes = util::list_get(con, username).await?;
+    con.switch(crate::TABLE_SESSIONS).await?;
+    let sessions = util::mget(con, hashes.clone()).await?;
+    let mut active: Vec<ActiveSession> = hashes
+        .into_iter()
+        .zip(sessions)
+        .filter_map(|(id, session)| match serde_json::from_str(&session?) {
+            Ok(session) => Some(ActiveSession {
+                current: current == Some(id.as_str()),
+                session,
+                id,
+            }),
+            Err(e) => {
+                log::warn!("Skipped the unreadable session `{id}`: {e}");
+                None
+            }
+        })
+        .filter(|active| !active.session.is_expired())
+        .collect();
+    active.sort_by_key(|a| std::cmp::Reverse(a.session.last_seen));
+    Ok(active)
+}
+
+/// End every session for a user except `keep` (if set). Returns the number of sessions ended
+pub async fn remove_all(
+    con: &mut Connection,
+    username: &str,
+    keep: Option<&str>,
+) -> crate::JotsyResponseResult<usize> {
+    con.switch(crate::TABLE_USER_SESSIONS).await?;
+    let hashes = util::list_get(con, username).await?;
+    let (kept, ended): (Vec<String>, Vec<String>) = hashes
+        .into_iter()
+        .partition(|hash| Some(hash.as_str()) == keep);
+    if !ended.is_empty() {
+        con.switch(crate::TABLE_SESSIONS).await?;
+        con.del(&ended).await?;
+    }
+    con.switch(crate::TABLE_USER_SESSIONS).await?;
+    con.del(username).await?;
+    if !kept.is_empty() {
+        util::expect_okay(con.run_query(&query!("LSET", username, kept)).await?)?;
+    }
+    Ok(ended.len())
+}
+
+/// Purge all expired sessions. Returns the number of sessions purged
+async fn reap(pool: &AsyncPool) -> crate::JotsyResponseResult<usize> {
+    let mut con = pool.get().await?;
+    con.switch(crate::TABLE_SESSIONS).await?;
+    let hashes: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    let sessions = util::mget(&mut con, hashes.clone()).await?;
+    let mut purged = 0;
+    for (hash, session) in hashes.into_iter().zip(sessions) {
+        let expired = match session.map(|json| serde_json::from_str::<Session>(&json)) {
+            Some(Ok(session)) => session.is_expired(),
+            Some(Err(e)) => {
+                log::warn!("Skipped the unreadable session `{hash}`: {e}");
+                false
+            }
+            None => false,
+        };
+        if expired && self::remove(&mut con, &hash).await? {
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Periodically purge expired sessions. This is spawned as a background task and never returns
+pub async fn reaper(pool: AsyncPool) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match self::reap(&pool).await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {purged} expired session(s)"),
+            Err(e) => log::error!("Failed to purge expired sessions: {e}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// The form to end a session
+pub struct RevokeSessionForm {
+    id: String,
+}
+
+/// `POST` for `/account/sessions/revoke`
+///
+/// This will:
+/// - Verify the session
+/// - If the session to end is the current one, log out
+/// - Otherwise, end that session if it belongs to the user
+pub async fn revoke_session(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<RevokeSessionForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    if creds.session_hash().as_deref() == Some(form.id.as_str()) {
+        drop(con);
+        return super::logout::logout_core(creds.cookies, "Logged out successfully", db).await;
+    }
+    match self::get(&mut con, &form.id).await? {
+        Some(session) if session.username == username => {
+            self::remove(&mut con, &form.id).await?;
+            resp(
+                StatusCode::OK,
+                NoticePage::redirect_to("Ended session", "/account"),
+            )
+        }
+        _ => resp(
+            StatusCode::NOT_FOUND,
+            NoticePage::render_new("That session doesn't exist", false),
+        ),
+    }
+}
+
+/// `POST` for `/account/sessions/revoke-others`
+///
+/// This will end every session for the user except the current one
+pub async fn revoke_other_sessions(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(_): Form<util::Empty>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let current = creds.session_hash();
+    let ended = self::remove_all(&mut con, &username, current.as_deref()).await?;
+    log::info!("Ended {ended} other session(s) for `{username}`");
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to(
+            format!("Logged out of {ended} other session(s)"),
+            "/account",
+        ),
+    )
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Per-user settings. These are stored in `default:jotsysettings`, which maps
+//! `username/setting -> value`. A setting that isn't stored has its default value
+
+use chrono_tz::Tz;
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    query, RespCode,
+};
+
+/// The user's timezone (an IANA name like `Asia/Kolkata`). By default, dates are shown in
+/// the browser's timezone
+pub const TIMEZONE: &str = "timezone";
+/// Every setting, so that they can all be removed with the account
+const SETTINGS: [&str; 1] = [TIMEZONE];
+
+fn key(username: &str, setting: &str) -> String {
+    format!("{username}/{setting}")
+}
+
+/// Returns the value of a setting, if it is set
+pub async fn get(
+    con: &mut Connection,
+    username: &str,
+    setting: &str,
+) -> crate::JotsyResponseResult<Option<String>> {
+    con.switch(crate::TABLE_SETTINGS).await?;
+    let value: Result<String, Error> = con.get(self::key(username, setting)).await;
+    match value {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Set a setting, or reset it to its default if `value` is `None`
+pub async fn set(
+    con: &mut Connection,
+    username: &str,
+    setting: &str,
+    value: Option<&str>,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_SETTINGS).await?;
+    let key = self::key(username, setting);
+    match value {
+        Some(value) => {
+            let _: u64 = con.run_query(&query!("USET", key, value)).await?;
+        }
+        None => {
+            con.del(key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the user's timezone, if they have chosen one
+pub async fn get_timezone(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Option<Tz>> {
+    let tz = self::get(con, username, TIMEZONE).await?;
+    Ok(tz.and_then(|tz| tz.parse().ok()))
+}
+
+/// Remove all of a user's settings
+pub async fn remove_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    let keys: Vec<String> = SETTINGS
+        .iter()
+        .map(|setting| self::key(username, setting))
+        .collect();
+    con.switch(crate::TABLE_SETTINGS).await?;
+    con.del(keys).await?;
+    Ok(())
+}
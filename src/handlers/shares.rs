@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Public share links. Anyone with the link to a share (`/s/{slug}`) can read the note,
+//! without an account. Unlike tokens, a slug isn't a secret from its owner, so it is stored
+//! as is (and shown on the account page):
+//! - `default:jotsyshares` maps `slug -> Share`
+//! - `default:jotsyusershares` maps `username -> [slug]` to list and revoke them
+
+use super::{
+    app::{self, rfc3339},
+    Credentials,
+};
+use crate::{
+    templates::{NoticePage, SharedNote},
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form, Path},
+    http::StatusCode,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    RespCode,
+};
+
+/// The length of a slug. Like session tokens, these are random enough that they can't be
+/// guessed, but only use characters that are safe in a URL
+const SLUG_LEN: usize = 32;
+/// Shares can't be set to last longer than this (in days)
+const MAX_EXPIRY_DAYS: i64 = 3650;
+/// The number of characters of a note shown as its preview on the account page
+const PREVIEW_LEN: usize = 60;
+
+#[derive(Serialize, Deserialize)]
+/// A `Share`. This is stored as JSON in Skytable and is ser/de-d as required
+pub struct Share {
+    pub username: String,
+    /// The ID of the shared note
+    pub note: String,
+    #[serde(with = "rfc3339")]
+    pub created: DateTime<Utc>,
+    /// When the link stops working, if it ever does
+    #[serde(default, with = "rfc3339::option")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Share {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| Utc::now() >= expires)
+    }
+}
+
+/// A share as listed on the account page
+pub struct ActiveShare {
+    pub slug: String,
+    pub share: Share,
+    /// The start of the shared note, or `None` if the note is in the trash
+    pub preview: Option<String>,
+}
+
+/// Returns the share for the given slug, if it exists
+async fn get(con: &mut Connection, slug: &str) -> crate::JotsyResponseResult<Option<Share>> {
+    con.switch(crate::TABLE_SHARES).await?;
+    let share: Result<String, Error> = con.get(slug).await;
+    match share {
+        Ok(json) => Ok(Some(serde_json::from_str(&json).unwrap())),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Revoke a share. Returns false if it didn't exist
+async fn remove(con: &mut Connection, slug: &str) -> crate::JotsyResponseResult<bool> {
+    match self::get(con, slug).await? {
+        Some(share) => {
+            con.switch(crate::TABLE_SHARES).await?;
+            let del = con.del(slug).await?;
+            con.switch(crate::TABLE_USER_SHARES).await?;
+            util::list_remove(con, &share.username, |s| s == slug).await?;
+            Ok(del == 1)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Returns the first line of a note, shortened to a preview
+fn preview(body: &str) -> String {
+    let line = body
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    match line.char_indices().nth(PREVIEW_LEN) {
+        Some((idx, _)) => format!("{}…", &line[..idx]),
+        None => line.to_owned(),
+    }
+}
+
+/// Returns all the shares of a user that haven't expired, newest first. Expired shares are
+/// revoked along the way
+pub async fn list(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<ActiveShare>> {
+    con.switch(crate::TABLE_USER_SHARES).await?;
+    let slugs = util::list_get(con, username).await?;
+    con.switch(crate::TABLE_SHARES).await?;
+    let shares = util::mget(con, slugs.clone()).await?;
+    let mut active = Vec::new();
+    for (slug, share) in slugs.into_iter().zip(shares) {
+        let share: Share = match share {
+            Some(json) => serde_json::from_str(&json).unwrap(),
+            None => continue,
+        };
+        if share.is_expired() {
+            self::remove(con, &slug).await?;
+            continue;
+        }
+        active.push(ActiveShare {
+            slug,
+            share,
+            preview: None,
+        });
+    }
+    let ids: Vec<String> = active.iter().map(|a| a.share.note.clone()).collect();
+    let notes = app::get_notes(con, username, &ids).await?;
+    for active in &mut active {
+        active.preview = notes
+            .iter()
+            .find(|note| note.id == active.share.note && note.trashed.is_none())
+            .map(|note| self::preview(&note.body));
+    }
+    active.reverse();
+    Ok(active)
+}
+
+/// Revoke every share of a note
+pub async fn remove_for_note(
+    con: &mut Connection,
+    username: &str,
+    id: &str,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_USER_SHARES).await?;
+    let slugs = util::list_get(con, username).await?;
+    con.switch(crate::TABLE_SHARES).await?;
+    let shares = util::mget(con, slugs.clone()).await?;
+    for (slug, share) in slugs.iter().zip(shares) {
+        let of_note =
+            share.is_some_and(|json| serde_json::from_str::<Share>(&json).unwrap().note == id);
+        if of_note {
+            self::remove(con, slug).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Revoke every share of a user
+pub async fn remove_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_USER_SHARES).await?;
+    let slugs = util::list_get(con, username).await?;
+    if !slugs.is_empty() {
+        con.switch(crate::TABLE_SHARES).await?;
+        con.del(&slugs).await?;
+    }
+    con.switch(crate::TABLE_USER_SHARES).await?;
+    con.del(username).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+/// The form to share a note
+pub struct ShareForm {
+    /// The number of days the link works for. Empty means until it is revoked
+    #[serde(default)]
+    expires: String,
+}
+
+/// `POST` for `/notes/:id/share`
+///
+/// This will:
+/// - Verify the session
+/// - Create a share link for the note (if it isn't in the trash)
+/// - Return the path of the link
+pub async fn create_share(
+    Path(id): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<ShareForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let expires = match form.expires.trim() {
+        "" => None,
+        days => match days.parse::<i64>() {
+            Ok(days) if (1..=MAX_EXPIRY_DAYS).contains(&days) => {
+                Some(Utc::now() + Duration::days(days))
+            }
+            _ => {
+                return resp(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Links must expire after between 1 and {MAX_EXPIRY_DAYS} days"),
+                )
+            }
+        },
+    };
+    if app::get_live_note(&mut con, &username, &id)
+        .await?
+        .is_none()
+    {
+        return app::note_not_found();
+    }
+    let slug = util::random_alphanumeric(SLUG_LEN);
+    let share = Share {
+        username: username.clone(),
+        note: id,
+        created: Utc::now(),
+        expires,
+    };
+    con.switch(crate::TABLE_SHARES).await?;
+    con.set(&slug, serde_json::to_string(&share).unwrap())
+        .await?;
+    con.switch(crate::TABLE_USER_SHARES).await?;
+    util::list_push(&mut con, &username, &slug).await?;
+    log::info!("Shared note `{}` for `{username}`", share.note);
+    resp(StatusCode::CREATED, format!("/s/{slug}"))
+}
+
+/// Returns the page for a share that doesn't exist (anymore)
+fn share_not_found() -> crate::JotsyResponse {
+    resp(
+        StatusCode::NOT_FOUND,
+        NoticePage::render_new("This link has expired or was revoked", false),
+    )
+}
+
+/// `GET` for `/s/:slug`
+///
+/// This will:
+/// - Find the share (no session is needed)
+/// - Revoke it if it has expired
+/// - Return the shared note, read-only, if it still exists, isn't in the trash and its owner
+///   hasn't been disabled
+pub async fn shared_note(
+    Path(slug): Path<String>,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let share = match self::get(&mut con, &slug).await? {
+        Some(share) if share.is_expired() => {
+            self::remove(&mut con, &slug).await?;
+            return self::share_not_found();
+        }
+        Some(share) => share,
+        None => return self::share_not_found(),
+    };
+    if super::admin::is_disabled(&mut con, &share.username).await? {
+        return self::share_not_found();
+    }
+    match app::get_live_note(&mut con, &share.username, &share.note).await? {
+        Some(note) => resp(StatusCode::OK, SharedNote::render_new(note)),
+        None => self::share_not_found(),
+    }
+}
+
+#[derive(Deserialize)]
+/// The form to revoke a share
+pub struct RevokeShareForm {
+    slug: String,
+}
+
+/// `POST` for `/account/shares/revoke`
+pub async fn revoke_share(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<RevokeShareForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    match self::get(&mut con, &form.slug).await? {
+        Some(share) if share.username == username => {
+            self::remove(&mut con, &form.slug).await?;
+            log::info!("Revoked a share of note `{}` for `{username}`", share.note);
+            resp(
+                StatusCode::OK,
+                NoticePage::redirect_to("Revoked link", "/account"),
+            )
+        }
+        _ => resp(
+            StatusCode::NOT_FOUND,
+            NoticePage::render_new("That link doesn't exist", false),
+        ),
+    }
+}
@@ -14,6 +14,7 @@
  * limitations under the License.
 */
 
+use super::Credentials;
 use crate::{
     error::ResponseError,
     templates::{NoticePage, SignupPage},
@@ -51,13 +52,17 @@ pub async fn signup_get(cookies: Cookies) -> Html<String> {
 /// Signup flow:
 /// 1. Hash the password (TODO: report error if vpassword != password)
 /// 2. Now `set` username->hashed passowrd
-/// a. If this fails, username is taken
-/// b. If this succeeds, username is available and we've created an user
-/// 3. Now call super::login::authenticate(username, &mut cookies, &mut connection)
+///    a. If this fails, username is taken
+///    b. If this succeeds, username is available and we've created an user (who is made an
+///    admin if they are the first one)
+/// 3. Now call super::login::authenticate(username, &mut creds, &mut connection)
+///
+/// If `JOTSY_HIDE_TAKEN_USERNAMES` is set, we never say whether the username was taken: the
+/// user gets the same page either way and has to log in to find out
 ///
 pub async fn signup(
     Form(data): Form<SignupForm>,
-    mut cookies: Cookies,
+    mut creds: Credentials,
     Extension(db): Extension<AsyncPool>,
 ) -> crate::JotsyResponse {
     // do a double check on the data; never trust the client
@@ -93,8 +98,12 @@ pub async fn signup(
         Ok(created_new) if created_new => {
             // cool, we did well
             log::info!("New user `{uname}` created.", uname = data.username);
-            let ret =
-                super::login::authenticate(data.username.clone(), &mut cookies, &mut con).await?;
+            super::admin::bootstrap(&mut con, &data.username).await?;
+            let ret = if util::hide_taken_usernames() {
+                self::maybe_created()
+            } else {
+                super::login::authenticate(data.username.clone(), &mut creds, &mut con).await
+            }?;
             con.switch(crate::TABLE_NOTES).await?;
             // attempt to create an empty list
             let query = query!("LSET", data.username);
@@ -106,6 +115,7 @@ pub async fn signup(
                 ))
             }
         }
+        Ok(_) if util::hide_taken_usernames() => self::maybe_created(),
         Ok(_) => {
             // nope, username is taken
             resp(
@@ -121,6 +131,17 @@ pub async fn signup(
     }
 }
 
+/// Returns the page for a signup when we don't say whether the username was taken
+fn maybe_created() -> crate::JotsyResponse {
+    resp(
+        StatusCode::OK,
+        NoticePage::render_new(
+            "If that username was available, your account has been created. Log in to continue",
+            false,
+        ),
+    )
+}
+
 /// `GET` for `/signup` for cases where signups are disabled
 pub async fn no_signup() -> crate::JotsyResponse {
     resp(
@@ -0,0 +1,183 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Tags. A note is tagged with every `#hashtag` in its body. The tags are stored in the note
+//! and in an [index](super::index) of the notes for each tag (in `default:jotsytags`) along
+//! with each user's tags (in `default:jotsyusertags`)
+
+use super::{
+    app::{self, NoteList, View},
+    index, Credentials,
+};
+use crate::{templates::NoticePage, util::resp};
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+};
+use skytable::{aio::Connection, pool::AsyncPool};
+use std::collections::BTreeSet;
+
+/// Tags longer than this are ignored
+const TAG_MAX_LEN: usize = 32;
+
+/// Returns the tag (lowercased) if `tag` is a valid tag: letters, digits, `_` or `-` (but not
+/// only digits, so that `#1` isn't a tag)
+pub(super) fn normalize(tag: &str) -> Option<String> {
+    let valid = !tag.is_empty()
+        && tag.chars().count() <= TAG_MAX_LEN
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        && !tag.chars().all(|c| c.is_ascii_digit());
+    valid.then(|| tag.to_lowercase())
+}
+
+/// Returns the tags in the body of a note. A tag is a `#` at the start of a word followed by a
+/// valid tag. Tags are case insensitive and anything in a fenced code block is ignored
+pub fn extract(body: &str) -> BTreeSet<String> {
+    let mut tags = BTreeSet::new();
+    let mut in_code = false;
+    for line in body.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        let mut prev = None;
+        for (idx, c) in line.char_indices() {
+            let at_word_start = prev.is_none_or(|p: char| p.is_whitespace() || p == '(');
+            prev = Some(c);
+            if c != '#' || !at_word_start {
+                continue;
+            }
+            let rest = &line[idx + 1..];
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(rest.len());
+            tags.extend(self::normalize(&rest[..len]));
+        }
+    }
+    tags
+}
+
+/// A tag, with the number of notes that have it
+pub struct TagCount {
+    pub name: String,
+    pub count: u64,
+}
+
+/// Returns all of a user's tags (alphabetically), with the number of notes for each
+pub async fn get_tags(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<TagCount>> {
+    let mut names = index::TAGS.keys(con, username).await?;
+    names.sort_unstable();
+    let mut tags = Vec::with_capacity(names.len());
+    for name in names {
+        let count = index::TAGS.count(con, username, &name).await?;
+        tags.push(TagCount { name, count });
+    }
+    Ok(tags)
+}
+
+/// `GET` for `/tags/:tag`
+///
+/// This will:
+/// - Verify the session
+/// - Return the app, showing the first page of the notes with the tag (most recently tagged
+///   first)
+pub async fn tag_page(
+    Path(tag): Path<String>,
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    // this is also shown unescaped on the page, so only accept valid tags
+    let tag = match self::normalize(tag.trim_start_matches('#')) {
+        Some(tag) => tag,
+        None => {
+            return resp(
+                StatusCode::NOT_FOUND,
+                NoticePage::render_new("That isn't a tag", false),
+            )
+        }
+    };
+    let page = app::get_page(&mut con, &username, NoteList::Tag(&tag), None)
+        .await?
+        .expect("no cursor");
+    app::render_app(&mut con, &creds, username, page, View::Tag(tag)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, normalize, TAG_MAX_LEN};
+
+    fn tags(body: &str) -> Vec<String> {
+        extract(body).into_iter().collect()
+    }
+
+    #[test]
+    fn normalize_accepts_only_valid_tags() {
+        assert_eq!(normalize("Rust").as_deref(), Some("rust"));
+        assert_eq!(normalize("to-do_list").as_deref(), Some("to-do_list"));
+        assert_eq!(normalize("2022-plans").as_deref(), Some("2022-plans"));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("123"), None);
+        assert_eq!(normalize("a b"), None);
+        assert_eq!(normalize("<script>"), None);
+        assert_eq!(normalize(&"a".repeat(TAG_MAX_LEN + 1)), None);
+        assert!(normalize(&"a".repeat(TAG_MAX_LEN)).is_some());
+    }
+
+    #[test]
+    fn punctuation_ends_a_tag() {
+        assert_eq!(
+            tags("Done with #work, then #home. (#errands) #why?"),
+            ["errands", "home", "why", "work"]
+        );
+    }
+
+    #[test]
+    fn hashes_inside_words_are_not_tags() {
+        assert_eq!(
+            tags("C# and issue#12 and a#b and ##double"),
+            Vec::<String>::new()
+        );
+        assert_eq!(tags("#1 is a number, #1st is a tag"), ["1st"]);
+    }
+
+    #[test]
+    fn unicode_tags() {
+        assert_eq!(tags("#Café #日本語 #ÉTÉ"), ["café", "été", "日本語"]);
+    }
+
+    #[test]
+    fn tags_are_unique_and_case_insensitive() {
+        assert_eq!(tags("#Rust #rust\n#RUST and #go"), ["go", "rust"]);
+    }
+
+    #[test]
+    fn code_blocks_are_ignored() {
+        let body = "#shown\n```\n#include <stdio.h>\n```\n#also-shown";
+        assert_eq!(tags(body), ["also-shown", "shown"]);
+        // an unclosed block hides everything after it
+        assert_eq!(tags("#before\n```sh\n# comment #hidden"), ["before"]);
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Personal API tokens. These are long-lived, named tokens that non-browser clients send
+//! with an `Authorization: Bearer` header. Like session tokens, we only ever store their hash:
+//! - `default:jotsyapitokens` maps `sha2(token) -> username` to verify a token
+//! - `default:jotsyusertokens` maps `username -> [ApiToken]` to list and revoke them
+
+use super::Credentials;
+use crate::{
+    templates::NoticePage,
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    RespCode,
+};
+
+const API_TOKEN_PREFIX: &str = "jotsy_";
+const API_TOKEN_LEN: usize = 40;
+const API_TOKEN_ID_LEN: usize = 8;
+const API_TOKEN_NAME_MAX_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+/// An API token as listed on the account page. This is stored as JSON in the user's list of
+/// tokens
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    #[serde(with = "created")]
+    pub created: DateTime<Utc>,
+    hash: String,
+}
+
+/// Token dates are stored like note dates (see [`super::app::rfc3339`]). Tokens created by
+/// older versions have a display string instead, like `March 01, 2022` in the server's
+/// timezone, which is taken to be midnight on that day
+mod created {
+    use super::super::app::rfc3339;
+    use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    /// The format older versions stored token dates in
+    const LEGACY_FORMAT: &str = "%B %d, %Y";
+
+    pub use rfc3339::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+        let date = String::deserialize(d)?;
+        if let Some(date) = crate::util::parse_date(&date) {
+            return Ok(date);
+        }
+        NaiveDate::parse_from_str(&date, LEGACY_FORMAT)
+            .ok()
+            .and_then(|day| day.and_hms_opt(0, 0, 0))
+            .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+            .map(|date| date.with_timezone(&Utc))
+            .ok_or_else(|| D::Error::custom(format!("invalid date `{date}`")))
+    }
+}
+
+/// Returns all the API tokens for the given user
+pub async fn get_tokens(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<ApiToken>> {
+    con.switch(crate::TABLE_USER_TOKENS).await?;
+    let tokens = util::list_get(con, username).await?;
+    Ok(tokens
+        .iter()
+        .map(|token| serde_json::from_str(token).unwrap())
+        .collect())
+}
+
+/// Verify an API token, returning the username it belongs to if it's valid
+pub async fn verify_token(
+    con: &mut Connection,
+    token: &str,
+) -> crate::JotsyResponseResult<Option<String>> {
+    con.switch(crate::TABLE_API_TOKENS).await?;
+    let username: Result<String, Error> = con.get(util::sha2(token)).await;
+    match username {
+        Ok(username) => Ok(Some(username)),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Revoke every API token for the given user
+pub async fn revoke_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    let tokens = self::get_tokens(con, username).await?;
+    if !tokens.is_empty() {
+        con.switch(crate::TABLE_API_TOKENS).await?;
+        let hashes: Vec<String> = tokens.into_iter().map(|token| token.hash).collect();
+        con.del(hashes).await?;
+    }
+    con.switch(crate::TABLE_USER_TOKENS).await?;
+    con.del(username).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+/// The form to create a new API token
+pub struct NewTokenForm {
+    name: String,
+}
+
+/// `POST` for `/account/tokens`
+///
+/// This will:
+/// - Verify the session
+/// - Generate a new token and store its hash
+/// - Return the account page, showing the token this one time
+pub async fn create_token(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<NewTokenForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let name = form.name.trim();
+    if name.is_empty() || name.len() > API_TOKEN_NAME_MAX_LEN {
+        return resp(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            NoticePage::render_new("Token names must have between 1 and 64 characters", false),
+        );
+    }
+    let token = format!(
+        "{API_TOKEN_PREFIX}{}",
+        util::random_alphanumeric(API_TOKEN_LEN)
+    );
+    let record = ApiToken {
+        id: util::random_alphanumeric(API_TOKEN_ID_LEN),
+        name: name.to_owned(),
+        created: Utc::now(),
+        hash: util::sha2(&token),
+    };
+    con.switch(crate::TABLE_API_TOKENS).await?;
+    con.set(&record.hash, &username).await?;
+    con.switch(crate::TABLE_USER_TOKENS).await?;
+    util::list_push(&mut con, &username, serde_json::to_string(&record).unwrap()).await?;
+    log::info!("Created API token `{}` for `{username}`", record.name);
+    super::account::account_page(&mut con, &creds, username, Some(token)).await
+}
+
+#[derive(Deserialize)]
+/// The form to revoke an API token
+pub struct RevokeTokenForm {
+    id: String,
+}
+
+/// `POST` for `/account/tokens/revoke`
+pub async fn revoke_token(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<RevokeTokenForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    con.switch(crate::TABLE_USER_TOKENS).await?;
+    let removed = util::list_remove(&mut con, &username, |token| {
+        serde_json::from_str::<ApiToken>(token).unwrap().id == form.id
+    })
+    .await?;
+    match removed {
+        Some(token) => {
+            let token: ApiToken = serde_json::from_str(&token).unwrap();
+            con.switch(crate::TABLE_API_TOKENS).await?;
+            con.del(&token.hash).await?;
+            log::info!("Revoked API token `{}` for `{username}`", token.name);
+            resp(
+                StatusCode::OK,
+                NoticePage::redirect_to("Revoked token", "/account"),
+            )
+        }
+        None => resp(
+            StatusCode::NOT_FOUND,
+            NoticePage::render_new("That token doesn't exist", false),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiToken;
+    use chrono::{DateTime, Local, TimeZone, Utc};
+
+    fn token_json(created: &str) -> String {
+        format!(r#"{{"id":"abcd1234","name":"cli","created":"{created}","hash":"x"}}"#)
+    }
+
+    #[test]
+    fn dates_are_stored_in_utc() {
+        let token: ApiToken = serde_json::from_str(&token_json("2022-03-01T21:30:00Z")).unwrap();
+        assert_eq!(
+            token.created,
+            "2022-03-01T21:30:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        let json = serde_json::to_string(&token).unwrap();
+        assert!(json.contains(r#""created":"2022-03-01T21:30:00Z""#));
+    }
+
+    #[test]
+    fn legacy_dates_are_read() {
+        let token: ApiToken = serde_json::from_str(&token_json("March 01, 2022")).unwrap();
+        let midnight = Local
+            .with_ymd_and_hms(2022, 3, 1, 0, 0, 0)
+            .earliest()
+            .unwrap();
+        assert_eq!(token.created, midnight.with_timezone(&Utc));
+        assert!(serde_json::from_str::<ApiToken>(&token_json("sometime")).is_err());
+    }
+}
@@ -0,0 +1,543 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Two-factor authentication with TOTP (the codes from an authenticator app). Users with it
+//! turned on have to enter a code (or a recovery code) after their password before they are
+//! logged in. We keep:
+//! - `default:jotsytotp` mapping `username -> TotpSecret`. A secret is stored (but not
+//!   enabled) as soon as a user starts setting it up, and enabled once they confirm a code
+//! - `default:jotsyrecoverycodes` mapping `username -> [hash(recovery code)]`. Each code can
+//!   be used once instead of a TOTP code
+//! - `default:jotsypendinglogins` mapping `hash(token) -> PendingLogin` for logins that got
+//!   the password right and are waiting for the code
+
+use super::Credentials;
+use crate::{
+    error::ResponseError,
+    templates::{NoticePage, TotpLoginPage, TotpSetup},
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use qrcode::{render::svg, QrCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+use std::time::Duration;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// The name authenticator apps show for our codes
+const ISSUER: &str = "Jotsy";
+/// The length of a secret (in bytes). RFC 4226 recommends 160 bits
+const SECRET_LEN: usize = 20;
+const DIGITS: usize = 6;
+/// How long a code is valid for (in seconds)
+const STEP: u64 = 30;
+/// The number of recovery codes a user gets when they turn on two-factor authentication
+const RECOVERY_CODES: usize = 10;
+/// The length of a recovery code, not counting the dash in the middle
+const RECOVERY_CODE_LEN: usize = 10;
+/// How long a user has to enter their code after entering their password (in seconds)
+const PENDING_LOGIN_TTL: i64 = 5 * 60;
+/// How many wrong codes can be entered for a login before it has to be started over
+const MAX_ATTEMPTS: u8 = 5;
+/// The length of the token that identifies a pending login
+const PENDING_TOKEN_LEN: usize = 32;
+/// How often the reaper looks for pending logins that have expired
+const REAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize)]
+/// A user's TOTP secret. This is stored as JSON in Skytable and is ser/de-d as required
+pub struct TotpSecret {
+    /// The secret, base32 encoded (as authenticator apps expect it)
+    secret: String,
+    /// Has the user confirmed a code for this secret?
+    enabled: bool,
+    /// The last time step a code was accepted for, so that a code can't be used twice
+    #[serde(default)]
+    last_step: u64,
+}
+
+impl TotpSecret {
+    /// Returns a new (not yet enabled) random secret
+    fn new() -> Self {
+        let bytes: [u8; SECRET_LEN] = rand::thread_rng().gen();
+        let secret = match Secret::Raw(bytes.to_vec()).to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => unreachable!("secret was just encoded"),
+        };
+        Self {
+            secret,
+            enabled: false,
+            last_step: 0,
+        }
+    }
+    fn totp(&self) -> TOTP {
+        let secret = Secret::Encoded(self.secret.clone()).to_bytes().unwrap();
+        TOTP::new_unchecked(Algorithm::SHA1, DIGITS, 1, STEP, secret)
+    }
+    /// Returns the `otpauth://` URI that authenticator apps can add this secret from
+    fn uri(&self, username: &str) -> String {
+        format!(
+            "otpauth://totp/{ISSUER}:{username}?secret={}&issuer={ISSUER}",
+            self.secret
+        )
+    }
+    /// Check a code. Codes for the current time step and the ones next to it (to allow for
+    /// clock drift) are accepted, unless an earlier code was already accepted for that step.
+    /// Returns the step that the code was for if it is valid
+    fn verify(&self, code: &str) -> Option<u64> {
+        let totp = self.totp();
+        let current = util::now() as u64 / STEP;
+        (current.saturating_sub(1)..=current + 1)
+            .filter(|step| *step > self.last_step)
+            .find(|step| totp.generate(step * STEP) == code)
+    }
+}
+
+/// Returns the user's TOTP secret, if they have one (even if it isn't enabled yet)
+async fn get(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Option<TotpSecret>> {
+    con.switch(crate::TABLE_TOTP).await?;
+    let secret: Result<String, Error> = con.get(username).await;
+    match secret {
+        // never treat this as missing, which would turn two-factor authentication off
+        Ok(json) => serde_json::from_str(&json).map(Some).map_err(|e| {
+            ResponseError::CorruptRecord(format!("the TOTP secret of `{username}`: {e}"))
+        }),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn set(
+    con: &mut Connection,
+    username: &str,
+    secret: &TotpSecret,
+) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_TOTP).await?;
+    let json = serde_json::to_string(secret).unwrap();
+    let _: u64 = con.run_query(&query!("USET", username, json)).await?;
+    Ok(())
+}
+
+/// Returns true if the user has turned on two-factor authentication
+pub async fn is_enabled(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<bool> {
+    Ok(self::get(con, username)
+        .await?
+        .is_some_and(|secret| secret.enabled))
+}
+
+/// Returns the number of recovery codes the user has left, or `None` if they haven't turned on
+/// two-factor authentication
+pub async fn status(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Option<u64>> {
+    if !self::is_enabled(con, username).await? {
+        return Ok(None);
+    }
+    con.switch(crate::TABLE_RECOVERY_CODES).await?;
+    Ok(Some(util::list_len(con, username).await?))
+}
+
+/// Turn off two-factor authentication for a user, removing their secret and recovery codes
+pub async fn remove_all(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<()> {
+    con.switch(crate::TABLE_TOTP).await?;
+    con.del(username).await?;
+    con.switch(crate::TABLE_RECOVERY_CODES).await?;
+    con.del(username).await?;
+    Ok(())
+}
+
+/// Recovery codes are typed in by hand, so case, spaces and dashes don't matter
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|ch| ch.to_ascii_lowercase())
+        .collect()
+}
+
+/// Replace the user's recovery codes with new ones. Only their hashes are stored, so the
+/// codes are returned to be shown to the user once
+async fn new_recovery_codes(
+    con: &mut Connection,
+    username: &str,
+) -> crate::JotsyResponseResult<Vec<String>> {
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = util::random_alphanumeric(RECOVERY_CODE_LEN).to_ascii_lowercase();
+            let (first, second) = code.split_at(RECOVERY_CODE_LEN / 2);
+            format!("{first}-{second}")
+        })
+        .collect();
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| util::sha2(self::normalize_recovery_code(code)))
+        .collect();
+    con.switch(crate::TABLE_RECOVERY_CODES).await?;
+    con.del(username).await?;
+    util::expect_okay(con.run_query(&query!("LSET", username, hashes)).await?)?;
+    Ok(codes)
+}
+
+/// Use up a recovery code. Returns false if the user doesn't have this code
+async fn use_recovery_code(
+    con: &mut Connection,
+    username: &str,
+    code: &str,
+) -> crate::JotsyResponseResult<bool> {
+    let hash = util::sha2(self::normalize_recovery_code(code));
+    con.switch(crate::TABLE_RECOVERY_CODES).await?;
+    Ok(util::list_remove(con, username, |h| h == hash)
+        .await?
+        .is_some())
+}
+
+/// Check a TOTP code (or a recovery code) for a user with two-factor authentication turned
+/// on. An accepted TOTP code can't be used again and an accepted recovery code is used up
+async fn check_code(
+    con: &mut Connection,
+    username: &str,
+    code: &str,
+) -> crate::JotsyResponseResult<bool> {
+    let code = code.trim();
+    if code.len() == DIGITS && code.chars().all(|ch| ch.is_ascii_digit()) {
+        let mut secret = match self::get(con, username).await? {
+            Some(secret) if secret.enabled => secret,
+            _ => return Ok(false),
+        };
+        match secret.verify(code) {
+            Some(step) => {
+                secret.last_step = step;
+                self::set(con, username, &secret).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    } else if self::use_recovery_code(con, username, code).await? {
+        log::info!("`{username}` logged in with a recovery code");
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+/// A login that is waiting for its second factor. This is stored as JSON in Skytable and is
+/// ser/de-d as required
+pub struct PendingLogin {
+    username: String,
+    /// When this login has to be started over (a UNIX timestamp)
+    expires: i64,
+    /// The number of wrong codes entered so far
+    attempts: u8,
+}
+
+impl PendingLogin {
+    fn is_expired(&self) -> bool {
+        util::now() >= self.expires
+    }
+}
+
+/// Start a login for a user who got their password right but still has to enter a code.
+/// Returns the page that asks for the code
+pub(super) async fn start_login(con: &mut Connection, username: String) -> crate::JotsyResponse {
+    let token = util::random_alphanumeric(PENDING_TOKEN_LEN);
+    let pending = PendingLogin {
+        username,
+        expires: util::now() + PENDING_LOGIN_TTL,
+        attempts: 0,
+    };
+    con.switch(crate::TABLE_PENDING_LOGINS).await?;
+    con.set(util::sha2(&token), serde_json::to_string(&pending).unwrap())
+        .await?;
+    resp(StatusCode::OK, TotpLoginPage::render_new(token, false))
+}
+
+/// Purge all expired pending logins. Returns the number of pending logins purged
+async fn reap(pool: &AsyncPool) -> crate::JotsyResponseResult<usize> {
+    let mut con = pool.get().await?;
+    con.switch(crate::TABLE_PENDING_LOGINS).await?;
+    let hashes: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    let pending = util::mget(&mut con, hashes.clone()).await?;
+    let expired: Vec<String> = hashes
+        .into_iter()
+        .zip(pending)
+        .filter(|(hash, pending)| {
+            pending
+                .as_ref()
+                .is_some_and(|json| match serde_json::from_str::<PendingLogin>(json) {
+                    Ok(pending) => pending.is_expired(),
+                    Err(e) => {
+                        log::warn!("Skipped the unreadable pending login `{hash}`: {e}");
+                        false
+                    }
+                })
+        })
+        .map(|(hash, _)| hash)
+        .collect();
+    if !expired.is_empty() {
+        con.del(&expired).await?;
+    }
+    Ok(expired.len())
+}
+
+/// Periodically purge pending logins that were never finished. This is spawned as a
+/// background task and never returns
+pub async fn reaper(pool: AsyncPool) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match self::reap(&pool).await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {purged} expired pending login(s)"),
+            Err(e) => log::error!("Failed to purge expired pending logins: {e}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// The second step of the login form
+pub struct TotpLoginForm {
+    /// The token of the pending login
+    token: String,
+    /// A TOTP code or a recovery code
+    code: String,
+}
+
+/// `POST` for `/login/2fa`
+///
+/// This will:
+/// - Find the pending login for the token (which must not have expired)
+/// - Refuse to check the code if there were too many failed logins to the account or from the
+///   client's address recently
+/// - Check the code. If it is wrong, record the failure and ask for it again, unless there have
+///   been too many wrong codes, in which case the login has to be started over
+/// - If it is right, end the pending login and call `authenticate`
+pub async fn login_2fa(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<TotpLoginForm>,
+) -> crate::JotsyResponseResult<Response> {
+    let mut con = db.get().await?;
+    let hash = util::sha2(&form.token);
+    con.switch(crate::TABLE_PENDING_LOGINS).await?;
+    let pending: Result<String, Error> = con.get(&hash).await;
+    let mut pending: PendingLogin = match pending.map(|json| serde_json::from_str(&json)) {
+        Ok(Ok(pending)) => pending,
+        Ok(Err(e)) => {
+            // the password has to be entered again, just like for an expired login
+            log::warn!("Ended the unreadable pending login `{hash}`: {e}");
+            con.del(&hash).await?;
+            return Ok(self::login_expired());
+        }
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => {
+            return Ok(self::login_expired())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if pending.is_expired() {
+        con.del(&hash).await?;
+        return Ok(self::login_expired());
+    }
+    if let Some(wait) = super::lockout::check(&mut con, &pending.username, creds.ip()).await? {
+        return Ok(super::login::rate_limited(wait));
+    }
+    if self::check_code(&mut con, &pending.username, &form.code).await? {
+        con.switch(crate::TABLE_PENDING_LOGINS).await?;
+        con.del(&hash).await?;
+        let ret = super::login::authenticate(pending.username, &mut creds, &mut con).await;
+        return Ok(ret?.into_response());
+    }
+    super::lockout::record_failure(&mut con, &pending.username, creds.ip()).await?;
+    pending.attempts += 1;
+    con.switch(crate::TABLE_PENDING_LOGINS).await?;
+    if pending.attempts >= MAX_ATTEMPTS {
+        con.del(&hash).await?;
+        log::warn!(
+            "Ended a login for `{}` after {MAX_ATTEMPTS} wrong codes",
+            pending.username
+        );
+        let ret = resp(
+            StatusCode::UNAUTHORIZED,
+            NoticePage::new_redirect("Too many wrong codes. Please log in again"),
+        );
+        return Ok(ret?.into_response());
+    }
+    con.update(&hash, serde_json::to_string(&pending).unwrap())
+        .await?;
+    let ret = resp(
+        StatusCode::UNAUTHORIZED,
+        TotpLoginPage::render_new(form.token, true),
+    );
+    Ok(ret?.into_response())
+}
+
+/// Returns the page for a pending login that has expired (or never existed)
+fn login_expired() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Html(NoticePage::new_redirect(
+            "This login has expired. Please log in again",
+        )),
+    )
+        .into_response()
+}
+
+/// Returns the setup page for a secret, with a QR code for authenticator apps to scan
+fn setup_page(creds: &Credentials, username: String, secret: &TotpSecret, failed: bool) -> String {
+    let qr = QrCode::new(secret.uri(&username))
+        .unwrap()
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    TotpSetup::render_new(
+        username,
+        qr,
+        secret.secret.clone(),
+        failed,
+        creds.csrf_token(),
+    )
+}
+
+/// `GET` for `/account/2fa`
+///
+/// This will:
+/// - Verify the session
+/// - Create a new secret for the user (unless two-factor authentication is already on)
+/// - Return the setup page with a QR code for the secret and a form to confirm a code
+pub async fn setup_get(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    if self::is_enabled(&mut con, &username).await? {
+        return resp(
+            StatusCode::OK,
+            NoticePage::redirect_to("Two-factor authentication is already on", "/account"),
+        );
+    }
+    let secret = TotpSecret::new();
+    self::set(&mut con, &username, &secret).await?;
+    resp(
+        StatusCode::OK,
+        self::setup_page(&creds, username, &secret, false),
+    )
+}
+
+#[derive(Deserialize)]
+/// The form to confirm a code when setting up two-factor authentication
+pub struct SetupForm {
+    code: String,
+}
+
+/// `POST` for `/account/2fa`
+///
+/// This will:
+/// - Verify the session
+/// - Check the code against the secret being set up. If it is wrong, show the setup page again
+/// - Turn on two-factor authentication and show the user their recovery codes (only once)
+pub async fn setup_post(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<SetupForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let mut secret = match self::get(&mut con, &username).await? {
+        Some(secret) if !secret.enabled => secret,
+        Some(_) => {
+            return resp(
+                StatusCode::OK,
+                NoticePage::redirect_to("Two-factor authentication is already on", "/account"),
+            )
+        }
+        None => {
+            return resp(
+                StatusCode::NOT_FOUND,
+                NoticePage::redirect_to(
+                    "Start setting up two-factor authentication first",
+                    "/account/2fa",
+                ),
+            )
+        }
+    };
+    let step = match secret.verify(form.code.trim()) {
+        Some(step) => step,
+        None => {
+            return resp(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                self::setup_page(&creds, username, &secret, true),
+            )
+        }
+    };
+    secret.enabled = true;
+    secret.last_step = step;
+    self::set(&mut con, &username, &secret).await?;
+    let codes = self::new_recovery_codes(&mut con, &username).await?;
+    log::info!("Turned on two-factor authentication for `{username}`");
+    let mut details = vec![
+        "These are your recovery codes. If you lose your authenticator, you can log in with \
+        one of them instead of a code. Each works once, and they won't be shown again, so keep \
+        them somewhere safe"
+            .to_owned(),
+    ];
+    details.extend(codes);
+    resp(
+        StatusCode::OK,
+        NoticePage::render_report("Turned on two-factor authentication", details, "/account"),
+    )
+}
+
+#[derive(Deserialize)]
+/// The form to turn off two-factor authentication
+pub struct DisableForm {
+    password: String,
+}
+
+/// `POST` for `/account/2fa/disable`
+///
+/// This will:
+/// - Verify the password in the form
+/// - Remove the user's secret and recovery codes
+pub async fn disable(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(form): Form<DisableForm>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::account::privileged_verify(&mut creds, &mut con, &form.password).await?;
+    self::remove_all(&mut con, &username).await?;
+    log::info!("Turned off two-factor authentication for `{username}`");
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to("Turned off two-factor authentication", "/account"),
+    )
+}
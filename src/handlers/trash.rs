@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! The trash. Deleting a note moves it to the user's trash (`default:jotsytrash` maps
+//! `username -> [note ID]`, oldest first) instead of deleting it. It can be restored from
+//! there until it has been in the trash for `JOTSY_TRASH_RETENTION_DAYS`, after which the
+//! [`purger`] deletes it (and its history) for good
+
+use super::{
+    app::{self, Note, NoteList, View},
+    Credentials,
+};
+use crate::{
+    templates::NoticePage,
+    util::{self, resp},
+};
+use axum::{
+    extract::{Extension, Form},
+    http::StatusCode,
+};
+use chrono::Utc;
+use skytable::{actions::AsyncActions, aio::Connection, ddl::AsyncDdl, pool::AsyncPool};
+use std::{collections::HashMap, time::Duration};
+
+/// How often the purger looks for notes that have been in the trash for too long
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// `GET` for `/trash`
+///
+/// This will:
+/// - Verify the session
+/// - Return the app, showing the first page of the trash (most recently deleted first)
+pub async fn trash_page(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let page = app::get_page(&mut con, &username, NoteList::Trash, None)
+        .await?
+        .expect("no cursor");
+    app::render_app(&mut con, &creds, username, page, View::Trash).await
+}
+
+/// Delete every note in the user's trash for good. Returns the number of notes deleted
+async fn empty(con: &mut Connection, username: &str) -> crate::JotsyResponseResult<usize> {
+    con.switch(crate::TABLE_TRASH).await?;
+    let ids = util::list_get(con, username).await?;
+    for id in &ids {
+        app::remove_note(con, username, id).await?;
+    }
+    Ok(ids.len())
+}
+
+/// `POST` for `/trash/empty`
+///
+/// This will:
+/// - Verify the session
+/// - Delete every note in the trash for good
+/// - Redirect back to the (now empty) trash
+pub async fn empty_trash(
+    mut creds: Credentials,
+    Extension(db): Extension<AsyncPool>,
+    Form(_): Form<util::Empty>,
+) -> crate::JotsyResponse {
+    let mut con = db.get().await?;
+    let username = super::root::verify_user_or_error(&mut con, &mut creds).await?;
+    let emptied = self::empty(&mut con, &username).await?;
+    log::info!("Emptied the trash of `{username}` ({emptied} note(s))");
+    resp(
+        StatusCode::OK,
+        NoticePage::redirect_to(format!("Deleted {emptied} note(s) forever"), "/trash"),
+    )
+}
+
+/// Delete every note that has been in the trash for longer than the retention period. Returns
+/// the number of notes deleted
+async fn purge(pool: &AsyncPool) -> crate::JotsyResponseResult<usize> {
+    let mut con = pool.get().await?;
+    con.switch(crate::TABLE_TRASH).await?;
+    let usernames: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    let cutoff = Utc::now() - util::trash_retention();
+    let mut purged = 0;
+    for username in usernames {
+        con.switch(crate::TABLE_TRASH).await?;
+        let ids = util::list_get(&mut con, &username).await?;
+        let notes: HashMap<String, Note> = app::get_notes(&mut con, &username, &ids)
+            .await?
+            .into_iter()
+            .map(|note| (note.id.clone(), note))
+            .collect();
+        for id in ids {
+            // an ID without a note can't be restored anyway, so it goes too
+            let expired = notes
+                .get(&id)
+                .is_none_or(|note| note.trashed.is_some_and(|trashed| trashed <= cutoff));
+            if expired {
+                app::remove_note(&mut con, &username, &id).await?;
+                purged += 1;
+            }
+        }
+    }
+    Ok(purged)
+}
+
+/// Periodically purge notes that have been in the trash for too long. This is spawned as a
+/// background task and never returns
+pub async fn purger(pool: AsyncPool) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match self::purge(&pool).await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {purged} note(s) from the trash"),
+            Err(e) => log::error!("Failed to purge the trash: {e}"),
+        }
+    }
+}
@@ -16,9 +16,10 @@
 
 use axum::{
     http::StatusCode,
+    middleware,
     response::Html,
-    routing::{get, post},
-    Extension, Router,
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
 };
 use skytable::pool;
 use std::{env, net::SocketAddr};
@@ -27,20 +28,55 @@ use tower_cookies::CookieManagerLayer;
 mod config;
 mod error;
 mod handlers;
+mod migrate;
 mod templates;
 mod util;
 
 const TABLE_AUTH: &str = "default:jotsyauth";
 const TABLE_NOTES: &str = "default:jotsynotes";
+const TABLE_NOTE_DATA: &str = "default:jotsynotedata";
+const TABLE_API_TOKENS: &str = "default:jotsyapitokens";
+const TABLE_USER_TOKENS: &str = "default:jotsyusertokens";
+const TABLE_SESSIONS: &str = "default:jotsysessions";
+const TABLE_USER_SESSIONS: &str = "default:jotsyusersessions";
+const TABLE_SEARCH: &str = "default:jotsysearch";
+const TABLE_SEARCH_TERMS: &str = "default:jotsysearchterms";
+const TABLE_TAGS: &str = "default:jotsytags";
+const TABLE_USER_TAGS: &str = "default:jotsyusertags";
+const TABLE_NOTEBOOKS: &str = "default:jotsynotebooks";
+const TABLE_NOTEBOOK_NOTES: &str = "default:jotsynotebooknotes";
+const TABLE_PINNED: &str = "default:jotsypinned";
+const TABLE_ARCHIVE: &str = "default:jotsyarchive";
+const TABLE_SHARES: &str = "default:jotsyshares";
+const TABLE_USER_SHARES: &str = "default:jotsyusershares";
+const TABLE_TRASH: &str = "default:jotsytrash";
+const TABLE_REVISIONS: &str = "default:jotsyrevisions";
+const TABLE_SETTINGS: &str = "default:jotsysettings";
+const TABLE_META: &str = "default:jotsymeta";
+const TABLE_USERS: &str = "default:jotsyusers";
+const TABLE_TOTP: &str = "default:jotsytotp";
+const TABLE_RECOVERY_CODES: &str = "default:jotsyrecoverycodes";
+const TABLE_PENDING_LOGINS: &str = "default:jotsypendinglogins";
+const TABLE_PASSKEYS: &str = "default:jotsypasskeys";
+const TABLE_PASSKEY_HANDLES: &str = "default:jotsypasskeyhandles";
+const TABLE_PASSKEY_CEREMONIES: &str = "default:jotsypasskeyceremonies";
+const TABLE_LOGIN_ATTEMPTS: &str = "default:jotsyloginattempts";
 
 type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
 type JotsyResponseResult<T> = Result<T, error::ResponseError>;
 type JotsyResponse = JotsyResponseResult<(StatusCode, Html<String>)>;
+type ApiResponse<T> = Result<(StatusCode, Json<T>), error::ApiError>;
 
 #[tokio::main]
 async fn main() -> DynResult<()> {
     let cfg = config::Config::init()?;
     util::set_prod_mode(cfg.is_prod);
+    util::set_trust_proxy(cfg.trust_proxy);
+    util::set_session_policy(cfg.session_ttl_days, cfg.session_sliding);
+    util::set_trash_retention(cfg.trash_retention_days);
+    util::set_admin_user(cfg.admin_user);
+    util::set_hide_taken_usernames(cfg.hide_taken_usernames);
+    handlers::passkeys::init(&cfg.public_url)?;
     // configure our logger
     env_logger::Builder::new()
         .parse_filters(&env::var("JOTSY_LOG").unwrap_or_else(|_| "info".to_owned()))
@@ -60,15 +96,105 @@ async fn main() -> DynResult<()> {
     log::trace!("Connected to Skytable pool");
     util::create_tables(&pool).await?;
     log::trace!("Created/reinitialized tables");
+    migrate::run(&pool).await?;
+    log::trace!("Finished running migrations");
+    // purge expired sessions in the background
+    tokio::spawn(handlers::sessions::reaper(pool.clone()));
+    // and purge notes that have been in the trash for too long
+    tokio::spawn(handlers::trash::purger(pool.clone()));
+    // and logins that never got their second factor
+    tokio::spawn(handlers::totp::reaper(pool.clone()));
+    // and passkey registrations and logins that were never finished
+    tokio::spawn(handlers::passkeys::reaper(pool.clone()));
+    // and failed logins that no longer lock anyone out
+    tokio::spawn(handlers::lockout::reaper(pool.clone()));
+    // the JSON API, versioned so that we can change it without breaking scripts
+    let api_v1 = Router::new()
+        .route("/notes", get(handlers::api::list_notes))
+        .route("/notes", post(handlers::api::create_note))
+        .route("/notes/:id", get(handlers::api::get_note))
+        .route("/notes/:id", put(handlers::api::update_note))
+        .route("/notes/:id", delete(handlers::api::delete_note));
     // create the routes
     let mut router = Router::new()
         // this is our GET for /
         .route("/", get(handlers::root))
         .route("/create/note", post(handlers::app::create_note))
+        .route("/notes/:id", put(handlers::app::edit_note))
+        .route("/notes/:id", delete(handlers::app::delete_note))
+        .route("/notes/:id/:action", post(handlers::app::note_action))
+        .route("/notes/:id/history", get(handlers::history::history_page))
+        .route("/notes/:id/share", post(handlers::shares::create_share))
+        .route("/s/:slug", get(handlers::shares::shared_note))
+        .route(
+            "/notes/:id/history/:number/restore",
+            post(handlers::history::restore),
+        )
+        .route("/archive", get(handlers::app::archive_page))
+        .route("/trash", get(handlers::trash::trash_page))
+        .route("/trash/empty", post(handlers::trash::empty_trash))
         .route("/login", post(handlers::login))
         .route("/login", get(handlers::login_get))
+        .route("/login/2fa", post(handlers::totp::login_2fa))
+        .route("/login/passkey", post(handlers::passkeys::login))
+        .route(
+            "/login/passkey/options",
+            post(handlers::passkeys::login_options),
+        )
         .route("/logout", post(handlers::logout))
+        .route("/notes", get(handlers::app::notes_page))
+        .route("/search", get(handlers::search::search_page))
+        .route("/tags/:tag", get(handlers::tags::tag_page))
+        .route("/notebooks", post(handlers::notebooks::create_notebook))
+        .route("/notebooks/:id", get(handlers::notebooks::notebook_page))
+        .route(
+            "/notebooks/:id/rename",
+            post(handlers::notebooks::rename_notebook),
+        )
+        .route(
+            "/notebooks/:id/delete",
+            post(handlers::notebooks::delete_notebook),
+        )
         .route("/account", get(handlers::account::account))
+        .route("/account/password", get(handlers::account::password_get))
+        .route("/account/password", post(handlers::account::password_post))
+        .route("/account/2fa", get(handlers::totp::setup_get))
+        .route("/account/2fa", post(handlers::totp::setup_post))
+        .route("/account/2fa/disable", post(handlers::totp::disable))
+        .route("/account/passkeys", post(handlers::passkeys::register))
+        .route(
+            "/account/passkeys/options",
+            post(handlers::passkeys::register_options),
+        )
+        .route(
+            "/account/passkeys/remove",
+            post(handlers::passkeys::remove_passkey),
+        )
+        .route("/account/export", get(handlers::account::export))
+        .route("/account/timezone", post(handlers::account::timezone_post))
+        .route("/account/import", post(handlers::import::import))
+        .route("/account/tokens", post(handlers::tokens::create_token))
+        .route(
+            "/account/tokens/revoke",
+            post(handlers::tokens::revoke_token),
+        )
+        .route(
+            "/account/shares/revoke",
+            post(handlers::shares::revoke_share),
+        )
+        .route(
+            "/account/sessions/revoke",
+            post(handlers::sessions::revoke_session),
+        )
+        .route(
+            "/account/sessions/revoke-others",
+            post(handlers::sessions::revoke_other_sessions),
+        )
+        .route("/admin", get(handlers::admin::admin_page))
+        .route(
+            "/admin/users/:username/:action",
+            post(handlers::admin::user_action),
+        )
         .route("/delete/account", get(handlers::account::del_account_get))
         .route("/delete/account", post(handlers::account::del_account_post))
         .route("/delete/notes", get(handlers::account::del_notes_get))
@@ -81,7 +207,16 @@ async fn main() -> DynResult<()> {
         .route("/static/css/app.css", get(handlers::assets::index_app_css))
         .route("/static/js/login.js", get(handlers::assets::index_login_js))
         .route("/static/js/app.js", get(handlers::assets::index_app_js))
-        .route("/favicon.ico", get(handlers::assets::favicon));
+        .route(
+            "/static/js/passkeys.js",
+            get(handlers::assets::index_passkeys_js),
+        )
+        .route(
+            "/static/js/account.js",
+            get(handlers::assets::index_account_js),
+        )
+        .route("/favicon.ico", get(handlers::assets::favicon))
+        .nest("/api/v1", api_v1);
     if cfg.signup_enabled {
         router = router
             .route("/signup", post(handlers::signup))
@@ -90,6 +225,9 @@ async fn main() -> DynResult<()> {
         router = router.route("/signup", get(handlers::signup::no_signup))
     }
     router = router
+        // check the CSRF token of requests that change something (this needs the cookies, so
+        // it goes inside the cookie layer)
+        .layer(middleware::from_fn(handlers::csrf::verify))
         // add a cookie "layer" (axum's way of customizing routing)
         .layer(CookieManagerLayer::new())
         // add the database "layer"
@@ -98,7 +236,8 @@ async fn main() -> DynResult<()> {
     let addr = SocketAddr::new(cfg.host.parse()?, cfg.port);
     log::info!("Running server on http://127.0.0.1:2022/");
     tokio::select! {
-        _ = axum::Server::bind(&addr).serve(router.into_make_service()) => {}
+        _ = axum::Server::bind(&addr)
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>()) => {}
         _ = tokio::signal::ctrl_c() => {}
     }
     log::info!("Finished serving. Goodbye!");
@@ -0,0 +1,285 @@
+/*
+ * Copyright (c) 2022, Sayan Nandan <nandansayan@outlook.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+
+//! Upgrades data stored by older versions of Jotsy. Migrations run on every startup, so each
+//! one either checks the data it upgrades and skips whatever is already upgraded, or is a
+//! one-off that records `migration/<name>` in the meta table once it has finished (see
+//! [`has_run`] and [`mark_run`]) and is skipped from then on. A one-off that is stopped halfway
+//! runs again in full on the next startup, so it must be safe to run twice
+
+use crate::{
+    handlers::{
+        app::{self, Note, NoteFormat},
+        index, notebooks, search,
+        sessions::{self, Session},
+        tags,
+    },
+    util,
+};
+use chrono::{DateTime, SecondsFormat};
+use serde::Deserialize;
+use serde_json::Value;
+use skytable::{
+    actions::AsyncActions,
+    aio::Connection,
+    ddl::AsyncDdl,
+    error::{Error, SkyhashError},
+    pool::AsyncPool,
+    query, RespCode,
+};
+
+pub async fn run(pool: &AsyncPool) -> crate::DynResult<()> {
+    let mut con = pool.get().await?;
+    self::note_ids(&mut con).await?;
+    self::dates(&mut con).await?;
+    self::sessions(&mut con).await?;
+    self::search_index(&mut con).await?;
+    self::tags(&mut con).await?;
+    self::notebooks(&mut con).await?;
+    Ok(())
+}
+
+/// Returns true if the one-off migration called `name` has already run
+async fn has_run(con: &mut Connection, name: &str) -> crate::DynResult<bool> {
+    con.switch(crate::TABLE_META).await?;
+    let done: Result<String, Error> = con.get(format!("migration/{name}")).await;
+    match done {
+        Ok(_) => Ok(true),
+        Err(Error::SkyError(SkyhashError::Code(RespCode::NotFound))) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record that the one-off migration called `name` has run, so that it never runs again
+async fn mark_run(con: &mut Connection, name: &str) -> crate::DynResult<()> {
+    con.switch(crate::TABLE_META).await?;
+    con.set(format!("migration/{name}"), util::now().to_string())
+        .await?;
+    Ok(())
+}
+
+/// Returns every username that has a list of notes (up to [`util::LSKEYS_LIMIT`], since
+/// Skytable can't list keys a page at a time)
+async fn users(con: &mut Connection) -> crate::DynResult<Vec<String>> {
+    con.switch(crate::TABLE_NOTES).await?;
+    let users: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    if users.len() as u64 >= util::LSKEYS_LIMIT {
+        log::warn!(
+            "There are more than {} users, so migrations will skip some of them",
+            util::LSKEYS_LIMIT
+        );
+    }
+    Ok(users)
+}
+
+#[derive(Deserialize)]
+/// A note as stored by versions that kept the JSON directly in the user's list
+struct LegacyNote {
+    date: String,
+    body: String,
+}
+
+/// Notes used to be stored as JSON right in the user's list. Move each of them into the
+/// note data table under a fresh ID and replace it in the list with the ID. Every note is
+/// replaced on its own after it was stored, so if this is stopped halfway the list just has
+/// both kinds of items and the rest are moved on the next startup
+async fn note_ids(con: &mut Connection) -> crate::DynResult<()> {
+    for username in self::users(con).await? {
+        let items = app::get_note_ids(con, &username).await?;
+        let mut migrated = 0usize;
+        for (index, item) in items.into_iter().enumerate() {
+            if !item.starts_with('{') {
+                continue;
+            }
+            let legacy: LegacyNote = serde_json::from_str(&item)?;
+            let date = util::parse_date(&legacy.date).unwrap_or_default();
+            let note = Note::new(date, legacy.body, NoteFormat::Markdown);
+            app::put_note(con, &username, &note).await?;
+            con.switch(crate::TABLE_NOTES).await?;
+            let q = query!("LMOD", &username, "REPLACE", index.to_string(), &note.id);
+            util::expect_okay(con.run_query(&q).await?)?;
+            migrated += 1;
+        }
+        if migrated != 0 {
+            log::info!("Migrated {migrated} note(s) for `{username}`");
+        }
+    }
+    Ok(())
+}
+
+/// Notes used to store their date as a display string (formatted in the server's timezone).
+/// Rewrite them as RFC 3339 timestamps in UTC. Dates that can't be read at all become the
+/// UNIX epoch, so that the note can still be loaded
+async fn dates(con: &mut Connection) -> crate::DynResult<()> {
+    const NAME: &str = "dates";
+    if self::has_run(con, NAME).await? {
+        return Ok(());
+    }
+    for username in self::users(con).await? {
+        let keys: Vec<String> = app::get_note_ids(con, &username)
+            .await?
+            .iter()
+            .map(|id| app::note_key(&username, id))
+            .collect();
+        if keys.is_empty() {
+            continue;
+        }
+        con.switch(crate::TABLE_NOTE_DATA).await?;
+        let notes = util::mget(con, keys.clone()).await?;
+        let mut migrated = 0usize;
+        for (key, json) in keys.iter().zip(notes) {
+            let mut note: Value = match json {
+                Some(json) => serde_json::from_str(&json)?,
+                None => continue,
+            };
+            // notes written by newer versions have `created` instead, which is always RFC 3339
+            let old = match note["date"].as_str() {
+                Some(old) if DateTime::parse_from_rfc3339(old).is_err() => old,
+                _ => continue,
+            };
+            let date = util::parse_date(old).unwrap_or_else(|| {
+                log::warn!("Couldn't read the date `{old}` of note `{key}`");
+                DateTime::default()
+            });
+            note["date"] = Value::from(date.to_rfc3339_opts(SecondsFormat::Secs, true));
+            let _: u64 = con
+                .run_query(&query!("USET", key, note.to_string()))
+                .await?;
+            migrated += 1;
+        }
+        if migrated != 0 {
+            log::info!("Migrated the dates of {migrated} note(s) for `{username}`");
+        }
+    }
+    self::mark_run(con, NAME).await
+}
+
+/// Sessions used to be stored in the auth table as `sha2(token) -> username`, right next to
+/// `username -> bcrypt hash`. Move them into the sessions table. Nothing but passwords is
+/// stored in the auth table since, so this only ever runs once
+async fn sessions(con: &mut Connection) -> crate::DynResult<()> {
+    const NAME: &str = "sessions";
+    if self::has_run(con, NAME).await? {
+        return Ok(());
+    }
+    con.switch(crate::TABLE_AUTH).await?;
+    let keys: Vec<String> = con.lskeys(util::LSKEYS_LIMIT).await?;
+    let mut migrated = 0usize;
+    for key in keys {
+        con.switch(crate::TABLE_AUTH).await?;
+        let value: String = con.get(&key).await?;
+        // bcrypt hashes always start with `$2`; anything else is a session
+        if value.starts_with("$2") {
+            continue;
+        }
+        let now = util::now();
+        let session = Session {
+            username: value,
+            created: now,
+            last_seen: now,
+            user_agent: None,
+            ip: None,
+        };
+        sessions::create(con, &key, &session).await?;
+        con.switch(crate::TABLE_AUTH).await?;
+        con.del(&key).await?;
+        migrated += 1;
+    }
+    if migrated != 0 {
+        log::info!("Migrated {migrated} session(s) to the sessions table");
+    }
+    self::mark_run(con, NAME).await
+}
+
+/// Notes created before search existed aren't in the index. Index all the notes of any user
+/// who has notes but nothing in the index. Notes are indexed as they are written since, so
+/// this only ever runs once
+async fn search_index(con: &mut Connection) -> crate::DynResult<()> {
+    const NAME: &str = "search-index";
+    if self::has_run(con, NAME).await? {
+        return Ok(());
+    }
+    for username in self::users(con).await? {
+        if search::has_index(con, &username).await? {
+            continue;
+        }
+        let ids = app::get_note_ids(con, &username).await?;
+        if ids.is_empty() {
+            continue;
+        }
+        let notes = app::get_notes(con, &username, &ids).await?;
+        for note in &notes {
+            search::index_note(con, &username, &note.id, &note.body).await?;
+        }
+        log::info!("Indexed {} note(s) for `{username}`", notes.len());
+    }
+    self::mark_run(con, NAME).await
+}
+
+/// Notes created before tags existed don't have any. Tag every note that has `#hashtags` in it
+/// and add it to the tag index. Since most notes have no tags, we can't tell if this has
+/// already run by looking at the notes, so it's only ever run once
+async fn tags(con: &mut Connection) -> crate::DynResult<()> {
+    const NAME: &str = "tags";
+    if self::has_run(con, NAME).await? {
+        return Ok(());
+    }
+    for username in self::users(con).await? {
+        let ids = app::get_note_ids(con, &username).await?;
+        let mut tagged = 0usize;
+        for mut note in app::get_notes(con, &username, &ids).await? {
+            let tags = tags::extract(&note.body);
+            if tags.is_empty() || tags == note.tags {
+                continue;
+            }
+            let old = std::mem::replace(&mut note.tags, tags);
+            app::put_note(con, &username, &note).await?;
+            index::TAGS
+                .update(con, &username, &note.id, &old, &note.tags)
+                .await?;
+            tagged += 1;
+        }
+        if tagged != 0 {
+            log::info!("Tagged {tagged} note(s) for `{username}`");
+        }
+    }
+    self::mark_run(con, NAME).await
+}
+
+/// Notes created before notebooks existed belong to the default notebook, but aren't in its
+/// list of notes yet. Since a user's notes are already in order, that list is a copy of the
+/// user's list of notes
+async fn notebooks(con: &mut Connection) -> crate::DynResult<()> {
+    const NAME: &str = "notebooks";
+    if self::has_run(con, NAME).await? {
+        return Ok(());
+    }
+    for username in self::users(con).await? {
+        let ids = app::get_note_ids(con, &username).await?;
+        if ids.is_empty() {
+            continue;
+        }
+        let key = notebooks::notes_key(&username, notebooks::DEFAULT_NOTEBOOK);
+        con.switch(crate::TABLE_NOTEBOOK_NOTES).await?;
+        con.del(&key).await?;
+        util::expect_okay(con.run_query(&query!("LSET", &key, &ids)).await?)?;
+        log::info!(
+            "Moved {} note(s) into the default notebook for `{username}`",
+            ids.len()
+        );
+    }
+    self::mark_run(con, NAME).await
+}
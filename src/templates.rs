@@ -13,21 +13,47 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
 */
-
-use crate::handlers::app::Note;
+use crate::handlers::{
+    admin::AdminUser,
+    app::{Note, Page, View},
+    history::Version,
+    notebooks::NotebookCount,
+    passkeys::StoredPasskey,
+    search::SearchResult,
+    sessions::ActiveSession,
+    shares::ActiveShare,
+    tags::TagCount,
+    tokens::ApiToken,
+};
 use crate::util;
 use askama::Template;
 use axum::{body, http::StatusCode, response::Response};
+use chrono_tz::Tz;
 
 #[derive(Template)]
 #[template(path = "login.html")]
 pub struct LoginPage {
     login_failed: bool,
+    /// How long the client has to wait after too many failed logins
+    wait: Option<String>,
 }
 
 impl LoginPage {
     pub fn render_new(login_failed: bool) -> String {
-        Self { login_failed }.render().unwrap()
+        Self {
+            login_failed,
+            wait: None,
+        }
+        .render()
+        .unwrap()
+    }
+    pub fn render_rate_limited(wait: String) -> String {
+        Self {
+            login_failed: false,
+            wait: Some(wait),
+        }
+        .render()
+        .unwrap()
     }
 }
 
@@ -36,6 +62,9 @@ impl LoginPage {
 pub struct NoticePage {
     message: String,
     redirect: bool,
+    target: String,
+    /// More lines to list below the message
+    details: Vec<String>,
 }
 
 impl NoticePage {
@@ -43,6 +72,8 @@ impl NoticePage {
         NoticePage {
             message: message.to_string(),
             redirect,
+            target: "/".to_owned(),
+            details: Vec::new(),
         }
         .render()
         .unwrap()
@@ -50,6 +81,33 @@ impl NoticePage {
     pub fn new_redirect(message: impl ToString) -> String {
         Self::render_new(message, true)
     }
+    /// Like [`Self::new_redirect`], but redirects to `target` instead of the homepage
+    pub fn redirect_to(message: impl ToString, target: impl ToString) -> String {
+        NoticePage {
+            message: message.to_string(),
+            redirect: true,
+            target: target.to_string(),
+            details: Vec::new(),
+        }
+        .render()
+        .unwrap()
+    }
+    /// A notice with a list of details (like what happened to every file in an import) that
+    /// links back to `target`
+    pub fn render_report(
+        message: impl ToString,
+        details: Vec<String>,
+        target: impl ToString,
+    ) -> String {
+        NoticePage {
+            message: message.to_string(),
+            redirect: false,
+            target: target.to_string(),
+            details,
+        }
+        .render()
+        .unwrap()
+    }
     pub fn e500() -> String {
         Self::render_new("An internal server error occurred", false)
     }
@@ -67,6 +125,21 @@ impl NoticePage {
     }
 }
 
+#[derive(Template)]
+#[template(path = "login_totp.html")]
+/// The second step of logging in, for users with two-factor authentication on
+pub struct TotpLoginPage {
+    /// The token of the pending login
+    token: String,
+    failed: bool,
+}
+
+impl TotpLoginPage {
+    pub fn render_new(token: String, failed: bool) -> String {
+        Self { token, failed }.render().unwrap()
+    }
+}
+
 #[derive(Template)]
 #[template(path = "signup.html")]
 pub struct SignupPage {
@@ -86,17 +159,74 @@ impl SignupPage {
     }
 }
 
+/// Custom filters for our templates
+mod filters {
+    use crate::handlers::app::{Note, NoteFormat};
+    use crate::util;
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use chrono_tz::Tz;
+
+    /// Render the body of a note according to its format. Markdown is sanitized by
+    /// [`util::md_to_html`] and plaintext is escaped and shown exactly as it was written
+    pub fn render(note: &Note) -> askama::Result<String> {
+        match note.format {
+            NoteFormat::Markdown => Ok(util::md_to_html(&note.body)),
+            NoteFormat::Plaintext => Ok(format!(
+                "<p class=\"plaintext\">{}</p>",
+                askama::filters::escape(askama::Html, &note.body)?
+            )),
+        }
+    }
+
+    /// Format a date in the user's timezone (see [`util::format_date`])
+    pub fn date(date: &DateTime<Utc>, tz: &Option<Tz>) -> askama::Result<String> {
+        Ok(util::format_date(date, *tz))
+    }
+
+    /// Format a date as an RFC 3339 timestamp, for `<time datetime="...">`
+    pub fn rfc3339(date: &DateTime<Utc>) -> askama::Result<String> {
+        Ok(date.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+}
+
 #[derive(Template)]
 #[template(path = "note.html", escape = "none")]
 pub struct SingleNote {
     note: Note,
+    tz: Option<Tz>,
 }
 
 impl SingleNote {
-    pub fn render_new(mut note: Note) -> String {
-        // update markdown
-        note.body = util::md_to_html(&note.body);
-        Self { note }.render().unwrap()
+    pub fn render_new(note: Note, tz: Option<Tz>) -> String {
+        Self { note, tz }.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "shared.html", escape = "none")]
+/// A note shared with a link, for anyone to read
+pub struct SharedNote {
+    note: Note,
+    /// Always `None`, since the date is shown in the reader's timezone
+    tz: Option<Tz>,
+}
+
+impl SharedNote {
+    pub fn render_new(note: Note) -> String {
+        Self { note, tz: None }.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "notes.html", escape = "none")]
+pub struct NotesPage {
+    page: Page,
+    tz: Option<Tz>,
+}
+
+impl NotesPage {
+    pub fn render_new(page: Page, tz: Option<Tz>) -> String {
+        Self { page, tz }.render().unwrap()
     }
 }
 
@@ -104,16 +234,121 @@ impl SingleNote {
 #[template(path = "app.html", escape = "none")]
 pub struct App {
     username: String,
-    count: usize,
-    notes: Vec<Note>,
+    count: u64,
+    page: Page,
+    tags: Vec<TagCount>,
+    notebooks: Vec<NotebookCount>,
+    view: View,
+    /// The user's timezone, if they have chosen one
+    tz: Option<Tz>,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
 }
 
 impl App {
-    pub fn render_new(username: String, notes: Vec<Note>) -> String {
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_new(
+        username: String,
+        count: u64,
+        page: Page,
+        tags: Vec<TagCount>,
+        notebooks: Vec<NotebookCount>,
+        view: View,
+        tz: Option<Tz>,
+        csrf_token: String,
+    ) -> String {
+        Self {
+            username,
+            count,
+            page,
+            tags,
+            notebooks,
+            view,
+            tz,
+            csrf_token,
+        }
+        .render()
+        .unwrap()
+    }
+    /// Is this the notebook being shown?
+    fn is_current_notebook(&self, id: &str) -> bool {
+        matches!(&self.view, View::Notebook(notebook) if notebook.id == id)
+    }
+    /// Is this the tag being shown?
+    fn is_current(&self, tag: &str) -> bool {
+        matches!(&self.view, View::Tag(t) if t == tag)
+    }
+    /// Is the archive being shown?
+    fn is_archive(&self) -> bool {
+        matches!(self.view, View::Archive)
+    }
+    /// Is the trash being shown?
+    fn is_trash(&self) -> bool {
+        matches!(self.view, View::Trash)
+    }
+    /// Returns how many days notes stay in the trash
+    fn retention_days(&self) -> i64 {
+        util::trash_retention().num_days()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "search.html")]
+pub struct SearchPage {
+    username: String,
+    query: String,
+    results: Vec<SearchResult>,
+    tz: Option<Tz>,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
+}
+
+impl SearchPage {
+    pub fn render_new(
+        username: String,
+        query: String,
+        results: Vec<SearchResult>,
+        tz: Option<Tz>,
+        csrf_token: String,
+    ) -> String {
+        Self {
+            username,
+            query,
+            results,
+            tz,
+            csrf_token,
+        }
+        .render()
+        .unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+pub struct HistoryPage {
+    username: String,
+    note: Note,
+    /// Newest first
+    versions: Vec<Version>,
+    tz: Option<Tz>,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
+}
+
+impl HistoryPage {
+    pub fn render_new(
+        username: String,
+        note: Note,
+        versions: Vec<Version>,
+        tz: Option<Tz>,
+        csrf_token: String,
+    ) -> String {
         Self {
             username,
-            count: notes.len(),
-            notes,
+            note,
+            versions,
+            tz,
+            csrf_token,
         }
         .render()
         .unwrap()
@@ -125,21 +360,184 @@ impl App {
 pub struct Account {
     count: u64,
     username: String,
+    tokens: Vec<ApiToken>,
+    new_token: Option<String>,
+    sessions: Vec<ActiveSession>,
+    notebooks: Vec<NotebookCount>,
+    timezone: Option<Tz>,
+    shares: Vec<ActiveShare>,
+    /// Is the user an admin? Admins get a link to the admin console
+    admin: bool,
+    /// The number of recovery codes left, if two-factor authentication is on
+    totp: Option<u64>,
+    passkeys: Vec<StoredPasskey>,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
 }
 
 impl Account {
-    pub fn render_new(count: u64, username: String) -> String {
-        Self { count, username }.render().unwrap()
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_new(
+        count: u64,
+        username: String,
+        tokens: Vec<ApiToken>,
+        new_token: Option<String>,
+        sessions: Vec<ActiveSession>,
+        notebooks: Vec<NotebookCount>,
+        timezone: Option<Tz>,
+        shares: Vec<ActiveShare>,
+        admin: bool,
+        totp: Option<u64>,
+        passkeys: Vec<StoredPasskey>,
+        csrf_token: String,
+    ) -> String {
+        Self {
+            count,
+            username,
+            tokens,
+            new_token,
+            sessions,
+            notebooks,
+            timezone,
+            shares,
+            admin,
+            totp,
+            passkeys,
+            csrf_token,
+        }
+        .render()
+        .unwrap()
+    }
+    /// Returns the names of all the timezones a user can choose
+    fn timezones(&self) -> impl Iterator<Item = &'static str> {
+        chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name())
+    }
+    /// Is this the user's timezone?
+    fn is_timezone(&self, name: &str) -> bool {
+        self.timezone.is_some_and(|tz| tz.name() == name)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+pub struct AdminPage {
+    username: String,
+    /// Every account on the instance
+    users: Vec<AdminUser>,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
+}
+
+impl AdminPage {
+    pub fn render_new(username: String, users: Vec<AdminUser>, csrf_token: String) -> String {
+        Self {
+            username,
+            users,
+            csrf_token,
+        }
+        .render()
+        .unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "totp.html")]
+pub struct TotpSetup {
+    username: String,
+    /// The QR code for the secret, as an SVG
+    qr: String,
+    /// The secret, for authenticator apps that can't scan the QR code
+    secret: String,
+    failed: bool,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
+}
+
+impl TotpSetup {
+    pub fn render_new(
+        username: String,
+        qr: String,
+        secret: String,
+        failed: bool,
+        csrf_token: String,
+    ) -> String {
+        Self {
+            username,
+            qr,
+            secret,
+            failed,
+            csrf_token,
+        }
+        .render()
+        .unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "password.html")]
+pub struct ChangePassword {
+    username: String,
+    error: Option<&'static str>,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
+}
+
+impl ChangePassword {
+    pub fn render_new(username: String, error: Option<&'static str>, csrf_token: String) -> String {
+        Self {
+            username,
+            error,
+            csrf_token,
+        }
+        .render()
+        .unwrap()
     }
 }
 
+#[derive(Template)]
+#[template(path = "export.html", escape = "none")]
+/// The start of a static page with all of a user's notes, for exports. The notes are streamed
+/// after it one at a time (see [`ExportNote`]), followed by [`EXPORT_END`]
+pub struct ExportPage {
+    username: String,
+    /// The number of notes that will follow
+    count: usize,
+}
+
+impl ExportPage {
+    pub fn render_new(username: String, count: usize) -> String {
+        Self { username, count }.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "export_note.html", escape = "none")]
+/// A note on the export page
+pub struct ExportNote<'a> {
+    note: &'a Note,
+    /// The name of the note's notebook
+    notebook: &'a str,
+    tz: Option<Tz>,
+}
+
+impl<'a> ExportNote<'a> {
+    pub fn render_new(note: &'a Note, notebook: &'a str, tz: Option<Tz>) -> String {
+        Self { note, notebook, tz }.render().unwrap()
+    }
+}
+
+/// The end of the export page
+pub const EXPORT_END: &str = "  </body>\n</html>\n";
+
 #[derive(Template)]
 #[template(path = "delete.html")]
 pub struct DeleteUI {
     what: String,
     action: String,
     username: String,
-    lose: String,
+    warning: String,
+    /// The CSRF token to send back with every form
+    csrf_token: String,
 }
 
 impl DeleteUI {
@@ -147,13 +545,15 @@ impl DeleteUI {
         what: impl ToString,
         action: impl ToString,
         username: impl ToString,
-        lose: impl ToString,
+        warning: impl ToString,
+        csrf_token: String,
     ) -> String {
         Self {
             what: what.to_string(),
             action: action.to_string(),
             username: username.to_string(),
-            lose: lose.to_string(),
+            warning: warning.to_string(),
+            csrf_token,
         }
         .render()
         .unwrap()
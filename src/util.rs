@@ -14,20 +14,81 @@
  * limitations under the License.
 */
 
+use ammonia::Builder;
 use axum::{http::StatusCode, response::Html};
+use chrono::{offset::LocalResult, DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use comrak::{markdown_to_html as to_html, ComrakOptions};
 use cookie::SameSite;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
-use skytable::{pool::AsyncPool, Query};
+use skytable::{
+    aio::Connection,
+    pool::AsyncPool,
+    query,
+    types::{Array, FromSkyhashBytes},
+    Pipeline, Query,
+};
+use std::sync::OnceLock;
 use time::{Duration, OffsetDateTime};
 use tower_cookies::Cookie;
 
 const CREATE_JOTSY_TABLE_AUTH: &str = "create table default:jotsyauth keymap(binstr,binstr)";
 const CREATE_JOTSY_TABLE_NOTES: &str = "create table default:jotsynotes keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_NOTE_DATA: &str = "create table default:jotsynotedata keymap(str,str)";
+const CREATE_JOTSY_TABLE_API_TOKENS: &str = "create table default:jotsyapitokens keymap(str,str)";
+const CREATE_JOTSY_TABLE_USER_TOKENS: &str =
+    "create table default:jotsyusertokens keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_SESSIONS: &str = "create table default:jotsysessions keymap(str,str)";
+const CREATE_JOTSY_TABLE_USER_SESSIONS: &str =
+    "create table default:jotsyusersessions keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_SEARCH: &str = "create table default:jotsysearch keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_SEARCH_TERMS: &str =
+    "create table default:jotsysearchterms keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_TAGS: &str = "create table default:jotsytags keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_USER_TAGS: &str =
+    "create table default:jotsyusertags keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_NOTEBOOKS: &str =
+    "create table default:jotsynotebooks keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_NOTEBOOK_NOTES: &str =
+    "create table default:jotsynotebooknotes keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_PINNED: &str = "create table default:jotsypinned keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_ARCHIVE: &str = "create table default:jotsyarchive keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_SHARES: &str = "create table default:jotsyshares keymap(str,str)";
+const CREATE_JOTSY_TABLE_USER_SHARES: &str =
+    "create table default:jotsyusershares keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_TRASH: &str = "create table default:jotsytrash keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_REVISIONS: &str =
+    "create table default:jotsyrevisions keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_SETTINGS: &str = "create table default:jotsysettings keymap(str,str)";
+const CREATE_JOTSY_TABLE_META: &str = "create table default:jotsymeta keymap(str,str)";
+const CREATE_JOTSY_TABLE_USERS: &str = "create table default:jotsyusers keymap(str,str)";
+const CREATE_JOTSY_TABLE_TOTP: &str = "create table default:jotsytotp keymap(str,str)";
+const CREATE_JOTSY_TABLE_RECOVERY_CODES: &str =
+    "create table default:jotsyrecoverycodes keymap(str,list<str>)";
+const CREATE_JOTSY_TABLE_PENDING_LOGINS: &str =
+    "create table default:jotsypendinglogins keymap(str,str)";
+const CREATE_JOTSY_TABLE_PASSKEYS: &str = "create table default:jotsypasskeys keymap(str,str)";
+const CREATE_JOTSY_TABLE_PASSKEY_HANDLES: &str =
+    "create table default:jotsypasskeyhandles keymap(str,str)";
+const CREATE_JOTSY_TABLE_PASSKEY_CEREMONIES: &str =
+    "create table default:jotsypasskeyceremonies keymap(str,str)";
+const CREATE_JOTSY_TABLE_LOGIN_ATTEMPTS: &str =
+    "create table default:jotsyloginattempts keymap(str,str)";
 static JOTSY_PROD: AtomicBool = AtomicBool::new(true);
+static JOTSY_TRUST_PROXY: AtomicBool = AtomicBool::new(false);
+static JOTSY_SESSION_TTL_DAYS: AtomicU32 = AtomicU32::new(15);
+static JOTSY_SESSION_SLIDING: AtomicBool = AtomicBool::new(false);
+static JOTSY_TRASH_RETENTION_DAYS: AtomicU32 = AtomicU32::new(30);
+static JOTSY_ADMIN_USER: OnceLock<String> = OnceLock::new();
+static JOTSY_HIDE_TAKEN_USERNAMES: AtomicBool = AtomicBool::new(false);
 const ORD_RELAXED: Ordering = Ordering::Relaxed;
+/// The maximum number of keys we'll ask Skytable for when listing a table
+pub const LSKEYS_LIMIT: u64 = 100_000;
+/// The format used to display dates and times
+pub const DATE_FORMAT: &str = "%B %d, %Y | %I:%M %p";
 
 pub fn set_prod_mode(is_prod: bool) {
     self::JOTSY_PROD.store(is_prod, ORD_RELAXED)
@@ -37,6 +98,101 @@ pub fn is_prod() -> bool {
     self::JOTSY_PROD.load(ORD_RELAXED)
 }
 
+pub fn set_trust_proxy(trust_proxy: bool) {
+    self::JOTSY_TRUST_PROXY.store(trust_proxy, ORD_RELAXED)
+}
+
+pub fn trust_proxy() -> bool {
+    self::JOTSY_TRUST_PROXY.load(ORD_RELAXED)
+}
+
+pub fn set_session_policy(ttl_days: u32, sliding: bool) {
+    self::JOTSY_SESSION_TTL_DAYS.store(ttl_days, ORD_RELAXED);
+    self::JOTSY_SESSION_SLIDING.store(sliding, ORD_RELAXED);
+}
+
+/// Returns how long a session lasts (in seconds) after it is created or, if sliding renewal
+/// is enabled, after it was last used
+pub fn session_ttl() -> i64 {
+    i64::from(self::JOTSY_SESSION_TTL_DAYS.load(ORD_RELAXED)) * 24 * 60 * 60
+}
+
+/// Returns true if sessions should be renewed on activity
+pub fn session_sliding() -> bool {
+    self::JOTSY_SESSION_SLIDING.load(ORD_RELAXED)
+}
+
+pub fn set_trash_retention(days: u32) {
+    self::JOTSY_TRASH_RETENTION_DAYS.store(days, ORD_RELAXED)
+}
+
+/// Returns how long notes stay in the trash before they are deleted for good
+pub fn trash_retention() -> chrono::Duration {
+    chrono::Duration::days(i64::from(
+        self::JOTSY_TRASH_RETENTION_DAYS.load(ORD_RELAXED),
+    ))
+}
+
+pub fn set_hide_taken_usernames(hide: bool) {
+    self::JOTSY_HIDE_TAKEN_USERNAMES.store(hide, ORD_RELAXED)
+}
+
+/// Returns true if signing up shouldn't tell whether a username is taken
+pub fn hide_taken_usernames() -> bool {
+    self::JOTSY_HIDE_TAKEN_USERNAMES.load(ORD_RELAXED)
+}
+
+pub fn set_admin_user(username: Option<String>) {
+    if let Some(username) = username {
+        let _ = self::JOTSY_ADMIN_USER.set(username);
+    }
+}
+
+/// Returns the user that is always an admin, if one was configured
+pub fn admin_user() -> Option<&'static str> {
+    self::JOTSY_ADMIN_USER.get().map(String::as_str)
+}
+
+/// Returns the current UNIX timestamp (in seconds)
+pub fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Format a UNIX timestamp (in seconds) for display
+pub fn format_timestamp(ts: i64) -> String {
+    match Local.timestamp_opt(ts, 0) {
+        LocalResult::Single(time) => time.format(DATE_FORMAT).to_string(),
+        _ => "Unknown".to_owned(),
+    }
+}
+
+/// Parse the date of a note. Notes store RFC 3339 timestamps, but older versions stored dates
+/// formatted with [`DATE_FORMAT`] in the server's timezone and imports can have plain dates
+/// (like `2022-03-01`, which is taken to be midnight UTC)
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDateTime::parse_from_str(date, DATE_FORMAT) {
+        return Local
+            .from_local_datetime(&date)
+            .earliest()
+            .map(|date| date.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// Format the date of a note for display, in the given timezone (or in UTC if the user hasn't
+/// chosen one, in which case the browser shows it in its own timezone instead)
+pub fn format_date(date: &DateTime<Utc>, tz: Option<Tz>) -> String {
+    match tz {
+        Some(tz) => date.with_timezone(&tz).format(DATE_FORMAT).to_string(),
+        None => format!("{} UTC", date.format(DATE_FORMAT)),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Empty {}
 
@@ -45,22 +201,190 @@ pub fn query(q: &str) -> Query {
     Query::from(q)
 }
 
-use skytable::{error::errorstring::ERR_ALREADY_EXISTS, Element, RespCode};
+use skytable::{
+    error::{errorstring::ERR_ALREADY_EXISTS, SkyhashError},
+    Element, RespCode,
+};
 
 pub async fn create_tables(pool: &AsyncPool) -> crate::DynResult<()> {
     let mut con = pool.get().await?;
-    let r1 = con.run_query(&query(CREATE_JOTSY_TABLE_AUTH)).await?;
-    let r2 = con.run_query(&query(CREATE_JOTSY_TABLE_NOTES)).await?;
     let check_error = |e| match e {
         Element::RespCode(RespCode::Okay) => {}
         Element::RespCode(RespCode::ErrorString(s)) if s.eq(ERR_ALREADY_EXISTS) => {}
         _ => panic!("Unexpected response: {:?}", e),
     };
-    check_error(r1);
-    check_error(r2);
+    for table in [
+        CREATE_JOTSY_TABLE_AUTH,
+        CREATE_JOTSY_TABLE_NOTES,
+        CREATE_JOTSY_TABLE_NOTE_DATA,
+        CREATE_JOTSY_TABLE_API_TOKENS,
+        CREATE_JOTSY_TABLE_USER_TOKENS,
+        CREATE_JOTSY_TABLE_SESSIONS,
+        CREATE_JOTSY_TABLE_USER_SESSIONS,
+        CREATE_JOTSY_TABLE_SEARCH,
+        CREATE_JOTSY_TABLE_SEARCH_TERMS,
+        CREATE_JOTSY_TABLE_TAGS,
+        CREATE_JOTSY_TABLE_USER_TAGS,
+        CREATE_JOTSY_TABLE_NOTEBOOKS,
+        CREATE_JOTSY_TABLE_NOTEBOOK_NOTES,
+        CREATE_JOTSY_TABLE_PINNED,
+        CREATE_JOTSY_TABLE_ARCHIVE,
+        CREATE_JOTSY_TABLE_SHARES,
+        CREATE_JOTSY_TABLE_USER_SHARES,
+        CREATE_JOTSY_TABLE_TRASH,
+        CREATE_JOTSY_TABLE_REVISIONS,
+        CREATE_JOTSY_TABLE_SETTINGS,
+        CREATE_JOTSY_TABLE_META,
+        CREATE_JOTSY_TABLE_USERS,
+        CREATE_JOTSY_TABLE_TOTP,
+        CREATE_JOTSY_TABLE_RECOVERY_CODES,
+        CREATE_JOTSY_TABLE_PENDING_LOGINS,
+        CREATE_JOTSY_TABLE_PASSKEYS,
+        CREATE_JOTSY_TABLE_PASSKEY_HANDLES,
+        CREATE_JOTSY_TABLE_PASSKEY_CEREMONIES,
+        CREATE_JOTSY_TABLE_LOGIN_ATTEMPTS,
+    ] {
+        check_error(con.run_query(&query(table)).await?);
+    }
     Ok(())
 }
 
+/// Returns an error unless the server responded with `Okay`
+pub fn expect_okay(e: Element) -> crate::JotsyResponseResult<()> {
+    if let Element::RespCode(RespCode::Okay) = e {
+        Ok(())
+    } else {
+        Err(crate::error::ResponseError::DatabaseError(
+            SkyhashError::UnexpectedDataType.into(),
+        ))
+    }
+}
+
+/// Returns the length of a list in the current table (zero if it doesn't exist)
+pub async fn list_len(con: &mut Connection, key: &str) -> crate::JotsyResponseResult<u64> {
+    match con.run_query(&query!("LGET", key, "len")).await? {
+        Element::RespCode(RespCode::NotFound) => Ok(0),
+        e => Ok(u64::from_element(e)?),
+    }
+}
+
+/// Returns the items at `start..stop` in a list in the current table. The range must be
+/// within the list
+pub async fn list_range(
+    con: &mut Connection,
+    key: &str,
+    start: u64,
+    stop: u64,
+) -> crate::JotsyResponseResult<Vec<String>> {
+    if start >= stop {
+        return Ok(Vec::new());
+    }
+    let q = query!("LGET", key, "range", start.to_string(), stop.to_string());
+    match con.run_query(&q).await? {
+        Element::RespCode(RespCode::NotFound) => Ok(Vec::new()),
+        e => Ok(Vec::<String>::from_element(e)?),
+    }
+}
+
+/// Returns the items in a list in the current table, or an empty list if it doesn't exist
+pub async fn list_get(con: &mut Connection, key: &str) -> crate::JotsyResponseResult<Vec<String>> {
+    match con.run_query(&query!("LGET", key)).await? {
+        Element::RespCode(RespCode::NotFound) => Ok(Vec::new()),
+        e => Ok(Vec::<String>::from_element(e)?),
+    }
+}
+
+/// Get multiple lists from the current table in a single round trip. Lists that don't exist
+/// are empty
+pub async fn list_mget(
+    con: &mut Connection,
+    keys: &[String],
+) -> crate::JotsyResponseResult<Vec<Vec<String>>> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    let pipeline = keys.iter().fold(Pipeline::new(), |pipeline, key| {
+        pipeline.append(query!("LGET", key))
+    });
+    con.run_pipeline(pipeline)
+        .await?
+        .into_iter()
+        .map(|e| match e {
+            Element::RespCode(RespCode::NotFound) => Ok(Vec::new()),
+            e => Ok(Vec::<String>::from_element(e)?),
+        })
+        .collect()
+}
+
+/// Get multiple keys from the current table. Keys that don't exist are `None`
+pub async fn mget(
+    con: &mut Connection,
+    keys: Vec<String>,
+) -> crate::JotsyResponseResult<Vec<Option<String>>> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    match con.run_query(&query!("MGET", keys)).await? {
+        Element::Array(Array::Str(values)) => Ok(values),
+        Element::Array(Array::NonNullStr(values)) => Ok(values.into_iter().map(Some).collect()),
+        _ => Err(crate::error::ResponseError::DatabaseError(
+            SkyhashError::UnexpectedDataType.into(),
+        )),
+    }
+}
+
+/// Append an item to a list in the current table, creating the list if it doesn't exist
+pub async fn list_push(
+    con: &mut Connection,
+    key: &str,
+    item: impl AsRef<str>,
+) -> crate::JotsyResponseResult<()> {
+    let item = item.as_ref();
+    match con.run_query(&query!("LMOD", key, "PUSH", item)).await? {
+        Element::RespCode(RespCode::NotFound) => {
+            expect_okay(con.run_query(&query!("LSET", key, item)).await?)
+        }
+        e => expect_okay(e),
+    }
+}
+
+/// Remove the first item matching `remove` from a list in the current table, returning it
+pub async fn list_remove(
+    con: &mut Connection,
+    key: &str,
+    remove: impl Fn(&str) -> bool,
+) -> crate::JotsyResponseResult<Option<String>> {
+    let mut items = self::list_get(con, key).await?;
+    match items.iter().position(|item| remove(item)) {
+        Some(idx) => {
+            let q = query!("LMOD", key, "REMOVE", idx.to_string());
+            expect_okay(con.run_query(&q).await?)?;
+            Ok(Some(items.swap_remove(idx)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the sanitizer for rendered notes. Only the tags and attributes that our markdown
+/// can produce (and that are harmless) are kept: scripts, event handlers, styles and
+/// `javascript:`-like URLs are all stripped
+fn sanitizer() -> &'static Builder<'static> {
+    static SANITIZER: OnceLock<Builder<'static>> = OnceLock::new();
+    SANITIZER.get_or_init(|| {
+        let mut builder = Builder::default();
+        builder
+            // task lists are rendered as disabled checkboxes
+            .add_tags(["input"])
+            .add_tag_attributes("input", ["checked"])
+            .set_tag_attribute_value("input", "type", "checkbox")
+            .set_tag_attribute_value("input", "disabled", "")
+            // code blocks carry their language
+            .add_tag_attributes("code", ["class"]);
+        builder
+    })
+}
+
+/// Render markdown to HTML. The output is sanitized and is safe to embed as-is in a page
 pub fn md_to_html(md: &str) -> String {
     let mut options = ComrakOptions::default();
     options.extension.strikethrough = true;
@@ -71,9 +395,9 @@ pub fn md_to_html(md: &str) -> String {
     options.extension.superscript = true;
     options.extension.footnotes = true;
     options.extension.description_lists = true;
-    // this is fine because we escape tags anyways
+    // raw HTML is allowed, but everything goes through the sanitizer
     options.render.unsafe_ = true;
-    to_html(md, &options)
+    self::sanitizer().clean(&to_html(md, &options)).to_string()
 }
 
 pub fn resp(code: StatusCode, body: impl ToString) -> crate::JotsyResponse {
@@ -89,7 +413,7 @@ pub fn null_cookie(cookie_name: &str) -> Cookie<'static> {
 pub fn create_cookie(name: impl ToString, value: impl ToString) -> Cookie<'static> {
     let mut c = Cookie::new(name.to_string(), value.to_string());
     let mut now = OffsetDateTime::now_utc();
-    now += Duration::days(15);
+    now += Duration::seconds(self::session_ttl());
     c.set_expires(now);
     c.set_same_site(SameSite::Lax);
     if self::is_prod() {
@@ -108,6 +432,15 @@ pub fn bcrypt_verify(pass: impl AsRef<[u8]>, hash: impl AsRef<str>) -> bool {
     bcrypt::verify(pass, hash.as_ref()).unwrap()
 }
 
+/// A bcrypt hash (with the default cost) of a password nobody knows
+const BCRYPT_DUMMY_HASH: &str = "$2b$12$.xi6V/aPmrIhhe.Gt2hSCunzARuWjqeDccBhSePsggQnRKXJeY/Ua";
+
+/// Verify a password against a hash that no password matches. This takes as long as
+/// [`bcrypt_verify`], so that users who don't exist can't be told apart by timing
+pub fn bcrypt_dummy_verify(pass: impl AsRef<[u8]>) {
+    let _ = self::bcrypt_verify(pass, BCRYPT_DUMMY_HASH);
+}
+
 /// Hash the input and return a formatted hex
 pub fn sha2(input: impl AsRef<[u8]>) -> String {
     let mut h = Sha256::new();
@@ -115,3 +448,211 @@ pub fn sha2(input: impl AsRef<[u8]>) -> String {
     let ret = h.finalize();
     format!("{:X}", ret)
 }
+
+/// Returns a random alphanumeric string of the given length
+pub fn random_alphanumeric(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_date, md_to_html, parse_date};
+    use chrono::{Local, NaiveDate, TimeZone, Utc};
+
+    /// Known XSS payloads, both as raw HTML and as markdown constructs
+    const PAYLOADS: &[&str] = &[
+        "<script>alert(1)</script>",
+        "<SCRIPT SRC=//evil.example/xss.js></SCRIPT>",
+        "<img src=x onerror=alert(1)>",
+        "<img src=\"x\" oNeRrOr=\"alert(1)\">",
+        "<svg onload=alert(1)>",
+        "<svg><script>alert(1)</script></svg>",
+        "<math><mtext><table><mglyph><style><img src=x onerror=alert(1)>",
+        "<body onload=alert(1)>",
+        "<iframe src=\"javascript:alert(1)\"></iframe>",
+        "<iframe srcdoc=\"<script>alert(1)</script>\"></iframe>",
+        "<object data=\"javascript:alert(1)\"></object>",
+        "<embed src=\"javascript:alert(1)\">",
+        "<a href=\"javascript:alert(1)\">click</a>",
+        "<a href=\"JaVaScRiPt:alert(1)\">click</a>",
+        "<a href=\"&#106;avascript:alert(1)\">click</a>",
+        "<a href=\"jav&#x09;ascript:alert(1)\">click</a>",
+        "<a href=\"vbscript:msgbox(1)\">click</a>",
+        "<a href=\"data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==\">click</a>",
+        "<a href=\"#\" onclick=\"alert(1)\">click</a>",
+        "<div style=\"background:url(javascript:alert(1))\">x</div>",
+        "<style>body { display: none }</style>",
+        "<link rel=stylesheet href=//evil.example/x.css>",
+        "<meta http-equiv=\"refresh\" content=\"0;url=javascript:alert(1)\">",
+        "<base href=\"//evil.example/\">",
+        "<form action=\"//evil.example\"><input name=password></form>",
+        "<input autofocus onfocus=alert(1)>",
+        "<details open ontoggle=alert(1)>",
+        "<video><source onerror=alert(1)></video>",
+        "<noscript><p title=\"</noscript><img src=x onerror=alert(1)>\">",
+        "<textarea></textarea><img src=x onerror=alert(1)>",
+        "[click](javascript:alert(1))",
+        "[click](JAVASCRIPT:alert(1))",
+        "[click](javascript&#58;alert(1))",
+        "[click](<javascript:alert(1)>)",
+        "[click](data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==)",
+        "![img](javascript:alert(1))",
+        "![img](x\" onerror=\"alert(1))",
+        "[click][ref]\n\n[ref]: javascript:alert(1)",
+        "<javascript:alert(1)>",
+        "```\n</code></pre><script>alert(1)</script>\n```",
+        "`<script>alert(1)</script>`",
+        "- [x] <img src=x onerror=alert(1)>",
+        "| a |\n|---|\n| <img src=x onerror=alert(1)> |",
+    ];
+
+    /// Returns the lowercased tags in sanitized HTML. Any `<` in text is escaped by then, so
+    /// every `<` that is left opens a real tag
+    fn tags(html: &str) -> Vec<String> {
+        html.split('<')
+            .skip(1)
+            .map(|tag| tag.split('>').next().unwrap().to_lowercase())
+            .collect()
+    }
+
+    /// Fail if the HTML contains any markup that can run script
+    fn assert_safe(payload: &str, html: &str) {
+        for tag in tags(html) {
+            let name = tag.split_whitespace().next().unwrap_or_default();
+            assert!(
+                !matches!(
+                    name.trim_start_matches('/'),
+                    "script"
+                        | "iframe"
+                        | "object"
+                        | "embed"
+                        | "svg"
+                        | "math"
+                        | "style"
+                        | "link"
+                        | "meta"
+                        | "base"
+                        | "form"
+                        | "body"
+                        | "video"
+                        | "source"
+                        | "textarea"
+                        | "noscript"
+                ),
+                "<{tag}> survived sanitizing {payload:?}: {html}"
+            );
+            for attr in tag.split_whitespace().skip(1) {
+                assert!(
+                    !attr.starts_with("on")
+                        && !attr.starts_with("style")
+                        && !attr.starts_with("srcdoc"),
+                    "attribute `{attr}` survived sanitizing {payload:?}: {html}"
+                );
+            }
+            for scheme in ["javascript:", "vbscript:", "data:"] {
+                assert!(
+                    !tag.contains(scheme),
+                    "`{scheme}` URL survived sanitizing {payload:?}: {html}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn xss_payloads_are_sanitized() {
+        for payload in PAYLOADS {
+            assert_safe(payload, &md_to_html(payload));
+        }
+    }
+
+    /// Returns the name and (sorted) attributes of a tag. The sanitizer doesn't keep
+    /// attributes in any particular order
+    fn parse_tag(tag: &str) -> (&str, Vec<&str>) {
+        let mut parts = tag.split_whitespace();
+        let name = parts.next().unwrap();
+        let mut attrs: Vec<&str> = parts.collect();
+        attrs.sort_unstable();
+        (name, attrs)
+    }
+
+    #[test]
+    fn inputs_are_only_disabled_checkboxes() {
+        let tags = tags(&md_to_html("<input type=text value=x name=password>"));
+        assert_eq!(tags.len(), 1);
+        assert_eq!(
+            parse_tag(&tags[0]),
+            ("input", vec!["disabled=\"\"", "type=\"checkbox\""])
+        );
+    }
+
+    #[test]
+    fn links_are_kept_and_hardened() {
+        let tags = tags(&md_to_html("[jotsy](https://example.com)"));
+        assert!(tags[1].starts_with("a "));
+        assert!(tags[1].contains("href=\"https://example.com\""));
+        assert!(tags[1].contains("rel=\"noopener noreferrer\""));
+    }
+
+    #[test]
+    fn markdown_still_renders() {
+        let tasks = tags(&md_to_html("- [x] done\n- [ ] todo"));
+        assert_eq!(
+            parse_tag(&tasks[2]),
+            (
+                "input",
+                vec!["checked=\"\"", "disabled=\"\"", "type=\"checkbox\""]
+            )
+        );
+        assert_eq!(
+            md_to_html("```rust\nfn main() {}\n```").trim(),
+            "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"
+        );
+        assert_eq!(
+            md_to_html("**bold** ~~gone~~ ^up^").trim(),
+            "<p><strong>bold</strong> <del>gone</del> <sup>up</sup></p>"
+        );
+        assert!(md_to_html("| a |\n|---|\n| 1 |").contains("<td>1</td>"));
+    }
+
+    #[test]
+    fn dates_are_parsed() {
+        let utc = Utc.with_ymd_and_hms(2022, 11, 2, 14, 5, 0).unwrap();
+        assert_eq!(parse_date("2022-11-02T14:05:00Z"), Some(utc));
+        assert_eq!(parse_date(" 2022-11-02T19:35:00+05:30 "), Some(utc));
+        // legacy display strings are in the server's timezone
+        let local = NaiveDate::from_ymd_opt(2022, 11, 2)
+            .and_then(|date| date.and_hms_opt(14, 5, 0))
+            .and_then(|date| Local.from_local_datetime(&date).earliest())
+            .unwrap();
+        assert_eq!(
+            parse_date("November 02, 2022 | 02:05 PM"),
+            Some(local.with_timezone(&Utc))
+        );
+        assert_eq!(
+            parse_date("2022-11-02"),
+            Some(Utc.with_ymd_and_hms(2022, 11, 2, 0, 0, 0).unwrap())
+        );
+        assert_eq!(parse_date("yesterday"), None);
+        assert_eq!(parse_date("2022-13-01"), None);
+    }
+
+    #[test]
+    fn dates_are_formatted() {
+        let date = Utc.with_ymd_and_hms(2022, 11, 2, 14, 5, 0).unwrap();
+        assert_eq!(format_date(&date, None), "November 02, 2022 | 02:05 PM UTC");
+        assert_eq!(
+            format_date(&date, Some(chrono_tz::Asia::Kolkata)),
+            "November 02, 2022 | 07:35 PM"
+        );
+        // a date in another day (and year) in the user's timezone
+        let date = Utc.with_ymd_and_hms(2022, 12, 31, 23, 30, 0).unwrap();
+        assert_eq!(
+            format_date(&date, Some(chrono_tz::Asia::Tokyo)),
+            "January 01, 2023 | 08:30 AM"
+        );
+    }
+}
@@ -0,0 +1,22 @@
+// Imports from the account page. The files are sent from here so that the CSRF token can go in
+// a header, since the middleware doesn't read multipart bodies
+
+async function importNotes(event) {
+  event.preventDefault();
+  try {
+    const response = await fetch("/account/import", {
+      method: "POST",
+      headers: {
+        "X-CSRF-Token": document.querySelector('meta[name="csrf-token"]').content,
+      },
+      body: new FormData(event.target),
+    });
+    // the response is a page with the report of what was imported
+    const page = await response.text();
+    document.open();
+    document.write(page);
+    document.close();
+  } catch (e) {
+    alert(e.message);
+  }
+}
@@ -3,7 +3,7 @@ const notesData = document.getElementById("inputnote");
 const noteError = document.getElementById("newerror");
 const noteErrorMessage = document.getElementById("newerrormsg");
 const noteCount = document.getElementById("count");
-var lastNote = notesBody.getElementsByClassName("isnote")[0];
+const noteFormat = document.getElementById("inputformat");
 const loader = document.getElementById("loader");
 
 document.onkeyup = function (e) {
@@ -12,43 +12,87 @@ document.onkeyup = function (e) {
   }
 };
 
-function send(data) {
-  const XHR = new XMLHttpRequest();
-  var encodedData = "",
-    encodedDataPairs = [],
+function encode(data) {
+  var encodedDataPairs = [],
     name;
   for (name in data) {
     encodedDataPairs.push(
       encodeURIComponent(name) + "=" + encodeURIComponent(data[name])
     );
   }
-  encodedData = encodedDataPairs.join("&").replace(/%20/g, "+");
-  XHR.open("POST", "/create/note");
+  return encodedDataPairs.join("&").replace(/%20/g, "+");
+}
+
+// the CSRF token of this session, which has to be sent with every request that changes
+// something (pages without a session, like shared notes, don't have one)
+const csrfMeta = document.querySelector('meta[name="csrf-token"]');
+
+function request(method, url, data, onDone) {
+  const XHR = new XMLHttpRequest();
+  XHR.open(method, url);
   XHR.setRequestHeader("Content-Type", "application/x-www-form-urlencoded");
-  XHR.send(encodedData);
-  var ret;
+  if (csrfMeta) {
+    XHR.setRequestHeader("X-CSRF-Token", csrfMeta.content);
+  }
   XHR.onreadystatechange = function () {
     if (XHR.readyState == XMLHttpRequest.DONE) {
-      if (XHR.status === 201) {
-        var element = document.createElement("span");
-        element.innerHTML = String(XHR.responseText);
-        notesBody.insertBefore(element, lastNote);
-        lastNote = element;
-        if (document.getElementById("nonewnotes") != null) {
-          document.getElementById("nonewnotes").remove();
-        }
-        loader.hidden = true;
-        var n = parseInt(noteCount.textContent);
-        n += 1;
-        noteCount.textContent = n.toString();
-        notesData.innerText = "";
-      } else {
-        noteError.hidden = false;
-        loader.hidden = true;
-        noteErrorMessage.innerText = "Failed to submit new note";
-      }
+      onDone(XHR);
     }
   };
+  XHR.send(encode(data));
+}
+
+// dates are rendered in UTC unless the user has chosen a timezone. If they haven't, show
+// them in the browser's timezone instead
+function localizeDates(root) {
+  if (!document.body.dataset.localtime) {
+    return;
+  }
+  var dates = root.getElementsByClassName("notedate");
+  for (var i = 0; i < dates.length; i++) {
+    var date = new Date(dates[i].dateTime);
+    dates[i].textContent = date.toLocaleString(undefined, {
+      dateStyle: "long",
+      timeStyle: "short",
+    });
+  }
+}
+
+// turn a rendered note returned by the server into an element
+function noteFromHTML(html) {
+  var element = document.createElement("div");
+  element.innerHTML = String(html);
+  localizeDates(element);
+  return element.firstElementChild;
+}
+
+function updateCount(by) {
+  var n = parseInt(noteCount.textContent);
+  n += by;
+  noteCount.textContent = n.toString();
+}
+
+function send(data) {
+  request("POST", "/create/note", data, function (XHR) {
+    if (XHR.status === 201) {
+      var element = noteFromHTML(XHR.responseText);
+      // new notes go below the pinned notes
+      notesBody.insertBefore(
+        element,
+        notesBody.querySelector(".isnote:not(.pinned)")
+      );
+      if (document.getElementById("nonewnotes") != null) {
+        document.getElementById("nonewnotes").remove();
+      }
+      loader.hidden = true;
+      updateCount(1);
+      notesData.innerText = "";
+    } else {
+      noteError.hidden = false;
+      loader.hidden = true;
+      noteErrorMessage.innerText = "Failed to submit new note";
+    }
+  });
 }
 
 function submitAndUpdate() {
@@ -60,7 +104,185 @@ function submitAndUpdate() {
     // hide any previous error message
     noteError.hidden = true;
     loader.hidden = false;
-    send({ note: note });
+    var data = { note: note, format: noteFormat.value };
+    if (notesBody.dataset.notebook) {
+      data.notebook = notesBody.dataset.notebook;
+    }
+    send(data);
   }
   notesData.focus();
 }
+
+function noteElement(id) {
+  return document.getElementById("note-" + id);
+}
+
+function noteChild(id, className) {
+  return noteElement(id).getElementsByClassName(className)[0];
+}
+
+function editNote(id) {
+  var input = noteChild(id, "noteinput");
+  input.innerText = noteChild(id, "noteraw").value;
+  // offer to move the note to any of the user's notebooks
+  var notebooks = noteChild(id, "notenotebook");
+  var list = document.getElementById("notebooklist");
+  if (list != null) {
+    notebooks.innerHTML = list.innerHTML;
+    notebooks.value = noteElement(id).dataset.notebook;
+  } else {
+    notebooks.hidden = true;
+  }
+  noteChild(id, "notebody").hidden = true;
+  noteChild(id, "noteeditor").hidden = false;
+  input.focus();
+}
+
+function cancelEdit(id) {
+  noteChild(id, "noteeditor").hidden = true;
+  noteChild(id, "notebody").hidden = false;
+}
+
+function saveNote(id) {
+  var note = noteChild(id, "noteinput").innerText;
+  if (note.length === 0) {
+    alert("Note cannot be empty!");
+    return;
+  }
+  var data = { note: note, format: noteChild(id, "noteformat").value };
+  var notebooks = noteChild(id, "notenotebook");
+  if (!notebooks.hidden && notebooks.value) {
+    data.notebook = notebooks.value;
+  }
+  request("PUT", "/notes/" + id, data, function (XHR) {
+    if (XHR.status === 200) {
+      var element = noteFromHTML(XHR.responseText);
+      var current = notesBody.dataset.notebook;
+      if (current && element.dataset.notebook !== current) {
+        // the note was moved to another notebook
+        noteElement(id).remove();
+      } else {
+        noteElement(id).replaceWith(element);
+      }
+    } else {
+      alert("Failed to update note");
+    }
+  });
+}
+
+function deleteNote(id) {
+  if (!confirm("Move this note to the trash?")) {
+    return;
+  }
+  request("DELETE", "/notes/" + id, {}, function (XHR) {
+    if (XHR.status === 204) {
+      noteElement(id).remove();
+      updateCount(-1);
+    } else {
+      alert("Failed to delete note");
+    }
+  });
+}
+
+// create a link that anyone can use to read a note, optionally expiring after some days
+function shareNote(id) {
+  var expires = prompt(
+    "Share this note for how many days? Leave this empty to share it until you revoke the link from your account page."
+  );
+  if (expires === null) {
+    return;
+  }
+  request("POST", "/notes/" + id + "/share", { expires: expires }, function (XHR) {
+    if (XHR.status === 201) {
+      prompt("Anyone with this link can read the note:", location.origin + XHR.responseText);
+    } else if (XHR.status === 422) {
+      alert(XHR.responseText);
+    } else {
+      alert("Failed to share note");
+    }
+  });
+}
+
+// pin, unpin, archive, unarchive or restore a note
+function noteAction(id, action) {
+  request("POST", "/notes/" + id + "/" + action, {}, function (XHR) {
+    if (XHR.status !== 200) {
+      alert("Failed to " + action + " note");
+      return;
+    }
+    var element = noteFromHTML(XHR.responseText);
+    if (action === "restore") {
+      // the note is out of the trash, and so, back in the count
+      noteElement(id).remove();
+      updateCount(1);
+    } else if (action === "archive" || action === "unarchive") {
+      // the note is now in the archive or back in its notebook, so it leaves this list
+      // (unless we're looking at the notes with a tag, which include archived notes)
+      if (notesBody.dataset.notebook || notesBody.dataset.archived) {
+        noteElement(id).remove();
+      } else {
+        noteElement(id).replaceWith(element);
+      }
+    } else if (action === "pin" && notesBody.dataset.notebook) {
+      // pinned notes go to the top
+      noteElement(id).remove();
+      notesBody.insertBefore(
+        element,
+        notesBody.getElementsByClassName("isnote")[0] || null
+      );
+    } else {
+      noteElement(id).replaceWith(element);
+    }
+  });
+}
+
+// infinite scroll: when the end of the list comes into view, load the notes that are older
+// than the last one on the page
+const loadMoreObserver = new IntersectionObserver(function (entries) {
+  entries.forEach(function (entry) {
+    if (entry.isIntersecting) {
+      loadMore(entry.target);
+    }
+  });
+});
+
+function watchLoadMore() {
+  var sentinel = document.getElementById("loadmore");
+  if (sentinel != null) {
+    loadMoreObserver.observe(sentinel);
+  }
+}
+
+function loadMore(sentinel) {
+  loadMoreObserver.unobserve(sentinel);
+  // the server tells us where the next page starts
+  var query = { before: sentinel.dataset.before };
+  if (notesBody.dataset.notebook) {
+    query.notebook = notesBody.dataset.notebook;
+  }
+  if (notesBody.dataset.archived) {
+    query.archived = "true";
+  }
+  if (notesBody.dataset.trashed) {
+    query.trashed = "true";
+  }
+  if (notesBody.dataset.tag) {
+    query.tag = notesBody.dataset.tag;
+  }
+  var url = "/notes?" + encode(query);
+  request("GET", url, {}, function (XHR) {
+    sentinel.remove();
+    if (XHR.status === 200) {
+      var page = document.createElement("div");
+      page.innerHTML = XHR.responseText;
+      localizeDates(page);
+      while (page.firstElementChild) {
+        notesBody.appendChild(page.firstElementChild);
+      }
+      watchLoadMore();
+    }
+  });
+}
+
+localizeDates(document);
+watchLoadMore();
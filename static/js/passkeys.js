@@ -0,0 +1,143 @@
+// Passkey registration (from the account page) and login (from the login page)
+
+function bufferToBase64url(buffer) {
+  const bytes = new Uint8Array(buffer);
+  let binary = "";
+  for (const byte of bytes) {
+    binary += String.fromCharCode(byte);
+  }
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function base64urlToBuffer(base64url) {
+  const base64 = base64url.replace(/-/g, "+").replace(/_/g, "/");
+  const binary = atob(base64.padEnd(base64.length + ((4 - (base64.length % 4)) % 4), "="));
+  return Uint8Array.from(binary, (c) => c.charCodeAt(0)).buffer;
+}
+
+// the headers for our requests. Pages with a session have a CSRF token that has to be sent too
+function passkeyHeaders(json) {
+  const headers = {};
+  if (json) {
+    headers["Content-Type"] = "application/json";
+  }
+  const csrf = document.querySelector('meta[name="csrf-token"]');
+  if (csrf) {
+    headers["X-CSRF-Token"] = csrf.content;
+  }
+  return headers;
+}
+
+function passkeysSupported() {
+  if (!window.PublicKeyCredential) {
+    alert("Your browser doesn't support passkeys");
+    return false;
+  }
+  return true;
+}
+
+async function registerPasskey(event) {
+  event.preventDefault();
+  if (!passkeysSupported()) {
+    return;
+  }
+  try {
+    const started = await fetch("/account/passkeys/options", {
+      method: "POST",
+      headers: passkeyHeaders(false),
+    });
+    if (!started.ok) {
+      throw new Error("Couldn't start registering a passkey");
+    }
+    const { id, options } = await started.json();
+    const publicKey = options.publicKey;
+    publicKey.challenge = base64urlToBuffer(publicKey.challenge);
+    publicKey.user.id = base64urlToBuffer(publicKey.user.id);
+    for (const cred of publicKey.excludeCredentials || []) {
+      cred.id = base64urlToBuffer(cred.id);
+    }
+    const credential = await navigator.credentials.create({ publicKey });
+    const response = await fetch("/account/passkeys", {
+      method: "POST",
+      headers: passkeyHeaders(true),
+      body: JSON.stringify({
+        id,
+        name: document.getElementById("passkey-name").value,
+        credential: {
+          id: credential.id,
+          rawId: bufferToBase64url(credential.rawId),
+          type: credential.type,
+          extensions: credential.getClientExtensionResults(),
+          response: {
+            attestationObject: bufferToBase64url(credential.response.attestationObject),
+            clientDataJSON: bufferToBase64url(credential.response.clientDataJSON),
+          },
+        },
+      }),
+    });
+    if (!response.ok) {
+      throw new Error(await response.text());
+    }
+    window.location.reload();
+  } catch (e) {
+    alert(e.message);
+  }
+}
+
+async function loginWithPasskey() {
+  if (!passkeysSupported()) {
+    return;
+  }
+  try {
+    const started = await fetch("/login/passkey/options", {
+      method: "POST",
+      headers: passkeyHeaders(false),
+    });
+    if (started.status === 429) {
+      // the response is the login page, asking us to wait before trying again
+      const page = await started.text();
+      document.open();
+      document.write(page);
+      document.close();
+      return;
+    }
+    if (!started.ok) {
+      throw new Error("Couldn't start logging in with a passkey");
+    }
+    const { id, options } = await started.json();
+    const publicKey = options.publicKey;
+    publicKey.challenge = base64urlToBuffer(publicKey.challenge);
+    for (const cred of publicKey.allowCredentials || []) {
+      cred.id = base64urlToBuffer(cred.id);
+    }
+    const credential = await navigator.credentials.get({ publicKey });
+    const response = await fetch("/login/passkey", {
+      method: "POST",
+      headers: passkeyHeaders(true),
+      body: JSON.stringify({
+        id,
+        credential: {
+          id: credential.id,
+          rawId: bufferToBase64url(credential.rawId),
+          type: credential.type,
+          extensions: credential.getClientExtensionResults(),
+          response: {
+            authenticatorData: bufferToBase64url(credential.response.authenticatorData),
+            clientDataJSON: bufferToBase64url(credential.response.clientDataJSON),
+            signature: bufferToBase64url(credential.response.signature),
+            userHandle: credential.response.userHandle
+              ? bufferToBase64url(credential.response.userHandle)
+              : null,
+          },
+        },
+      }),
+    });
+    // the response is a page (that redirects to the app once we're logged in)
+    const page = await response.text();
+    document.open();
+    document.write(page);
+    document.close();
+  } catch (e) {
+    alert(e.message);
+  }
+}